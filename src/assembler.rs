@@ -0,0 +1,212 @@
+use super::debug_map::DebugMap;
+use std::collections::HashMap;
+
+//two pass assembler for the mnemonics the disassembler prints, so a listing
+//can be edited and assembled back into a rom.
+//
+//  ; comments run to the end of the line
+//  loop:               labels end with a colon and can be used as addresses
+//      MVI  V0, 0a     bare numbers are hex, like in the disassembly
+//      ADI  V0, #10    # marks a decimal number
+//      MVI  I, sprite  $ marks a hex address, labels work too
+//      JMP  loop
+//  sprite:
+//      DB   $f0, $90   raw bytes, DW for 16 bit words
+
+enum Item<'a> {
+    Instruction(&'a str, Vec<&'a str>),
+    Bytes(Vec<&'a str>),
+    Words(Vec<&'a str>),
+}
+
+struct Line<'a> {
+    number: usize,
+    address: u16,
+    item: Item<'a>,
+}
+
+//labels resolve relative to `origin`, the address the program is loaded at
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+    assemble_with_map(source, origin).map(|(program, _)| program)
+}
+
+//the program plus its debug map, without a file name set
+pub fn assemble_with_map(source: &str, origin: u16) -> Result<(Vec<u8>, DebugMap), String> {
+    //first pass: work out the address of every label
+    let mut labels = HashMap::new();
+    //as written, labels is lowercased for lookups
+    let mut names = Vec::new();
+    let mut lines = Vec::new();
+    let mut address = origin;
+    for (number, raw_line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut text = raw_line.split(';').next().unwrap_or("").trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || label.contains(char::is_whitespace) {
+                return Err(format!("line {}: bad label '{}'", number, label));
+            }
+            if labels.insert(label.to_lowercase(), address).is_some() {
+                return Err(format!("line {}: duplicate label '{}'", number, label));
+            }
+            names.push((address, label.to_string()));
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands: Vec<&str> = if rest.trim().is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',').map(str::trim).collect()
+        };
+        let (item, size) = match mnemonic.to_uppercase().as_str() {
+            "DB" => (Item::Bytes(operands.clone()), operands.len()),
+            "DW" => (Item::Words(operands.clone()), operands.len() * 2),
+            _ => (Item::Instruction(mnemonic, operands), 2),
+        };
+        lines.push(Line {
+            number,
+            address,
+            item,
+        });
+        address = address
+            .checked_add(size as u16)
+            .filter(|end| *end <= 0x1000)
+            .ok_or(format!("line {}: program does not fit in memory", number))?;
+    }
+
+    //second pass: encode with every label known
+    let mut output = Vec::new();
+    for line in &lines {
+        let context = |e: String| format!("line {}: {}", line.number, e);
+        match &line.item {
+            Item::Bytes(values) => {
+                for value in values {
+                    output.push(parse_number(value, &labels, 0xff).map_err(context)? as u8);
+                }
+            }
+            Item::Words(values) => {
+                for value in values {
+                    let word = parse_number(value, &labels, 0xffff).map_err(context)?;
+                    output.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            Item::Instruction(mnemonic, operands) => {
+                let word = encode(mnemonic, operands, &labels).map_err(context)?;
+                output.extend_from_slice(&word.to_be_bytes());
+            }
+        }
+    }
+    let map = DebugMap::new(
+        lines
+            .iter()
+            .map(|line| (line.address, line.number))
+            .collect(),
+        names,
+    );
+    Ok((output, map))
+}
+
+fn encode(mnemonic: &str, operands: &[&str], labels: &HashMap<String, u16>) -> Result<u16, String> {
+    let upper: Vec<String> = operands.iter().map(|o| o.to_uppercase()).collect();
+    let ops: Vec<&str> = upper.iter().map(String::as_str).collect();
+    let reg = |i: usize| register(operands[i]);
+    let addr = |i: usize| parse_number(operands[i], labels, 0xfff);
+    let byte = |i: usize| parse_number(operands[i], labels, 0xff);
+    let xy = |base: u16| -> Result<u16, String> { Ok(base | reg(0)? << 8 | reg(1)? << 4) };
+
+    let word = match (mnemonic.to_uppercase().as_str(), ops.as_slice()) {
+        ("CLS", []) => 0x00e0,
+        ("RET", []) => 0x00ee,
+        ("SYS", [_]) => addr(0)?,
+        ("JMP", [target]) if target.ends_with("(V0)") => {
+            let target = &operands[0][..operands[0].len() - 4];
+            0xb000 | parse_number(target, labels, 0xfff)?
+        }
+        ("JMP", [_]) => 0x1000 | addr(0)?,
+        ("CALL", [_]) => 0x2000 | addr(0)?,
+        ("S.EQ", [_, y]) if is_register(y) => xy(0x5000)?,
+        ("S.EQ", [_, _]) => 0x3000 | reg(0)? << 8 | byte(1)?,
+        ("S.NEQ", [_, y]) if is_register(y) => xy(0x9000)?,
+        ("S.NEQ", [_, _]) => 0x4000 | reg(0)? << 8 | byte(1)?,
+        ("MVI", ["I", _]) => 0xa000 | addr(1)?,
+        ("MVI", [_, "K"]) => 0xf00a | reg(0)? << 8,
+        ("MVI", [_, _]) => 0x6000 | reg(0)? << 8 | byte(1)?,
+        ("ADI", ["I", _]) => 0xf01e | reg(1)? << 8,
+        ("ADI", [_, _]) => 0x7000 | reg(0)? << 8 | byte(1)?,
+        ("MOV", [_, "DT"]) => 0xf007 | reg(0)? << 8,
+        ("MOV", [_, _]) => xy(0x8000)?,
+        ("OR", [_, _]) => xy(0x8001)?,
+        ("AND", [_, _]) => xy(0x8002)?,
+        ("XOR", [_, _]) => xy(0x8003)?,
+        ("ADD", [_, _]) => xy(0x8004)?,
+        ("SUB", [_, _]) => xy(0x8005)?,
+        ("SHR", [_]) => 0x8006 | reg(0)? << 8,
+        ("SHR", [_, _]) => xy(0x8006)?,
+        ("SUBN", [_, _]) => xy(0x8007)?,
+        ("SHL", [_]) => 0x800e | reg(0)? << 8,
+        ("SHL", [_, _]) => xy(0x800e)?,
+        ("RND", [_, _]) => 0xc000 | reg(0)? << 8 | byte(1)?,
+        ("DRW", [_, _, _]) => xy(0xd000)? | parse_number(operands[2], labels, 0xf)?,
+        ("SKP", [_]) => 0xe09e | reg(0)? << 8,
+        ("SKNP", [_]) => 0xe0a1 | reg(0)? << 8,
+        ("DELAY", ["DT", _]) => 0xf015 | reg(1)? << 8,
+        ("SOUND", ["ST", _]) => 0xf018 | reg(1)? << 8,
+        ("SPRITECHAR", ["I", _]) => 0xf029 | reg(1)? << 8,
+        ("MOVBCD", ["(I)", _]) => 0xf033 | reg(1)? << 8,
+        ("MVM", ["(I)", range]) => 0xf055 | register_range(range)? << 8,
+        ("MVM", [range, "(I)"]) => 0xf065 | register_range(range)? << 8,
+        _ => {
+            return Err(format!(
+                "can't assemble '{} {}'",
+                mnemonic,
+                operands.join(", ")
+            ))
+        }
+    };
+    Ok(word)
+}
+
+fn is_register(operand: &str) -> bool {
+    register(operand).is_ok()
+}
+
+fn register(operand: &str) -> Result<u16, String> {
+    let operand = operand.trim();
+    match operand.strip_prefix(['V', 'v']) {
+        Some(digit) if digit.len() == 1 => {
+            u16::from_str_radix(digit, 16).map_err(|_| format!("bad register '{}'", operand))
+        }
+        _ => Err(format!("expected a register, got '{}'", operand)),
+    }
+}
+
+//V0-Vx as used by FX55 and FX65
+fn register_range(operand: &str) -> Result<u16, String> {
+    match operand.split_once('-') {
+        Some((first, last)) if register(first) == Ok(0) => register(last),
+        _ => Err(format!("expected V0-Vx, got '{}'", operand)),
+    }
+}
+
+fn parse_number(operand: &str, labels: &HashMap<String, u16>, max: u16) -> Result<u16, String> {
+    let operand = operand.trim();
+    let value = if let Some(hex) = operand.strip_prefix('$') {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = operand.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(decimal) = operand.strip_prefix('#') {
+        decimal.parse().ok()
+    } else if let Some(address) = labels.get(&operand.to_lowercase()) {
+        Some(*address as u32)
+    } else {
+        u32::from_str_radix(operand, 16).ok()
+    };
+    match value {
+        Some(value) if value <= max as u32 => Ok(value as u16),
+        Some(_) => Err(format!("'{}' is out of range (max {:#x})", operand, max)),
+        None => Err(format!("bad number or unknown label '{}'", operand)),
+    }
+}
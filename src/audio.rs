@@ -0,0 +1,62 @@
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+
+const BEEP_FREQUENCY: f32 = 440.0;
+const BEEP_VOLUME: f32 = 0.15;
+
+struct SquareWave {
+    phase_inc: f32,
+    phase: f32,
+    volume: f32,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+        }
+    }
+}
+
+//plays a tone while the chip8 sound timer is running
+pub struct Beeper {
+    device: AudioDevice<SquareWave>,
+    playing: bool,
+}
+
+impl Beeper {
+    pub fn new(sdl_context: &Sdl) -> Result<Self, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = audio_subsystem.open_playback(None, &desired, |spec| SquareWave {
+            phase_inc: BEEP_FREQUENCY / spec.freq as f32,
+            phase: 0.0,
+            volume: BEEP_VOLUME,
+        })?;
+        Ok(Self {
+            device,
+            playing: false,
+        })
+    }
+    pub fn set_playing(&mut self, playing: bool) {
+        if playing != self.playing {
+            if playing {
+                self.device.resume();
+            } else {
+                self.device.pause();
+            }
+            self.playing = playing;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use super::chip8::{FaultKind, MEMORY_SIZE};
+use std::fmt;
+use std::str::FromStr;
+
+//everything the running program reads or writes goes through a Bus. addresses
+//are already inside the 4K address space, the machine's MemoryPolicy handles
+//the rest before the bus sees them. Send so a machine can move to another
+//thread, gym instances step in parallel
+pub trait Bus: Send {
+    fn read(&mut self, address: u16) -> Result<u8, FaultKind>;
+    fn write(&mut self, address: u16, value: u8) -> Result<(), FaultKind>;
+    //instruction fetches, separate so a bus can tell code from data
+    fn fetch(&mut self, address: u16) -> Result<u8, FaultKind> {
+        self.read(address)
+    }
+    //what a read would see, without its side effects. None where that can't
+    //be known, like a device register
+    fn peek(&self, address: u16) -> Option<u8> {
+        Some(self.ram()[address as usize])
+    }
+    //the backing ram without any side effects, for loading roms, resets and
+    //debuggers
+    fn ram(&self) -> &[u8; MEMORY_SIZE];
+    fn ram_mut(&mut self) -> &mut [u8; MEMORY_SIZE];
+}
+
+//memory mapped hardware, offsets are relative to the start of its region
+pub trait Device: Send {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, value: u8);
+}
+
+enum Mapping {
+    //writes fault, reads come from ram
+    ReadOnly,
+    //the region shows ram starting at target
+    Mirror { target: u16 },
+    Device(Box<dyn Device>),
+}
+
+struct Region {
+    start: u16,
+    end: u16,
+    mapping: Mapping,
+}
+
+//plain ram with optional regions on top, the last region mapped over an
+//address wins
+pub struct MemoryBus {
+    ram: [u8; MEMORY_SIZE],
+    regions: Vec<Region>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; MEMORY_SIZE],
+            regions: Vec::new(),
+        }
+    }
+    pub fn map_read_only(&mut self, start: u16, length: u16) {
+        self.map(start, length, Mapping::ReadOnly);
+    }
+    pub fn map_mirror(&mut self, start: u16, length: u16, target: u16) {
+        self.map(start, length, Mapping::Mirror { target });
+    }
+    pub fn map_device(&mut self, start: u16, length: u16, device: Box<dyn Device>) {
+        self.map(start, length, Mapping::Device(device));
+    }
+    fn map(&mut self, start: u16, length: u16, mapping: Mapping) {
+        let end = (start as usize + length as usize).min(MEMORY_SIZE) as u16;
+        self.regions.push(Region {
+            start,
+            end,
+            mapping,
+        });
+    }
+    fn region(&self, address: u16) -> Option<&Region> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|region| (region.start..region.end).contains(&address))
+    }
+    fn region_mut(&mut self, address: u16) -> Option<&mut Region> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|region| (region.start..region.end).contains(&address))
+    }
+    //ram index for an address, following mirrors
+    fn ram_index(&self, address: u16) -> usize {
+        match self.region(address) {
+            Some(Region {
+                start,
+                mapping: Mapping::Mirror { target },
+                ..
+            }) => (*target as usize + (address - *start) as usize) % MEMORY_SIZE,
+            _ => address as usize,
+        }
+    }
+}
+
+impl Bus for MemoryBus {
+    fn read(&mut self, address: u16) -> Result<u8, FaultKind> {
+        Ok(match self.region_mut(address) {
+            Some(Region {
+                start,
+                mapping: Mapping::Device(device),
+                ..
+            }) => device.read(address - *start),
+            _ => self.ram[self.ram_index(address)],
+        })
+    }
+    fn write(&mut self, address: u16, value: u8) -> Result<(), FaultKind> {
+        match self.region_mut(address) {
+            Some(Region {
+                mapping: Mapping::ReadOnly,
+                ..
+            }) => return Err(FaultKind::WriteProtected { address }),
+            Some(Region {
+                start,
+                mapping: Mapping::Device(device),
+                ..
+            }) => device.write(address - *start, value),
+            _ => {
+                let index = self.ram_index(address);
+                self.ram[index] = value;
+            }
+        }
+        Ok(())
+    }
+    fn peek(&self, address: u16) -> Option<u8> {
+        match self.region(address) {
+            Some(Region {
+                mapping: Mapping::Device(_),
+                ..
+            }) => None,
+            _ => Some(self.ram[self.ram_index(address)]),
+        }
+    }
+    fn ram(&self) -> &[u8; MEMORY_SIZE] {
+        &self.ram
+    }
+    fn ram_mut(&mut self) -> &mut [u8; MEMORY_SIZE] {
+        &mut self.ram
+    }
+}
+
+//areas that --protect makes read-only, writes to them fault
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protect {
+    //the built in hex digit sprites
+    Font,
+    //everything below the load address, where the original interpreter lived
+    Interpreter,
+    //the bytes the rom was loaded into, catches self-modifying code
+    Rom,
+}
+
+impl FromStr for Protect {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "font" => Ok(Protect::Font),
+            "interpreter" => Ok(Protect::Interpreter),
+            "rom" => Ok(Protect::Rom),
+            _ => Err(format!("unknown protected area: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protect::Font => write!(f, "font"),
+            Protect::Interpreter => write!(f, "interpreter"),
+            Protect::Rom => write!(f, "rom"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::trace::{TraceFilter, Tracer};
+
+    //counts up on every read, a write sets the count
+    struct Counter(u8);
+    impl Device for Counter {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.0 = self.0.wrapping_add(1);
+            self.0.wrapping_add(offset as u8 * 0x10)
+        }
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.0 = value;
+        }
+    }
+
+    #[test]
+    fn mirrors_show_their_target() {
+        let mut bus = MemoryBus::new();
+        bus.map_mirror(0x800, 0x100, 0x300);
+        bus.write(0x810, 0x42).unwrap();
+        assert_eq!(bus.ram()[0x310], 0x42);
+        assert_eq!(bus.ram()[0x810], 0);
+        bus.ram_mut()[0x3ff] = 0x17;
+        assert_eq!(bus.read(0x8ff).unwrap(), 0x17);
+        //past the end of the region is plain ram again
+        assert_eq!(bus.read(0x900).unwrap(), 0);
+    }
+
+    #[test]
+    fn devices_take_reads_and_writes() {
+        let mut bus = MemoryBus::new();
+        bus.map_device(0xf00, 2, Box::new(Counter(0)));
+        assert_eq!(bus.read(0xf00).unwrap(), 1);
+        assert_eq!(bus.read(0xf00).unwrap(), 2);
+        bus.write(0xf01, 0x80).unwrap();
+        assert_eq!(bus.read(0xf01).unwrap(), 0x91);
+        assert_eq!(bus.ram()[0xf00..0xf02], [0, 0]);
+        assert_eq!(bus.read(0xf02).unwrap(), 0);
+    }
+
+    #[test]
+    fn last_region_wins() {
+        let mut bus = MemoryBus::new();
+        bus.map_read_only(0x200, 0x100);
+        bus.map_mirror(0x280, 0x10, 0x400);
+        assert!(matches!(
+            bus.write(0x200, 1),
+            Err(FaultKind::WriteProtected { address: 0x200 })
+        ));
+        bus.write(0x280, 1).unwrap();
+        assert_eq!(bus.ram()[0x400], 1);
+    }
+
+    #[test]
+    fn programs_go_through_the_bus() {
+        let mut bus = MemoryBus::new();
+        bus.map_mirror(0xe00, 0x100, 0x300);
+        bus.map_device(0xf00, 1, Box::new(Counter(0)));
+        let mut chip8 = Chip8::new();
+        chip8.set_bus(Box::new(bus));
+        //V0 = 05, I = e00, store V0, I = f00, load V0 twice
+        let program = [
+            0x60, 0x05, 0xae, 0x00, 0xf0, 0x55, 0xaf, 0x00, 0xf0, 0x65, 0xf0, 0x65,
+        ];
+        chip8.load_program(&program).unwrap();
+        for _ in 0..6 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.memory()[0x300], 5);
+        assert_eq!(chip8.registers()[0], 2);
+    }
+
+    #[test]
+    fn traced_writes_see_the_mapped_byte() {
+        let mut bus = MemoryBus::new();
+        bus.map_mirror(0xe00, 0x100, 0x300);
+        bus.map_device(0xf00, 1, Box::new(Counter(0)));
+        bus.ram_mut()[0x300] = 0x42;
+        assert_eq!(bus.peek(0xe00), Some(0x42));
+        assert_eq!(bus.peek(0xf00), None);
+        let mut chip8 = Chip8::new();
+        chip8.set_bus(Box::new(bus));
+        chip8.set_tracer(Some(Tracer::open("ring", TraceFilter::default()).unwrap()));
+        //V0 = 05, I = e00, store V0, I = f00, store V0
+        let program = [0x60, 0x05, 0xae, 0x00, 0xf0, 0x55, 0xaf, 0x00, 0xf0, 0x55];
+        chip8.load_program(&program).unwrap();
+        chip8.poke(0x300, 0x42);
+        for _ in 0..5 {
+            chip8.emulate_cycle().unwrap();
+        }
+        let steps = chip8.tracer().unwrap().recent().unwrap();
+        assert_eq!(steps[2].memory, [(0xe00, 0x42, 5)]);
+        assert!(steps[4].memory.is_empty());
+    }
+}
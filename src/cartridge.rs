@@ -0,0 +1,179 @@
+use super::json::Json;
+use super::octo;
+use std::io::Cursor;
+
+//Octo "cartridges" are gifs with the program hidden in the pixels. every pair
+//of palette indices holds one byte in their low nybbles; the bytes are a 32 bit
+//big endian length followed by that much json:
+//  {"program": "<octo source>", "options": {"tickrate": 20, ...}}
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    //settings keys and values taken from the cartridge options
+    pub settings: Vec<(String, String)>,
+}
+
+pub fn is_cartridge(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+
+//the program is compiled to be loaded at `origin`
+pub fn load(data: &[u8], origin: u16) -> Result<Cartridge, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(Cursor::new(data))
+        .map_err(|e| format!("bad gif: {}", e))?;
+    let mut nybbles = Vec::new();
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|e| format!("bad gif: {}", e))?
+    {
+        nybbles.extend(frame.buffer.iter().map(|index| index & 0xf));
+    }
+    let bytes: Vec<u8> = nybbles
+        .chunks_exact(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+    let length = match bytes.get(..4) {
+        Some(header) => u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize,
+        None => return Err(String::from("not an Octo cartridge")),
+    };
+    let payload = bytes
+        .get(4..4 + length)
+        .ok_or("not an Octo cartridge, payload is cut short")?;
+    let text = std::str::from_utf8(payload).map_err(|_| "not an Octo cartridge")?;
+    let json = Json::parse(text).map_err(|e| format!("not an Octo cartridge, {}", e))?;
+
+    let Some(Json::String(source)) = json.get("program") else {
+        return Err(String::from("cartridge has no program"));
+    };
+    let program = octo::compile(source, origin).map_err(|e| format!("cartridge program: {}", e))?;
+    let mut settings = Vec::new();
+    if let Some(options) = json.get("options") {
+        //octo counts instructions per 60hz frame, 0 leaves the default
+        match options.get("tickrate").and_then(Json::as_f64) {
+            Some(tickrate) if tickrate >= 1.0 => {
+                settings.push((String::from("ips"), (tickrate as u32 * 60).to_string()));
+            }
+            _ => {}
+        }
+        if let (Some(Json::String(fill)), Some(Json::String(background))) =
+            (options.get("fillColor"), options.get("backgroundColor"))
+        {
+            settings.push((
+                String::from("palette"),
+                format!(
+                    "{},{}",
+                    fill.trim_start_matches('#'),
+                    background.trim_start_matches('#')
+                ),
+            ));
+        }
+        //octo has a switch per quirk, pick the closest of our profiles
+        let enabled = |name: &str| matches!(options.get(name), Some(Json::Bool(true)));
+        let profile =
+            if enabled("shiftQuirks") || enabled("loadStoreQuirks") || enabled("jumpQuirks") {
+                "schip"
+            } else {
+                "vip"
+            };
+        settings.push((String::from("quirks"), String::from(profile)));
+    }
+    Ok(Cartridge { program, settings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::object;
+
+    //a gif whose pixels hold `payload` the way Octo saves it
+    fn gif(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        gif_of_bytes(&bytes)
+    }
+    fn gif_of_bytes(bytes: &[u8]) -> Vec<u8> {
+        let mut pixels: Vec<u8> = bytes.iter().flat_map(|b| [b >> 4, b & 0xf]).collect();
+        let width = 64;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+        let palette: Vec<u8> = (0..16u8).flat_map(|i| [i * 16, i * 16, i * 16]).collect();
+        let mut data = Vec::new();
+        let mut encoder = gif::Encoder::new(
+            &mut data,
+            width as u16,
+            (pixels.len() / width) as u16,
+            &palette,
+        )
+        .unwrap();
+        let frame = gif::Frame::from_indexed_pixels(
+            width as u16,
+            (pixels.len() / width) as u16,
+            pixels,
+            None,
+        );
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+        data
+    }
+
+    fn cartridge(options: Json) -> Vec<u8> {
+        let json = object(&[
+            ("program", ": main\n\tv0 := 5\n\tjump main\n".into()),
+            ("options", options),
+        ]);
+        gif(json.to_string().as_bytes())
+    }
+
+    #[test]
+    fn program_and_options() {
+        let data = cartridge(object(&[
+            ("tickrate", 20usize.into()),
+            ("fillColor", "#FF6600".into()),
+            ("backgroundColor", "#000000".into()),
+            ("shiftQuirks", true.into()),
+        ]));
+        assert!(is_cartridge(&data));
+        let cartridge = load(&data, 0x200).unwrap();
+        assert_eq!(cartridge.program, [0x12, 0x02, 0x60, 0x05, 0x12, 0x02]);
+        let setting = |key: &str| {
+            cartridge
+                .settings
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(setting("ips"), Some("1200"));
+        assert_eq!(setting("palette"), Some("FF6600,000000"));
+        assert_eq!(setting("quirks"), Some("schip"));
+        //compiled for where it is loaded
+        assert_eq!(load(&data, 0x600).unwrap().program[..2], [0x16, 0x02]);
+    }
+
+    #[test]
+    fn zero_tickrate_keeps_the_default() {
+        let data = cartridge(object(&[("tickrate", 0usize.into())]));
+        let cartridge = load(&data, 0x200).unwrap();
+        assert_eq!(
+            cartridge.settings,
+            [(String::from("quirks"), String::from("vip"))]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let error = |data: &[u8]| load(data, 0x200).err().unwrap();
+        assert!(error(b"GIF89a\x01\x02").starts_with("bad gif"));
+        assert!(error(&gif(b"{}")).contains("no program"));
+        assert!(error(&gif(b"not json")).starts_with("not an Octo cartridge"));
+        assert!(error(&gif(&[0xff, 0xfe])).starts_with("not an Octo cartridge"));
+        let program = object(&[("program", ": start\n".into())]);
+        assert!(error(&gif(program.to_string().as_bytes())).starts_with("cartridge program:"));
+        //a length running past the pixels, and too few pixels for one
+        let mut header = 1000u32.to_be_bytes().to_vec();
+        header.extend_from_slice(b"{}");
+        assert!(error(&gif_of_bytes(&header)).contains("cut short"));
+        let empty = gif_of_bytes(&[]);
+        assert!(load(&empty, 0x200).is_err());
+    }
+}
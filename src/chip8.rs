@@ -1,24 +1,66 @@
-use super::file_utils;
-use rand;
+use super::bus::{Bus, MemoryBus};
+use super::debug_map::DebugMap;
+use super::debugger::{Breakpoints, WatchKind};
+use super::profiler::Profile;
+use super::trace::{TraceStep, Tracer};
 use rand::prelude::*;
-use rand::rngs;
-pub struct chip8 {
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+pub struct Chip8 {
     registers: [u8; 16],
-    memory: [u8; 4096],
+    //ram plus whatever regions and hooks are mapped over it
+    bus: Box<dyn Bus>,
     index: u16,
     pc: u16,
-    stack: [u16; 16],
-    sp: u16,
+    //return addresses, as deep as quirks.stack_depth allows
+    stack: Vec<u16>,
     delay_timer: u8,
     sound_timer: u8,
     keypad: [bool; 16],
     video: [bool; 64 * 32],
-    rng: rand::rngs::ThreadRng,
+    rng: rand::rngs::StdRng,
+    //kept so that reset() replays the same random sequence
+    seed: u64,
+    quirks: Quirks,
+    //frames run since the last reset, used to timestamp recorded input
+    frame: u64,
+    timing: Timing,
+    //instructions (Timing::Fixed) or machine cycles (Timing::Vip) left in the
+    //current frame, cycles can go negative when an instruction runs past the
+    //end of the frame
+    cycle_balance: i64,
+    //a fault stopped the current frame, run_frame(0) finishes it
+    mid_frame: bool,
+    //DXYN on the VIP blocks until the next display interrupt
+    waiting_for_vblank: bool,
+    //where load_program copies the rom and where reset() starts executing,
+    //0x600 on the ETI-660 and elsewhere for some hybrid roms
+    load_address: u16,
+    entry_point: u16,
+    memory_policy: MemoryPolicy,
+    //accesses MemoryPolicy::Log has printed since the last reset
+    logged_accesses: u32,
+    //None when not tracing, which costs one check per instruction
+    tracer: Option<Box<Tracer>>,
+    //memory written by the instruction being traced, (address, old, new)
+    traced_writes: Option<Vec<(u16, u8, u8)>>,
+    //execution counts for --profile
+    profile: Option<Box<Profile>>,
+    breakpoints: Breakpoints,
+    //labels and source lines of the loaded program, for showing it
+    symbols: Option<Arc<DebugMap>>,
 }
-const VIDEO_WIDTH: u16 = 64;
-const VIDEO_HEIGHT: u16 = 32;
-const FONTSET_START_ADDRESS: u8 = 0x50;
-const FONTSET: [u8; 80] = [
+pub const VIDEO_WIDTH: u16 = 64;
+pub const VIDEO_HEIGHT: u16 = 32;
+//only the 4K machine is emulated, XO-CHIP's 64K is not supported
+pub const MEMORY_SIZE: usize = 4096;
+//MemoryPolicy::Log stays quiet after this many accesses, a runaway loop would
+//otherwise print one line per instruction
+const MEMORY_LOG_LIMIT: u32 = 16;
+pub const DEFAULT_LOAD_ADDRESS: u16 = 0x200;
+pub const FONTSET_START_ADDRESS: u16 = 0x50;
+pub const FONTSET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
     0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
@@ -36,6 +78,255 @@ const FONTSET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
+//1.7609 MHz crystal, 8 clocks per machine cycle, 60 frames per second
+const VIP_MACHINE_CYCLES_PER_FRAME: u32 = 3668;
+//cycles stolen every frame by the 1861 display DMA and the interrupt routine
+const VIP_FRAME_OVERHEAD: u32 = 1024 + 44;
+//cycles left for the interpreter in one frame, the budget the frontend should
+//pass to run_frame when using Timing::Vip
+pub const VIP_CYCLES_PER_FRAME: u32 = VIP_MACHINE_CYCLES_PER_FRAME - VIP_FRAME_OVERHEAD;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timing {
+    //run_frame budget is a plain instruction count
+    Fixed,
+    //run_frame budget is in VIP machine cycles, each opcode costs what it did
+    //in the original interpreter and DXYN waits for vertical blank
+    Vip,
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timing::Fixed => write!(f, "fixed"),
+            Timing::Vip => write!(f, "vip"),
+        }
+    }
+}
+impl FromStr for Timing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Timing::Fixed),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!("unknown timing: {}", s)),
+        }
+    }
+}
+
+//behaviours that differ between interpreters, grouped into named profiles
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QuirkProfile {
+    //what this emulator has always done
+    Modern,
+    //original COSMAC VIP interpreter
+    Vip,
+    //SUPER-CHIP 1.1 on the HP48
+    Schip,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quirks {
+    pub profile: QuirkProfile,
+    //8XY1, 8XY2 and 8XY3 clear VF
+    pub vf_reset: bool,
+    //FX55 and FX65 leave I pointing past the last register
+    pub memory_increment: bool,
+    //8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    //BNNN jumps to NNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    //sprites are cut at the screen edge instead of wrapping around
+    pub clip_sprites: bool,
+    //nested calls before 2NNN faults with a stack overflow
+    pub stack_depth: StackDepth,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StackDepth {
+    Limited(usize),
+    //for development, runaway recursion is easier to see in the debugger
+    Unlimited,
+}
+
+impl fmt::Display for StackDepth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackDepth::Limited(depth) => write!(f, "{}", depth),
+            StackDepth::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+impl FromStr for StackDepth {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unlimited" => Ok(StackDepth::Unlimited),
+            _ => s
+                .parse()
+                .ok()
+                .filter(|depth| *depth > 0)
+                .map(StackDepth::Limited)
+                .ok_or(format!("invalid stack depth: {}", s)),
+        }
+    }
+}
+
+//what happens when the program touches an address past the end of memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemoryPolicy {
+    //only the low 12 address bits are decoded, like the VIP with 4K fitted
+    Wrap,
+    //stop with a fault
+    Fault,
+    //print the access and carry on, reads give 0 and writes are dropped
+    Log,
+}
+
+impl fmt::Display for MemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryPolicy::Wrap => write!(f, "wrap"),
+            MemoryPolicy::Fault => write!(f, "fault"),
+            MemoryPolicy::Log => write!(f, "log"),
+        }
+    }
+}
+impl FromStr for MemoryPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(MemoryPolicy::Wrap),
+            "fault" => Ok(MemoryPolicy::Fault),
+            "log" => Ok(MemoryPolicy::Log),
+            "64k" => Err(String::from(
+                "64K memory is not emulated, only 4K with wrap, fault or log",
+            )),
+            _ => Err(format!("unknown memory policy: {}", s)),
+        }
+    }
+}
+
+//an error in the running program, the machine stops on the faulting
+//instruction at pc
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fault {
+    pub pc: u16,
+    pub kind: FaultKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FaultKind {
+    StackOverflow {
+        depth: usize,
+    },
+    StackUnderflow,
+    //with MemoryPolicy::Fault
+    MemoryAccess {
+        address: u16,
+    },
+    //not errors, a debugger asked to stop here. pc is on the breakpoint, for
+    //a watchpoint it's past the instruction that touched the address
+    Breakpoint,
+    //watch is the kind of watchpoint that asked for the stop
+    Watchpoint {
+        address: u16,
+        write: bool,
+        watch: WatchKind,
+    },
+    //a write to a read-only region of the bus
+    WriteProtected {
+        address: u16,
+    },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::StackOverflow { depth } => write!(
+                f,
+                "stack overflow at {:#05x}, more than {} nested calls",
+                self.pc, depth
+            ),
+            FaultKind::StackUnderflow => write!(
+                f,
+                "stack underflow at {:#05x}, return with nothing to return to",
+                self.pc
+            ),
+            FaultKind::MemoryAccess { address } => write!(
+                f,
+                "memory access at {:#05x} to {:#06x}, past the end of memory",
+                self.pc, address
+            ),
+            FaultKind::Breakpoint => write!(f, "breakpoint at {:#05x}", self.pc),
+            FaultKind::Watchpoint { address, write, .. } => write!(
+                f,
+                "watchpoint at {:#05x}, {} of {:#05x}",
+                self.pc,
+                if write { "write" } else { "read" },
+                address
+            ),
+            FaultKind::WriteProtected { address } => write!(
+                f,
+                "write at {:#05x} to {:#05x}, which is read-only",
+                self.pc, address
+            ),
+        }
+    }
+}
+
+impl QuirkProfile {
+    pub fn quirks(self) -> Quirks {
+        let none = Quirks {
+            profile: self,
+            vf_reset: false,
+            memory_increment: false,
+            shift_uses_vy: false,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            stack_depth: StackDepth::Limited(16),
+        };
+        match self {
+            QuirkProfile::Modern => none,
+            QuirkProfile::Vip => Quirks {
+                vf_reset: true,
+                memory_increment: true,
+                shift_uses_vy: true,
+                clip_sprites: true,
+                //the interpreter kept its stack in 48 bytes below the display
+                stack_depth: StackDepth::Limited(12),
+                ..none
+            },
+            QuirkProfile::Schip => Quirks {
+                jump_uses_vx: true,
+                clip_sprites: true,
+                ..none
+            },
+        }
+    }
+}
+impl fmt::Display for QuirkProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuirkProfile::Modern => write!(f, "modern"),
+            QuirkProfile::Vip => write!(f, "vip"),
+            QuirkProfile::Schip => write!(f, "schip"),
+        }
+    }
+}
+impl FromStr for QuirkProfile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modern" => Ok(QuirkProfile::Modern),
+            "vip" => Ok(QuirkProfile::Vip),
+            "schip" => Ok(QuirkProfile::Schip),
+            _ => Err(format!("unknown quirk profile: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct OpCode {
     pub higher_byte: u8,
     pub lower_byte: u8,
@@ -44,58 +335,323 @@ pub struct OpCode {
 impl OpCode {
     pub fn get_nnn(&self) -> u16 {
         let full_op_code: u16 = ((self.higher_byte as u16) << 8) | self.lower_byte as u16;
-        let nnn = full_op_code & 0xFFF;
-        nnn as u16
+        full_op_code & 0xFFF
+    }
+    //approximate machine cycles spent by the COSMAC VIP interpreter on this
+    //opcode, including fetch and decode
+    pub fn vip_cycles(&self) -> u32 {
+        match self.higher_byte >> 4 {
+            0x0 => match self.lower_byte {
+                0xe0 => 24,
+                _ => 23,
+            },
+            0x1 | 0x2 | 0xB => 23,
+            0x3 | 0x4 | 0xA => 12,
+            0x5 | 0x9 => 16,
+            0x6 => 6,
+            0x7 => 10,
+            0x8 => 44,
+            0xC => 36,
+            //row count dominates, the wait for vblank is handled separately
+            0xD => 12 + 10 * (self.lower_byte & 0xF) as u32,
+            0xE => 16,
+            0xF => match self.lower_byte {
+                0x07 | 0x15 | 0x18 => 10,
+                0x0A => 16,
+                0x1E => 19,
+                0x29 => 20,
+                0x33 => 204,
+                0x55 | 0x65 => 29 + 14 * (self.higher_byte & 0xF) as u32,
+                _ => 10,
+            },
+            _ => 10,
+        }
     }
 }
-impl chip8 {
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Chip8 {
     pub fn new() -> Self {
+        let seed = rand::random();
         let mut chip8 = Self {
             registers: [0; 16],
-            pc: 0x200,
-            memory: [0; 4096],
-            stack: [0; 16],
+            pc: DEFAULT_LOAD_ADDRESS,
+            bus: Box::new(MemoryBus::new()),
+            stack: Vec::new(),
             index: 0,
-            sp: 0,
             delay_timer: 0,
             sound_timer: 0,
             keypad: [false; 16],
             video: [false; 64 * 32],
-            rng: rand::thread_rng(),
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            quirks: QuirkProfile::Modern.quirks(),
+            frame: 0,
+            timing: Timing::Fixed,
+            cycle_balance: 0,
+            mid_frame: false,
+            waiting_for_vblank: false,
+            load_address: DEFAULT_LOAD_ADDRESS,
+            entry_point: DEFAULT_LOAD_ADDRESS,
+            memory_policy: MemoryPolicy::Wrap,
+            logged_accesses: 0,
+            tracer: None,
+            traced_writes: None,
+            profile: None,
+            breakpoints: Breakpoints::default(),
+            symbols: None,
         };
         chip8.load_fontset();
         chip8
     }
     pub fn get_random_number(&mut self) -> u8 {
-        let random = self.rng.gen_range(0..255);
-        random
+        self.rng.gen_range(0..255)
     }
     pub fn reset(&mut self) {
-        self.pc = 0x200;
-        self.sp = 0;
+        self.pc = self.entry_point;
         self.delay_timer = 0;
         self.sound_timer = 0;
         self.keypad = [false; 16];
         self.video = [false; 64 * 32];
         self.registers = [0; 16];
-        self.memory = [0; 4096];
-        self.stack = [0; 16];
+        self.bus.ram_mut().fill(0);
+        self.stack.clear();
         self.index = 0;
+        self.cycle_balance = 0;
+        self.mid_frame = false;
+        self.waiting_for_vblank = false;
+        self.frame = 0;
+        self.logged_accesses = 0;
+        self.rng = StdRng::seed_from_u64(self.seed);
         self.load_fontset();
     }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    //takes effect immediately and on every reset after that
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+    //a fault stopped the last run_frame partway, resume with run_frame(0)
+    //instead of starting a new frame
+    pub fn mid_frame(&self) -> bool {
+        self.mid_frame
+    }
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+        self.cycle_balance = 0;
+        self.mid_frame = false;
+        self.waiting_for_vblank = false;
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+    //used by the next load_program
+    pub fn set_load_address(&mut self, address: u16) {
+        self.load_address = address;
+    }
+    pub fn entry_point(&self) -> u16 {
+        self.entry_point
+    }
+    //takes effect immediately and on every reset after that
+    pub fn set_entry_point(&mut self, address: u16) {
+        self.entry_point = address;
+        self.pc = address;
+    }
+
+    pub fn memory_policy(&self) -> MemoryPolicy {
+        self.memory_policy
+    }
+    pub fn set_memory_policy(&mut self, policy: MemoryPolicy) {
+        self.memory_policy = policy;
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_deref()
+    }
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_deref_mut()
+    }
+    //kept across resets
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer.map(Box::new);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.set_symbols(self.symbols.clone());
+        }
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+    //kept across resets, so it covers a whole session
+    pub fn set_profile(&mut self, profile: Option<Profile>) {
+        self.profile = profile.map(Box::new);
+    }
+
+    pub fn symbols(&self) -> Option<&DebugMap> {
+        self.symbols.as_deref()
+    }
+    //shared with the tracer
+    pub fn set_symbols(&mut self, symbols: Option<Arc<DebugMap>>) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.set_symbols(symbols.clone());
+        }
+        self.symbols = symbols;
+    }
+
+    pub fn bus(&self) -> &dyn Bus {
+        self.bus.as_ref()
+    }
+    pub fn bus_mut(&mut self) -> &mut dyn Bus {
+        self.bus.as_mut()
+    }
+    //swaps in a different bus, the ram of the old one is carried over
+    pub fn set_bus(&mut self, mut bus: Box<dyn Bus>) {
+        bus.ram_mut().copy_from_slice(self.bus.ram());
+        self.bus = bus;
+    }
 
     fn load_fontset(&mut self) {
-        self.memory[..80].copy_from_slice(&FONTSET);
+        let start = FONTSET_START_ADDRESS as usize;
+        self.bus.ram_mut()[start..start + FONTSET.len()].copy_from_slice(&FONTSET);
+    }
+    pub fn load_program(&mut self, rom_buffer: &[u8]) -> Result<(), String> {
+        let start = self.load_address as usize;
+        let space = MEMORY_SIZE.saturating_sub(start);
+        if rom_buffer.len() > space {
+            return Err(format!(
+                "rom is {} bytes, only {} fit when loaded at {:#05x}",
+                rom_buffer.len(),
+                space,
+                start
+            ));
+        }
+        self.bus.ram_mut()[start..start + rom_buffer.len()].copy_from_slice(rom_buffer);
+        Ok(())
+    }
+    //on a fault pc is left on the faulting instruction, so running again
+    //faults again until the machine is reset
+    pub fn emulate_cycle(&mut self) -> Result<(), Fault> {
+        let pc = self.pc;
+        if !self.breakpoints.is_empty() && self.breakpoints.stops_at(pc) {
+            return Err(Fault {
+                pc,
+                kind: FaultKind::Breakpoint,
+            });
+        }
+        let before = match &self.tracer {
+            Some(tracer) if tracer.wants_frame(self.frame) => Some((self.registers, self.index)),
+            _ => None,
+        };
+        if before.is_some() {
+            self.traced_writes = Some(Vec::new());
+        }
+        self.breakpoints.start_instruction();
+        let result = self
+            .fetch()
+            .and_then(|op_code| self.decode_and_execute(op_code).map(|()| op_code));
+        let writes = self.traced_writes.take();
+        let op_code = result.map_err(|kind| {
+            self.pc = pc;
+            Fault { pc, kind }
+        })?;
+        let opcode = u16::from_be_bytes([op_code.higher_byte, op_code.lower_byte]);
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, opcode);
+        }
+        if let (Some(tracer), Some((registers, index))) = (&mut self.tracer, before) {
+            tracer.record(TraceStep::new(
+                self.frame,
+                pc,
+                opcode,
+                (&registers, index),
+                (&self.registers, self.index),
+                writes.unwrap_or_default(),
+            ));
+        }
+        if self.timing == Timing::Vip {
+            self.cycle_balance -= op_code.vip_cycles() as i64;
+        }
+        //breakpoints().hits() has every watched access, not just this one
+        if let Some((address, write, watch)) = self.breakpoints.hit() {
+            return Err(Fault {
+                pc: self.pc,
+                kind: FaultKind::Watchpoint {
+                    address,
+                    write,
+                    watch,
+                },
+            });
+        }
+        Ok(())
     }
-    pub fn load_rom(&mut self, path: &str) {
-        let rom_buffer = file_utils::read_file_to_buffer(path);
-        println!("{:?}", rom_buffer);
-        let rombuffer_length = rom_buffer.len();
-        self.memory[0x200..(0x200 + rombuffer_length)].copy_from_slice(&rom_buffer);
+    //run one 60hz frame: `budget` instructions with Timing::Fixed or machine
+    //cycles with Timing::Vip, then the timers tick. a fault ends the frame
+    //early without ticking the timers, what is left of the budget carries
+    //over so run_frame(0) finishes the frame
+    pub fn run_frame(&mut self, budget: u32) -> Result<(), Fault> {
+        self.mid_frame = true;
+        match self.timing {
+            Timing::Fixed => {
+                self.cycle_balance += budget as i64;
+                while self.cycle_balance > 0 {
+                    let result = self.emulate_cycle();
+                    //a watchpoint stops after its instruction ran
+                    if result.is_ok()
+                        || matches!(
+                            result,
+                            Err(Fault {
+                                kind: FaultKind::Watchpoint { .. },
+                                ..
+                            })
+                        )
+                    {
+                        self.cycle_balance -= 1;
+                    }
+                    result?;
+                }
+                self.tick_timers();
+            }
+            Timing::Vip => {
+                self.cycle_balance += budget as i64;
+                while self.cycle_balance > 0 && !self.waiting_for_vblank {
+                    self.emulate_cycle()?;
+                }
+                self.vertical_blank();
+            }
+        }
+        self.frame += 1;
+        self.mid_frame = false;
+        if let Some(profile) = &mut self.profile {
+            profile.end_frame();
+        }
+        Ok(())
     }
-    pub fn emulate_cycle(&mut self) {
-        let op_code = self.fetch();
-        self.decode_and_execute(op_code);
+    //display interrupt: the VIP decrements the timers here and releases a DXYN
+    //waiting for the frame to end, whatever was left of the frame is lost
+    fn vertical_blank(&mut self) {
+        if self.waiting_for_vblank {
+            self.waiting_for_vblank = false;
+            self.cycle_balance = self.cycle_balance.min(0);
+        }
+        self.tick_timers();
     }
     pub fn keypress(&mut self, key: usize, value: bool) {
         self.keypad[key] = value;
@@ -103,18 +659,132 @@ impl chip8 {
     pub fn get_display(&self) -> &[bool] {
         &self.video
     }
-    fn fetch(&mut self) -> OpCode {
-        let higher_byte = self.memory[self.pc as usize];
-        let lower_byte = self.memory[(self.pc + 1) as usize];
-        let op_code = OpCode {
-            higher_byte,
-            lower_byte,
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+    //return addresses currently on the stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+    pub fn keypad(&self) -> &[bool; 16] {
+        &self.keypad
+    }
+    pub fn memory(&self) -> &[u8] {
+        self.bus.ram()
+    }
+    //what the program would read at an address, following the bus's mirrors.
+    //None for devices, reading those has side effects
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        self.bus.peek(address % MEMORY_SIZE as u16)
+    }
+    //for debuggers, straight into ram without going through the bus
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.bus.ram_mut()[address as usize % MEMORY_SIZE] = value;
+    }
+    pub fn set_register(&mut self, register: usize, value: u8) {
+        self.registers[register & 0xf] = value;
+    }
+    pub fn set_index(&mut self, value: u16) {
+        self.index = value;
+    }
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+    //drops return addresses until the stack is at most this deep
+    pub fn truncate_stack(&mut self, depth: usize) {
+        self.stack.truncate(depth);
+    }
+    //kept across resets
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+    //every memory access made by the program goes through read and write so
+    //that the memory policy applies to all of them, then on to the bus
+    fn read(&mut self, address: u16) -> Result<u8, FaultKind> {
+        let Some(address) = self.resolve(address, "read")? else {
+            return Ok(0);
+        };
+        if !self.breakpoints.is_empty() {
+            self.breakpoints.check_access(address, false);
+        }
+        self.bus.read(address)
+    }
+    fn write(&mut self, address: u16, value: u8) -> Result<(), FaultKind> {
+        let Some(address) = self.resolve(address, "write")? else {
+            return Ok(());
         };
-        self.pc += 2;
+        //through mirrors, device writes have no old value and aren't traced
+        let old = self.bus.peek(address);
+        self.bus.write(address, value)?;
+        if !self.breakpoints.is_empty() {
+            self.breakpoints.check_access(address, true);
+        }
+        if let (Some(writes), Some(old)) = (&mut self.traced_writes, old) {
+            writes.push((address, old, value));
+        }
+        Ok(())
+    }
+    fn resolve(&mut self, address: u16, access: &str) -> Result<Option<u16>, FaultKind> {
+        if (address as usize) < MEMORY_SIZE {
+            return Ok(Some(address));
+        }
+        match self.memory_policy {
+            MemoryPolicy::Wrap => Ok(Some(address % MEMORY_SIZE as u16)),
+            MemoryPolicy::Fault => Err(FaultKind::MemoryAccess { address }),
+            MemoryPolicy::Log => {
+                if self.logged_accesses < MEMORY_LOG_LIMIT {
+                    eprintln!(
+                        "Ignored {} of {:#06x} near {:#05x}, past the end of memory",
+                        access, address, self.pc
+                    );
+                } else if self.logged_accesses == MEMORY_LOG_LIMIT {
+                    eprintln!(
+                        "Ignoring more accesses past the end of memory without printing them"
+                    );
+                }
+                self.logged_accesses = self.logged_accesses.saturating_add(1);
+                Ok(None)
+            }
+        }
+    }
+    fn fetch_byte(&mut self, address: u16) -> Result<u8, FaultKind> {
+        match self.resolve(address, "fetch")? {
+            Some(address) => self.bus.fetch(address),
+            None => Ok(0),
+        }
+    }
+    fn fetch(&mut self) -> Result<OpCode, FaultKind> {
+        let higher_byte = self.fetch_byte(self.pc)?;
+        let lower_byte = self.fetch_byte(self.pc.wrapping_add(1))?;
+        self.pc = self.pc.wrapping_add(2);
 
-        op_code
+        Ok(OpCode {
+            higher_byte,
+            lower_byte,
+        })
     }
-    fn decode_and_execute(&mut self, op_code: OpCode) {
+    fn decode_and_execute(&mut self, op_code: OpCode) -> Result<(), FaultKind> {
         let first_half_byte = op_code.higher_byte >> 4;
         match first_half_byte {
             0x0 => {
@@ -125,11 +795,10 @@ impl chip8 {
                     }
                     //return from subroutine
                     0xee => {
-                        self.sp -= 1;
-                        self.pc = self.stack[self.sp as usize];
+                        self.pc = self.stack.pop().ok_or(FaultKind::StackUnderflow)?;
                     }
                     _ => {
-                        println!(
+                        eprintln!(
                             "Unknown opcode: {:02X}{:02X}",
                             op_code.higher_byte, op_code.lower_byte
                         );
@@ -142,8 +811,12 @@ impl chip8 {
             }
             //call subroutine
             0x2 => {
-                self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
+                if let StackDepth::Limited(depth) = self.quirks.stack_depth {
+                    if self.stack.len() >= depth {
+                        return Err(FaultKind::StackOverflow { depth });
+                    }
+                }
+                self.stack.push(self.pc);
                 self.pc = op_code.get_nnn();
             }
             //skip next instruction if Vx = kk
@@ -196,18 +869,27 @@ impl chip8 {
                         let vx: u8 = op_code.higher_byte & 0xF;
                         let vy: u8 = op_code.lower_byte >> 4;
                         self.registers[vx as usize] |= self.registers[vy as usize];
+                        if self.quirks.vf_reset {
+                            self.registers[0xF] = 0;
+                        }
                     }
                     //set Vx = Vx & Vy
                     0x2 => {
                         let vx: u8 = op_code.higher_byte & 0xF;
                         let vy: u8 = op_code.lower_byte >> 4;
                         self.registers[vx as usize] &= self.registers[vy as usize];
+                        if self.quirks.vf_reset {
+                            self.registers[0xF] = 0;
+                        }
                     }
                     //set Vx = Vx ^ Vy
                     0x3 => {
                         let vx: u8 = op_code.higher_byte & 0xF;
                         let vy: u8 = op_code.lower_byte >> 4;
                         self.registers[vx as usize] ^= self.registers[vy as usize];
+                        if self.quirks.vf_reset {
+                            self.registers[0xF] = 0;
+                        }
                     }
                     //set Vx = Vx + Vy, set VF = carry
                     0x4 => {
@@ -232,6 +914,10 @@ impl chip8 {
                     //set Vx = Vx SHR 1
                     0x6 => {
                         let vx: u8 = op_code.higher_byte & 0xF;
+                        if self.quirks.shift_uses_vy {
+                            let vy: u8 = op_code.lower_byte >> 4;
+                            self.registers[vx as usize] = self.registers[vy as usize];
+                        }
                         self.registers[0xF] = self.registers[vx as usize] & 0x1;
                         self.registers[vx as usize] >>= 1;
                     }
@@ -248,11 +934,15 @@ impl chip8 {
                     //set Vx = Vx SHL 1
                     0xE => {
                         let vx: u8 = op_code.higher_byte & 0xF;
+                        if self.quirks.shift_uses_vy {
+                            let vy: u8 = op_code.lower_byte >> 4;
+                            self.registers[vx as usize] = self.registers[vy as usize];
+                        }
                         self.registers[0xF] = self.registers[vx as usize] >> 7;
                         self.registers[vx as usize] <<= 1;
                     }
                     _ => {
-                        println!(
+                        eprintln!(
                             "Unknown opcode: {:02X}{:02X}",
                             op_code.higher_byte, op_code.lower_byte
                         );
@@ -273,7 +963,12 @@ impl chip8 {
             }
             //jump to address nnn+V0
             0xB => {
-                self.pc = op_code.get_nnn() + self.registers[0] as u16;
+                let vx = if self.quirks.jump_uses_vx {
+                    op_code.higher_byte & 0xF
+                } else {
+                    0
+                };
+                self.pc = op_code.get_nnn() + self.registers[vx as usize] as u16;
             }
             //set Vx = random byte AND kk
             0xC => {
@@ -294,27 +989,34 @@ impl chip8 {
 
                 for row in 0..height {
                     //for each sprite get current byte corresponding to the row
-                    let sprite_byte = self.memory[(self.index + row as u16) as usize];
+                    let sprite_byte = self.read(self.index.wrapping_add(row as u16))?;
 
                     for col in 0..8 {
                         //get sprite pixel using current row and mask 10000000 shifted by col
                         let sprite_pixel: u8 = sprite_byte & (0x80 >> col);
-                        println!("Binary: {:08b}", sprite_pixel);
                         //get video pixel using x and y positions
                         let video_pixel_index = (((y_position + row as u16) % VIDEO_HEIGHT)
                             * VIDEO_WIDTH
                             + ((x_position + col) % VIDEO_WIDTH))
                             as usize;
 
+                        if self.quirks.clip_sprites
+                            && (x_position + col >= VIDEO_WIDTH
+                                || y_position + row as u16 >= VIDEO_HEIGHT)
+                        {
+                            continue;
+                        }
                         if sprite_pixel != 0b00000000 {
-                            if self.video[video_pixel_index] == true {
+                            if self.video[video_pixel_index] {
                                 self.registers[0xF] = 1;
                             }
                             self.video[video_pixel_index] ^= true;
                         }
                     }
                 }
-                // println!("Result video memory: {:?}", self.video);
+                if self.timing == Timing::Vip {
+                    self.waiting_for_vblank = true;
+                }
             }
             //skip if key with value of Vx is pressed
             0xE => {
@@ -336,7 +1038,7 @@ impl chip8 {
                         }
                     }
                     _ => {
-                        println!(
+                        eprintln!(
                             "Unknown opcode: {:02x}{:02x}",
                             op_code.higher_byte, op_code.lower_byte
                         );
@@ -379,55 +1081,66 @@ impl chip8 {
                 //set I = I + Vx
                 0x1E => {
                     let vx: u8 = op_code.higher_byte & 0xF;
-                    self.index = self.index + self.registers[vx as usize] as u16;
+                    self.index = self.index.wrapping_add(self.registers[vx as usize] as u16);
                 }
                 //set I = location of sprite for digit Vx
                 0x29 => {
                     let vx: u8 = op_code.higher_byte & 0xF;
                     let digit = self.registers[vx as usize];
-                    self.index = (FONTSET_START_ADDRESS + (5 * digit as u8)) as u16;
+                    self.index = FONTSET_START_ADDRESS + 5 * (digit & 0xF) as u16;
                 }
                 //store BCD representation of Vx in memory locations I, I+1, and I+2
                 0x33 => {
                     let vx: u8 = op_code.higher_byte & 0xF;
                     let mut value = self.registers[vx as usize];
                     //ones digit
-                    self.memory[(self.index + 2) as usize] = value % 10;
+                    self.write(self.index.wrapping_add(2), value % 10)?;
                     value /= 10;
                     //tens digit
-                    self.memory[(self.index + 1) as usize] = value % 10;
+                    self.write(self.index.wrapping_add(1), value % 10)?;
                     value /= 10;
                     //hundreds digit
-                    self.memory[self.index as usize] = value % 10;
+                    self.write(self.index, value % 10)?;
                 }
                 //store registers V0 through Vx in memory starting at location I
                 0x55 => {
                     let vx: u8 = op_code.higher_byte & 0xF;
                     for i in 0..=vx {
-                        self.memory[(self.index + i as u16) as usize] = self.registers[i as usize];
+                        self.write(
+                            self.index.wrapping_add(i as u16),
+                            self.registers[i as usize],
+                        )?;
+                    }
+                    if self.quirks.memory_increment {
+                        self.index = self.index.wrapping_add(vx as u16 + 1);
                     }
                 }
                 //read registers V0 through Vx from memory starting at location I
                 0x65 => {
                     let vx: u8 = op_code.higher_byte & 0xF;
                     for i in 0..=vx {
-                        self.registers[i as usize] = self.memory[(self.index + i as u16) as usize];
+                        self.registers[i as usize] =
+                            self.read(self.index.wrapping_add(i as u16))?;
+                    }
+                    if self.quirks.memory_increment {
+                        self.index = self.index.wrapping_add(vx as u16 + 1);
                     }
                 }
                 _ => {
-                    println!(
+                    eprintln!(
                         "Unknown opcode: {:02x}{:02x}",
                         op_code.higher_byte, op_code.lower_byte
                     );
                 }
             },
             _ => {
-                println!(
+                eprintln!(
                     "Unknown opcode: {:02X}{:02X}",
                     op_code.higher_byte, op_code.lower_byte
                 );
             }
         }
+        Ok(())
     }
     pub fn tick_timers(&mut self) {
         //DECREMENT TIMERS
@@ -439,3 +1152,208 @@ impl chip8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Watchpoint;
+
+    //runs one instruction per word of the program with the quirks given
+    fn run(quirks: Quirks, program: &[u8], instructions: usize) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.set_quirks(quirks);
+        chip8.load_program(program).unwrap();
+        for _ in 0..instructions {
+            chip8.emulate_cycle().unwrap();
+        }
+        chip8
+    }
+
+    //the modern profile with only the quirk that `enable` turns on
+    fn with(enable: impl Fn(&mut Quirks)) -> Quirks {
+        let mut quirks = QuirkProfile::Modern.quirks();
+        enable(&mut quirks);
+        quirks
+    }
+
+    #[test]
+    fn vf_reset() {
+        //V0 = 0F, V1 = F0, VF = 1, V0 |= V1
+        let program = [0x60, 0x0f, 0x61, 0xf0, 0x6f, 0x01, 0x80, 0x11];
+        let off = run(with(|_| {}), &program, 4);
+        let on = run(with(|q| q.vf_reset = true), &program, 4);
+        assert_eq!(off.registers()[0], 0xff);
+        assert_eq!(off.registers()[0xf], 1);
+        assert_eq!(on.registers()[0], 0xff);
+        assert_eq!(on.registers()[0xf], 0);
+    }
+
+    #[test]
+    fn shift_uses_vy() {
+        //V0 = 01, V1 = 04, V0 >>= 1
+        let program = [0x60, 0x01, 0x61, 0x04, 0x80, 0x16];
+        let off = run(with(|_| {}), &program, 3);
+        let on = run(with(|q| q.shift_uses_vy = true), &program, 3);
+        assert_eq!((off.registers()[0], off.registers()[0xf]), (0, 1));
+        assert_eq!((on.registers()[0], on.registers()[0xf]), (2, 0));
+    }
+
+    #[test]
+    fn jump_uses_vx() {
+        //V0 = 04, V2 = 08, jump to 204 plus V0 or V2
+        let program = [0x60, 0x04, 0x62, 0x08, 0xb2, 0x04];
+        let off = run(with(|_| {}), &program, 3);
+        let on = run(with(|q| q.jump_uses_vx = true), &program, 3);
+        assert_eq!(off.pc(), 0x208);
+        assert_eq!(on.pc(), 0x20c);
+    }
+
+    #[test]
+    fn clip_sprites() {
+        //an 8 pixel wide line at x 62, y 0
+        let program = [0x60, 0x3e, 0x61, 0x00, 0xa2, 0x08, 0xd0, 0x11, 0xff];
+        let off = run(with(|_| {}), &program, 4);
+        let on = run(with(|q| q.clip_sprites = true), &program, 4);
+        let lit = |chip8: &Chip8| chip8.get_display().iter().filter(|&&p| p).count();
+        assert_eq!(lit(&off), 8);
+        assert!(off.get_display()[0] && off.get_display()[5]);
+        assert_eq!(lit(&on), 2);
+        assert!(on.get_display()[62] && on.get_display()[63]);
+    }
+
+    #[test]
+    fn memory_increment() {
+        //I = 300, V0 = 01, V1 = 02, store V0-V1, load V0-V1
+        let program = [0xa3, 0x00, 0x60, 0x01, 0x61, 0x02, 0xf1, 0x55, 0xf1, 0x65];
+        let off = run(with(|_| {}), &program, 4);
+        let on = run(with(|q| q.memory_increment = true), &program, 4);
+        assert_eq!(off.index(), 0x300);
+        assert_eq!(on.index(), 0x302);
+        assert_eq!(&on.memory()[0x300..0x302], [1, 2]);
+        let off = run(with(|_| {}), &program, 5);
+        let on = run(with(|q| q.memory_increment = true), &program, 5);
+        assert_eq!(off.index(), 0x300);
+        assert_eq!(on.index(), 0x304);
+        //reading past the stored registers gives 0
+        assert_eq!(&on.registers()[..2], [0, 0]);
+        assert_eq!(&off.registers()[..2], [1, 2]);
+    }
+
+    #[test]
+    fn stack_depth() {
+        //call itself forever
+        let program = [0x22, 0x00];
+        let mut chip8 = run(
+            with(|q| q.stack_depth = StackDepth::Limited(3)),
+            &program,
+            3,
+        );
+        assert_eq!(chip8.stack().len(), 3);
+        assert!(chip8.emulate_cycle().is_err());
+        let unlimited = run(
+            with(|q| q.stack_depth = StackDepth::Unlimited),
+            &program,
+            100,
+        );
+        assert_eq!(unlimited.stack().len(), 100);
+    }
+
+    #[test]
+    fn memory_policies() {
+        //I = fff, V0 = 0a, V1 = 0b, store V0-V1 across the end of memory
+        let program = [0xaf, 0xff, 0x60, 0x0a, 0x61, 0x0b, 0xf1, 0x55];
+        let machine = |policy| {
+            let mut chip8 = Chip8::new();
+            chip8.set_memory_policy(policy);
+            chip8.load_program(&program).unwrap();
+            for _ in 0..3 {
+                chip8.emulate_cycle().unwrap();
+            }
+            chip8
+        };
+        let mut wrap = machine(MemoryPolicy::Wrap);
+        wrap.emulate_cycle().unwrap();
+        assert_eq!((wrap.memory()[0xfff], wrap.memory()[0]), (0x0a, 0x0b));
+        let mut fault = machine(MemoryPolicy::Fault);
+        let error = fault.emulate_cycle().unwrap_err();
+        assert_eq!(error.pc, 0x206);
+        assert!(matches!(
+            error.kind,
+            FaultKind::MemoryAccess { address: 0x1000 }
+        ));
+        let mut log = machine(MemoryPolicy::Log);
+        for _ in 0..MEMORY_LOG_LIMIT + 4 {
+            log.set_pc(0x206);
+            log.emulate_cycle().unwrap();
+        }
+        assert_eq!((log.memory()[0xfff], log.memory()[0]), (0x0a, 0));
+        assert_eq!(log.logged_accesses, MEMORY_LOG_LIMIT + 4);
+        assert!("64k".parse::<MemoryPolicy>().is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let vip = QuirkProfile::Vip.quirks();
+        assert!(vip.vf_reset && vip.memory_increment && vip.shift_uses_vy && vip.clip_sprites);
+        assert!(!vip.jump_uses_vx);
+        let schip = QuirkProfile::Schip.quirks();
+        assert!(schip.jump_uses_vx && schip.clip_sprites);
+        assert!(!schip.vf_reset && !schip.memory_increment && !schip.shift_uses_vy);
+    }
+
+    #[test]
+    fn watchpoints_see_every_write() {
+        //I = 300, V0 = 01, V1 = 02, V2 = 03, store V0-V2
+        let program = [0xa3, 0x00, 0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xf2, 0x55];
+        let mut chip8 = run(with(|_| {}), &program, 4);
+        chip8.breakpoints_mut().add_hook_watch(Watchpoint {
+            start: 0x300,
+            length: 1,
+            kind: WatchKind::Write,
+        });
+        chip8.breakpoints_mut().add_watch(Watchpoint {
+            start: 0x302,
+            length: 1,
+            kind: WatchKind::Write,
+        });
+        let fault = chip8.emulate_cycle().unwrap_err();
+        //the fault names the user's watchpoint, the hits both
+        assert_eq!(
+            fault.kind,
+            FaultKind::Watchpoint {
+                address: 0x302,
+                write: true,
+                watch: WatchKind::Write
+            }
+        );
+        assert_eq!(chip8.breakpoints().hits(), [(0x300, true), (0x302, true)]);
+    }
+
+    #[test]
+    fn hooks_and_breakpoints_are_apart() {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&[0x12, 0x00]).unwrap();
+        chip8.breakpoints_mut().add_hook(0x200);
+        chip8.breakpoints_mut().add(0x200);
+        //a debugger dropping its breakpoints keeps the hook
+        chip8.breakpoints_mut().clear();
+        assert!(!chip8.breakpoints().contains(0x200));
+        let fault = chip8.emulate_cycle().unwrap_err();
+        assert_eq!(fault.kind, FaultKind::Breakpoint);
+    }
+
+    #[test]
+    fn resumed_frames_keep_their_budget() {
+        //V0 += 1, jump back
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        chip8.breakpoints_mut().add(0x202);
+        assert_eq!(chip8.run_frame(10).unwrap_err().kind, FaultKind::Breakpoint);
+        assert!(chip8.mid_frame());
+        chip8.breakpoints_mut().remove(0x202);
+        chip8.run_frame(0).unwrap();
+        assert!(!chip8.mid_frame());
+        assert_eq!(chip8.frame_count(), 1);
+        assert_eq!(chip8.registers()[0], 5);
+    }
+}
@@ -0,0 +1,322 @@
+use super::settings::Settings;
+
+pub const USAGE: &str = "\
+Usage: chip_8_emulator [run] <rom> [options]
+       chip_8_emulator test <rom> [options]
+       chip_8_emulator disasm <rom> [-o listing.asm] [--origin ADDR]
+       chip_8_emulator asm <source.asm> [-o game.ch8] [--origin ADDR]
+       chip_8_emulator info <rom>
+       chip_8_emulator tracediff <trace> <trace>
+       chip_8_emulator gym <rom> [options]
+       chip_8_emulator --dap [options]
+
+A rom can be a binary, hex text, an Octo cartridge .gif, a .zip holding one
+rom, .asm or Octo .8o source, or - to read it from stdin.
+
+Subcommands:
+  run          play a rom in a window (the default)
+  test         run a rom without input and print the final screen
+  disasm       print a listing of a rom
+  asm          assemble a listing back into a rom, with a .map of its
+               labels and lines that disasm, traces and debuggers pick up
+  info         print the size and sha1 of a rom
+  tracediff    find where two --trace files, or another emulator's log,
+               first differ
+  gym          serve the rom as reinforcement learning environments on
+               stdin and stdout, see Gym below
+
+Options:
+  --scale N          window pixels per chip-8 pixel (default 10)
+  --ips N            instructions per second (default 600)
+  --speed X          multiple of real time to run at (default 1.0)
+  --turbo X          speed while tab is held, a multiple or unthrottled
+                     (default)
+  --quirks NAME      modern, vip or schip
+  --timing NAME      fixed or vip
+  --stack DEPTH      nested calls allowed, or unlimited (vip 12, others 16)
+  --memory POLICY    accesses past 4K: wrap (default), fault or log, which
+                     prints the first few. there is no 64K mode
+  --protect AREAS    make font, interpreter and/or rom read-only, e.g. font,rom
+  --trace DEST       trace instructions to ring (last 64, printed on a fault),
+                     ring:N, a .txt/.log file or any other file in binary
+  --trace-pc RANGE   only trace addresses in a hex range, e.g. 200-2ff
+  --trace-ops LIST   only trace opcodes starting with these digits, e.g. d,f
+  --trace-frames R   only trace frames in a range, e.g. 100-200
+  --profile FILE     count executions per address and save a report when the
+                     run ends, or an annotated listing for .asm and .lst
+  --break LIST       stop at these game.asm:LINE locations, labels or hex
+                     addresses, comma separated. headless runs print the
+                     machine state and carry on
+  --gdb PORT         let gdb attach on 127.0.0.1:PORT (target remote :PORT).
+                     headless runs wait for it and, with no other limit,
+                     end when it detaches
+  --dap              serve the Debug Adapter Protocol on stdin and stdout for
+                     editors, the rom comes from the launch request
+  --script FILE      drive a run without a window with a Rhai script that
+                     hooks frames, addresses and memory writes, reads and
+                     changes the machine, presses keys, takes screenshots
+                     and asserts, see src/script.rs
+  --seed N           seed for the random number generator
+  --palette NAME     white, green, amber, gameboy, inverted or RRGGBB,RRGGBB
+  --scanlines, --grid, --bloom, --curvature, --vignette
+                     start with these crt effects on, F1-F5 toggle them
+  --renderer NAME    accelerated (default) or software for machines without
+                     a GPU
+  --keymap NAME      qwerty, qwertz, dvorak or 16 keys in keypad order 0-F
+  --mute             no sound
+  --headless         run without a window, needs --frames, --movie, --script
+                     or --gdb
+  --frames N         stop after N frames
+  --debug            start paused and print the machine state on every step
+  --watch            restart when the rom file changes, .asm and .8o too
+  --movie FILE       replay a movie recorded with F9
+  --record FILE      capture every frame to a .gif or raw .rgb file
+  --gif-skip N       keep every Nth frame in gifs (default 1, all of them)
+  --title NAME       window title, known roms use their name
+  --origin ADDR      hex address the rom is loaded at (default 200, ETI-660 600)
+  --entry ADDR       hex address execution starts at (default the origin)
+  --reward LIST      gym reward, the change in memory values like bcd:2f2 or
+                     -byte:lives, comma separated. byte, word or bcd
+  --done LIST        gym episodes end when any of these holds, e.g. byte:2f6=0
+                     or bcd:score>=100. --frames caps their length
+  --frame-skip N     gym frames per step (default 1)
+  --sticky P         gym chance of a frame keeping the last keys (default 0)
+  --envs N           gym instances, stepped together (default 1)
+  -o, --output FILE  output file for disasm and asm
+  -h, --help         show this help
+  -V, --version      show the version
+
+Gym:
+  The first line out is 'chip8-gym N 64 32' for N instances, then gym reads
+  one command per line:
+    reset SEED [N]   reset every instance, the nth with seed SEED+n, or only
+                     instance N. answers with an observation per instance
+    step KEYS...     a hex key mask per instance, bit n holds key n down.
+                     answers with 'REWARD DONE OBSERVATION' per instance,
+                     DONE is 1 when the episode is over
+    quit
+  An observation is the screen in hex, a row at a time with the leftmost
+  pixel in the top bit. Bad commands get 'error MESSAGE'.
+
+Defaults for these options, and overrides for single roms, are read from
+$XDG_CONFIG_HOME/chip_8_emulator/config.ini. Known roms get their title and
+recommended quirks and speed from a bundled database, over the defaults but
+not the overrides. Options given here win.";
+
+//options that take a value, named like the settings they set
+const VALUE_OPTIONS: [&str; 33] = [
+    "scale",
+    "ips",
+    "speed",
+    "turbo",
+    "quirks",
+    "timing",
+    "seed",
+    "palette",
+    "renderer",
+    "keymap",
+    "frames",
+    "movie",
+    "record",
+    "gif-skip",
+    "title",
+    "origin",
+    "entry",
+    "stack",
+    "memory",
+    "protect",
+    "trace",
+    "trace-pc",
+    "trace-ops",
+    "trace-frames",
+    "profile",
+    "gdb",
+    "break",
+    "script",
+    "reward",
+    "done",
+    "frame-skip",
+    "sticky",
+    "envs",
+];
+const FLAG_OPTIONS: [&str; 9] = [
+    "mute",
+    "headless",
+    "debug",
+    "watch",
+    "scanlines",
+    "grid",
+    "bloom",
+    "curvature",
+    "vignette",
+];
+
+pub enum Command {
+    Run {
+        rom: String,
+        overrides: Vec<(String, String)>,
+    },
+    Test {
+        rom: String,
+        overrides: Vec<(String, String)>,
+    },
+    //overrides only hold --origin
+    Disasm {
+        rom: String,
+        output: Option<String>,
+        overrides: Vec<(String, String)>,
+    },
+    Asm {
+        source: String,
+        output: Option<String>,
+        overrides: Vec<(String, String)>,
+    },
+    Info {
+        rom: String,
+    },
+    TraceDiff {
+        a: String,
+        b: String,
+    },
+    Gym {
+        rom: String,
+        overrides: Vec<(String, String)>,
+    },
+    //options are defaults for the launch request
+    Dap {
+        overrides: Vec<(String, String)>,
+    },
+    Help,
+    Version,
+}
+
+//args without the program name
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (subcommand, args) = match args.first().map(String::as_str) {
+        Some(name @ ("run" | "test" | "disasm" | "asm" | "info" | "tracediff" | "gym")) => {
+            (name, &args[1..])
+        }
+        _ => ("run", args),
+    };
+    let mut positional = Vec::new();
+    let mut overrides = Vec::new();
+    let mut output = None;
+    let mut dap = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        //a lone - is stdin
+        let Some(option) = arg
+            .strip_prefix("--")
+            .or(arg.strip_prefix('-'))
+            .filter(|option| !option.is_empty() || arg == "--")
+        else {
+            positional.push(arg.clone());
+            continue;
+        };
+        let (name, inline_value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (option, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or(format!("{} needs a value", arg))
+        };
+        match name {
+            "h" | "help" => return Ok(Command::Help),
+            "V" | "version" => return Ok(Command::Version),
+            "o" | "output" => output = Some(value()?),
+            "dap" => dap = true,
+            _ if VALUE_OPTIONS.contains(&name) => overrides.push((name.to_string(), value()?)),
+            _ if FLAG_OPTIONS.contains(&name) => overrides.push((
+                name.to_string(),
+                inline_value.unwrap_or_else(|| String::from("true")),
+            )),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    //catch bad values here instead of after a window has opened
+    let mut settings = Settings::default();
+    for (key, value) in &overrides {
+        settings
+            .set(key, value)
+            .map_err(|e| format!("--{}: {}", key, e))?;
+    }
+    if dap {
+        if subcommand != "run" || !positional.is_empty() || output.is_some() {
+            return Err(String::from(
+                "--dap only takes run options, the rom comes from the launch request",
+            ));
+        }
+        return Ok(Command::Dap { overrides });
+    }
+    if subcommand == "tracediff" {
+        if !overrides.is_empty() || output.is_some() {
+            return Err(String::from("tracediff only takes two trace files"));
+        }
+        return match positional.as_slice() {
+            [a, b] => Ok(Command::TraceDiff {
+                a: a.clone(),
+                b: b.clone(),
+            }),
+            _ => Err(String::from("tracediff needs two trace files")),
+        };
+    }
+    let path = match positional.as_slice() {
+        [path] => path.clone(),
+        [] => return Err(format!("{} needs a file", subcommand)),
+        _ => return Err(format!("{} takes one file", subcommand)),
+    };
+    let no_run_options = || -> Result<(), String> {
+        if overrides.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} doesn't take run options", subcommand))
+        }
+    };
+    let only_origin = || -> Result<(), String> {
+        if overrides.iter().all(|(key, _)| key == "origin") {
+            Ok(())
+        } else {
+            Err(format!("{} only takes --origin", subcommand))
+        }
+    };
+    if output.is_some() && matches!(subcommand, "run" | "test" | "info" | "gym") {
+        return Err(format!("{} doesn't take --output", subcommand));
+    }
+    Ok(match subcommand {
+        "test" => Command::Test {
+            rom: path,
+            overrides,
+        },
+        "gym" => Command::Gym {
+            rom: path,
+            overrides,
+        },
+        "disasm" => {
+            only_origin()?;
+            Command::Disasm {
+                rom: path,
+                output,
+                overrides,
+            }
+        }
+        "asm" => {
+            only_origin()?;
+            Command::Asm {
+                source: path,
+                output,
+                overrides,
+            }
+        }
+        "info" => {
+            no_run_options()?;
+            Command::Info { rom: path }
+        }
+        _ => Command::Run {
+            rom: path,
+            overrides,
+        },
+    })
+}
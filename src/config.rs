@@ -0,0 +1,189 @@
+use super::settings::Settings;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+//settings file, uses the same keys as the command line flags:
+//
+//  # defaults for every rom
+//  palette = amber
+//  scale = 12
+//  mute = yes
+//
+//  ; overrides for one rom, keyed by the sha1 printed by the info subcommand
+//  [b232ef880bd6060fb45fa6effed7edf0ae95670e]
+//  quirks = vip
+//  ips = 1000
+//  palette = #ffb000,#1a1000  # comments after a value need a space both sides
+const CONFIG_DIR: &str = "chip_8_emulator";
+const CONFIG_FILE: &str = "config.ini";
+
+pub type Entries = Vec<(usize, String, String)>;
+
+#[derive(Default)]
+pub struct Config {
+    global: Entries,
+    //keyed by lowercase rom sha1
+    roms: HashMap<String, Entries>,
+}
+
+//$XDG_CONFIG_HOME/chip_8_emulator/config.ini, falling back to ~/.config
+pub fn config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join(CONFIG_DIR).join(CONFIG_FILE))
+}
+
+impl Config {
+    //a missing file is the same as an empty one
+    pub fn load() -> Result<Config, String> {
+        let Some(path) = config_path() else {
+            return Ok(Config::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+    pub fn parse(text: &str) -> Result<Config, String> {
+        Config::parse_with(text, |key, value| Settings::default().set(key, value))
+    }
+    //same file format with a different check for each key = value pair
+    pub fn parse_with(
+        text: &str,
+        check: impl Fn(&str, &str) -> Result<(), String>,
+    ) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut section: Option<String> = None;
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let sha1 = name.trim().to_lowercase();
+                if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!(
+                        "line {}: section '{}' is not a rom sha1",
+                        number, name
+                    ));
+                }
+                config.roms.entry(sha1.clone()).or_default();
+                section = Some(sha1);
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected key = value", number));
+            };
+            let entry = (number, key.trim().to_string(), value.trim().to_string());
+            //check values now so mistakes show up even for roms that aren't running
+            check(&entry.1, &entry.2).map_err(|e| format!("line {}: {}", number, e))?;
+            match &section {
+                Some(sha1) => config.roms.entry(sha1.clone()).or_default().push(entry),
+                None => config.global.push(entry),
+            }
+        }
+        Ok(config)
+    }
+    pub fn rom_entries(&self, rom_sha1: &str) -> Option<&Entries> {
+        self.roms.get(&rom_sha1.to_lowercase())
+    }
+    //the defaults for every rom, which the rom database overrides
+    pub fn apply_defaults(&self, settings: &mut Settings) -> Result<(), String> {
+        apply_entries(&self.global, settings)
+    }
+    //the section for this rom if there is one
+    pub fn apply_rom(&self, settings: &mut Settings, rom_sha1: &str) -> Result<(), String> {
+        apply_entries(self.rom_entries(rom_sha1).unwrap_or(&Vec::new()), settings)
+    }
+}
+
+fn apply_entries(entries: &Entries, settings: &mut Settings) -> Result<(), String> {
+    for (number, key, value) in entries {
+        settings
+            .set(key, value)
+            .map_err(|e| format!("config line {}: {}", number, e))?;
+    }
+    Ok(())
+}
+
+//# and ; start a comment at the beginning of a line, or after a value when
+//surrounded by whitespace, so #rrggbb colors survive
+fn strip_comment(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with(['#', ';']) {
+        return "";
+    }
+    let bytes = line.as_bytes();
+    for (i, &byte) in bytes.iter().enumerate() {
+        let after = bytes.get(i + 1).is_none_or(u8::is_ascii_whitespace);
+        if (byte == b'#' || byte == b';') && i > 0 && bytes[i - 1].is_ascii_whitespace() && after {
+            return &line[..i];
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(config: &Config) -> Vec<(&str, &str)> {
+        config
+            .global
+            .iter()
+            .map(|(_, key, value)| (key.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn comments() {
+        let config = Config::parse(
+            "# whole line\n\
+             ; also a whole line\n\
+             \t# indented\n\
+             scale = 12 # after a value\n\
+             mute = yes ; after a value\n\
+             quirks = vip #\n",
+        )
+        .unwrap();
+        assert_eq!(
+            values(&config),
+            [("scale", "12"), ("mute", "yes"), ("quirks", "vip")]
+        );
+    }
+
+    #[test]
+    fn hex_colors_are_not_comments() {
+        let config = Config::parse(
+            "palette = #ffb000,#1a1000\n\
+             palette = #ffb000, #1a1000 # amber\n",
+        )
+        .unwrap();
+        assert_eq!(
+            values(&config),
+            [
+                ("palette", "#ffb000,#1a1000"),
+                ("palette", "#ffb000, #1a1000")
+            ]
+        );
+    }
+
+    #[test]
+    fn rom_sections() {
+        let sha1 = "b232ef880bd6060fb45fa6effed7edf0ae95670e";
+        let config = Config::parse(&format!("scale = 5\n[{}] # pong\nips = 1000\n", sha1)).unwrap();
+        assert_eq!(values(&config), [("scale", "5")]);
+        let entries = config.rom_entries(&sha1.to_uppercase()).unwrap();
+        assert_eq!(entries, &[(3, String::from("ips"), String::from("1000"))]);
+        assert!(Config::parse("[pong]\n").is_err());
+        assert!(Config::parse("scale 5\n").is_err());
+        assert!(Config::parse("scale = big\n").is_err());
+    }
+}
@@ -0,0 +1,1100 @@
+use super::chip8::{Chip8, Fault, FaultKind, MEMORY_SIZE};
+use super::debug_map::{self, DebugMap};
+use super::debugger;
+use super::disassembler;
+use super::json::{object, Json};
+use super::loader;
+use super::scheduler::{Scheduler, Speed};
+use super::settings::Settings;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+
+//chip-8 has one thread of execution
+const THREAD_ID: i64 = 1;
+//variablesReference of each scope
+const REGISTERS: i64 = 1;
+const STACK: i64 = 2;
+const TIMERS: i64 = 3;
+
+//Debug Adapter Protocol on stdin and stdout, for editors. the rom comes from
+//the launch request: {"program": "game.asm", "stopOnEntry": true,
+//"settings": {"quirks": "schip"}}. breakpoints can be set on lines of a .asm
+//program, or on addresses as instruction or function breakpoints ("2a4").
+//the machine runs without a window or input, core messages go to stderr
+pub fn serve(overrides: &[(String, String)]) -> Result<(), String> {
+    let (sender, messages) = mpsc::channel();
+    //stdin is read on its own thread so the machine can run while waiting
+    thread::spawn(move || {
+        let mut input = io::stdin().lock();
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut session = Session::new(overrides, io::stdout());
+    loop {
+        let message = match &session.machine {
+            Some(machine) if machine.running => {
+                let wait = machine.scheduler.time_until_next_frame(Instant::now());
+                match messages.recv_timeout(wait) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            _ => match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            },
+        };
+        if let Some(message) = message {
+            match message {
+                Ok(request) => {
+                    if !session.handle(&request)? {
+                        break;
+                    }
+                }
+                Err(e) => eprintln!("Bad debug adapter message: {}", e),
+            }
+        }
+        session.run_due_frames()?;
+    }
+    session.finish();
+    Ok(())
+}
+
+//one Content-Length framed message, None at the end of input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Result<Json, String>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(Json::parse(&String::from_utf8_lossy(&body))))
+}
+
+//a launched rom and how it is being run
+struct Machine {
+    chip8: Chip8,
+    scheduler: Scheduler,
+    running: bool,
+    //where next and stepOut stop: (address, stack depth)
+    step_target: Option<(u16, usize)>,
+}
+
+#[derive(Default)]
+struct Breakpoints {
+    lines: BTreeSet<u16>,
+    instructions: BTreeSet<u16>,
+    functions: BTreeSet<u16>,
+}
+
+//the client's side of the conversation, written to `out`
+struct Session<W> {
+    out: W,
+    overrides: Vec<(String, String)>,
+    seq: i64,
+    lines_start_at_1: bool,
+    stop_on_entry: bool,
+    machine: Option<Machine>,
+    breakpoints: Breakpoints,
+}
+
+impl<W: Write> Session<W> {
+    fn new(overrides: &[(String, String)], out: W) -> Self {
+        Self {
+            out,
+            overrides: overrides.to_vec(),
+            seq: 0,
+            lines_start_at_1: true,
+            stop_on_entry: false,
+            machine: None,
+            breakpoints: Breakpoints::default(),
+        }
+    }
+
+    fn send(&mut self, kind: &str, mut fields: Vec<(String, Json)>) -> Result<(), String> {
+        self.seq += 1;
+        fields.insert(0, (String::from("seq"), self.seq.into()));
+        fields.insert(1, (String::from("type"), kind.into()));
+        let body = Json::Object(fields).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|()| self.out.flush())
+            .map_err(|e| format!("debug adapter output: {}", e))
+    }
+    fn event(&mut self, name: &str, body: Json) -> Result<(), String> {
+        self.send(
+            "event",
+            vec![
+                (String::from("event"), name.into()),
+                (String::from("body"), body),
+            ],
+        )
+    }
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> Result<(), String> {
+        let mut fields = vec![
+            (
+                String::from("request_seq"),
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            (String::from("success"), result.is_ok().into()),
+            (
+                String::from("command"),
+                request.get("command").cloned().unwrap_or(Json::Null),
+            ),
+        ];
+        match result {
+            Ok(body) => fields.push((String::from("body"), body)),
+            Err(message) => fields.push((String::from("message"), message.into())),
+        }
+        self.send("response", fields)
+    }
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> Result<(), String> {
+        if let Some(machine) = &mut self.machine {
+            machine.running = false;
+            machine.step_target = None;
+            machine.scheduler.set_paused(true, Instant::now());
+        }
+        self.sync_breakpoints();
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(description) = description {
+            body.push(("description", description.clone().into()));
+            body.push(("text", description.into()));
+        }
+        self.event("stopped", object(&body))
+    }
+
+    //false once the client has disconnected
+    fn handle(&mut self, request: &Json) -> Result<bool, String> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let result = match command {
+            "initialize" => {
+                if let Some(start) = arguments.get("linesStartAt1").and_then(Json::as_bool) {
+                    self.lines_start_at_1 = start;
+                }
+                Ok(object(&[
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsFunctionBreakpoints", true.into()),
+                    ("supportsInstructionBreakpoints", true.into()),
+                    ("supportsReadMemoryRequest", true.into()),
+                    ("supportsDisassembleRequest", true.into()),
+                    ("supportsSteppingGranularity", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]))
+            }
+            "launch" => {
+                let result = self.launch(&arguments);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                //breakpoints can only be placed once the program is known
+                if launched {
+                    self.event("initialized", object(&[]))?;
+                }
+                return Ok(true);
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(object(&[])))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else if let Some(machine) = &mut self.machine {
+                    machine.running = true;
+                    machine.scheduler.set_paused(false, Instant::now());
+                }
+                return Ok(true);
+            }
+            "setBreakpoints" => self.set_line_breakpoints(&arguments),
+            "setInstructionBreakpoints" => self.set_address_breakpoints(&arguments, false),
+            "setFunctionBreakpoints" => self.set_address_breakpoints(&arguments, true),
+            "threads" => Ok(object(&[(
+                "threads",
+                vec![object(&[
+                    ("id", THREAD_ID.into()),
+                    ("name", "chip-8".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(&arguments),
+            "scopes" => self.machine().map(|machine| {
+                object(&[(
+                    "scopes",
+                    vec![
+                        scope("Registers", REGISTERS, 18),
+                        scope("Stack", STACK, machine.chip8.stack().len() + 1),
+                        scope("Timers", TIMERS, 2),
+                    ]
+                    .into(),
+                )])
+            }),
+            "variables" => self.variables(&arguments),
+            "readMemory" => self.read_memory(&arguments),
+            "disassemble" => self.disassemble(&arguments),
+            "continue" => self.machine_mut().map(|machine| {
+                let pc = machine.chip8.pc();
+                machine.chip8.breakpoints_mut().resume_from(pc);
+                machine.running = true;
+                machine.scheduler.set_paused(false, Instant::now());
+                object(&[("allThreadsContinued", true.into())])
+            }),
+            //answered before the stopped event they cause
+            "pause" | "stepIn" | "next" | "stepOut" => {
+                let result = self.machine().map(|_| object(&[]));
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                if !launched {
+                    return Ok(true);
+                }
+                if command == "pause" {
+                    self.stopped("pause", None)?;
+                } else {
+                    self.step(command)?;
+                }
+                return Ok(true);
+            }
+            "terminate" => {
+                self.respond(request, Ok(object(&[])))?;
+                self.event("terminated", object(&[]))?;
+                return Ok(true);
+            }
+            "disconnect" => {
+                self.respond(request, Ok(object(&[])))?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+        self.respond(request, result)?;
+        Ok(true)
+    }
+
+    fn machine(&self) -> Result<&Machine, String> {
+        self.machine
+            .as_ref()
+            .ok_or(String::from("no program launched"))
+    }
+    fn machine_mut(&mut self) -> Result<&mut Machine, String> {
+        self.machine
+            .as_mut()
+            .ok_or(String::from("no program launched"))
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a program")?;
+        let mut overrides = self.overrides.clone();
+        if let Some(Json::Object(fields)) = arguments.get("settings") {
+            for (key, value) in fields {
+                let value = match value {
+                    Json::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                overrides.push((key.clone(), value));
+            }
+        }
+        let rom = loader::load(path, Settings::source_origin(&overrides)?)?;
+        let settings = Settings::for_rom(&rom, &overrides)?;
+        let chip8 = settings.create_machine(&rom)?;
+        let mut scheduler = Scheduler::new(settings.instructions_per_second);
+        scheduler.set_speed(Speed::Scaled(settings.speed), Instant::now());
+        scheduler.set_paused(true, Instant::now());
+        self.stop_on_entry = arguments
+            .get("stopOnEntry")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        self.machine = Some(Machine {
+            chip8,
+            scheduler,
+            running: false,
+            step_target: None,
+        });
+        self.breakpoints = Breakpoints::default();
+        Ok(object(&[]))
+    }
+
+    //lines in the client's numbering
+    fn client_line(&self, line: usize) -> usize {
+        if self.lines_start_at_1 {
+            line
+        } else {
+            line - 1
+        }
+    }
+    fn source_line(&self, line: usize) -> usize {
+        if self.lines_start_at_1 {
+            line
+        } else {
+            line + 1
+        }
+    }
+
+    fn set_line_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .unwrap_or("");
+        let requested: Vec<usize> = arguments
+            .get("breakpoints")
+            .map(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_f64))
+            .map(|line| self.source_line(line as usize))
+            .collect();
+        let map = self.machine()?.chip8.symbols().filter(|map| {
+            map.file()
+                .is_some_and(|file| canonical(file) == canonical(path))
+        });
+        let mut addresses = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for line in requested {
+            match map.and_then(|map| map.address_of(line)) {
+                Some((address, line)) => {
+                    addresses.insert(address);
+                    breakpoints.push(object(&[
+                        ("verified", true.into()),
+                        ("line", self.client_line(line).into()),
+                        ("instructionReference", reference(address).into()),
+                    ]));
+                }
+                None => breakpoints.push(object(&[
+                    ("verified", false.into()),
+                    (
+                        "message",
+                        if map.is_some() {
+                            "no code at or after this line"
+                        } else {
+                            "not the source of the launched program"
+                        }
+                        .into(),
+                    ),
+                ])),
+            }
+        }
+        self.breakpoints.lines = addresses;
+        self.sync_breakpoints();
+        Ok(object(&[("breakpoints", breakpoints.into())]))
+    }
+
+    //instruction breakpoints have a reference and offset, function
+    //breakpoints a name that is a label, file:line or hex address
+    fn set_address_breakpoints(
+        &mut self,
+        arguments: &Json,
+        functions: bool,
+    ) -> Result<Json, String> {
+        self.machine()?;
+        let mut addresses = BTreeSet::new();
+        let mut breakpoints = Vec::new();
+        for requested in arguments
+            .get("breakpoints")
+            .map(Json::as_array)
+            .unwrap_or(&[])
+        {
+            let address = if functions {
+                let name = requested.get("name").and_then(Json::as_str).unwrap_or("");
+                let no_symbols = DebugMap::default();
+                let symbols = self.machine()?.chip8.symbols().unwrap_or(&no_symbols);
+                symbols.resolve(name)
+            } else {
+                let offset = requested
+                    .get("offset")
+                    .and_then(Json::as_f64)
+                    .unwrap_or(0.0);
+                requested
+                    .get("instructionReference")
+                    .and_then(Json::as_str)
+                    .and_then(debug_map::parse_address)
+                    .map(|address| (address as i64 + offset as i64) as u16)
+                    .filter(|&address| (address as usize) < MEMORY_SIZE)
+                    .ok_or(String::from("not an address"))
+            };
+            match address {
+                Ok(address) => {
+                    addresses.insert(address);
+                    breakpoints.push(object(&[
+                        ("verified", true.into()),
+                        ("instructionReference", reference(address).into()),
+                    ]));
+                }
+                Err(message) => breakpoints.push(object(&[
+                    ("verified", false.into()),
+                    ("message", message.into()),
+                ])),
+            }
+        }
+        if functions {
+            self.breakpoints.functions = addresses;
+        } else {
+            self.breakpoints.instructions = addresses;
+        }
+        self.sync_breakpoints();
+        Ok(object(&[("breakpoints", breakpoints.into())]))
+    }
+
+    //the core's breakpoints are all of the client's plus the step target
+    fn sync_breakpoints(&mut self) {
+        let Some(machine) = &mut self.machine else {
+            return;
+        };
+        let breakpoints = machine.chip8.breakpoints_mut();
+        breakpoints.clear();
+        let all = self
+            .breakpoints
+            .lines
+            .iter()
+            .chain(&self.breakpoints.instructions)
+            .chain(&self.breakpoints.functions);
+        for &address in all.chain(machine.step_target.as_ref().map(|(a, _)| a)) {
+            breakpoints.add(address);
+        }
+    }
+
+    fn stack_trace(&self, arguments: &Json) -> Result<Json, String> {
+        let machine = self.machine()?;
+        let chip8 = &machine.chip8;
+        //the current instruction, then the call of every return address
+        let mut addresses = vec![chip8.pc()];
+        addresses.extend(chip8.stack().iter().rev().map(|a| a.wrapping_sub(2)));
+        let start = arguments
+            .get("startFrame")
+            .and_then(Json::as_f64)
+            .unwrap_or(0.0) as usize;
+        let levels = match arguments.get("levels").and_then(Json::as_f64) {
+            Some(levels) if levels > 0.0 => levels as usize,
+            _ => addresses.len(),
+        };
+        let mut frames = Vec::new();
+        for (id, &address) in addresses.iter().enumerate().skip(start).take(levels) {
+            let mut frame = vec![
+                ("id", id.into()),
+                ("name", frame_name(chip8, address).into()),
+                ("instructionPointerReference", reference(address).into()),
+                ("line", 0usize.into()),
+                ("column", 0usize.into()),
+            ];
+            if let Some((path, line)) = source_line(&machine.chip8, address) {
+                frame[3] = ("line", self.client_line(line).into());
+                frame[4] = ("column", self.client_line(1).into());
+                frame.push(("source", source(path)));
+            }
+            frames.push(object(&frame));
+        }
+        Ok(object(&[
+            ("stackFrames", frames.into()),
+            ("totalFrames", addresses.len().into()),
+        ]))
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let chip8 = &self.machine()?.chip8;
+        let reference = arguments
+            .get("variablesReference")
+            .and_then(Json::as_f64)
+            .unwrap_or(0.0) as i64;
+        let byte = |name: String, value: u8| variable(&name, format!("0x{:02x}", value), None);
+        let address =
+            |name: String, value: u16| variable(&name, format!("0x{:04x}", value), Some(value));
+        let variables = match reference {
+            REGISTERS => {
+                let mut variables: Vec<Json> = chip8
+                    .registers()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &value)| byte(format!("V{:X}", i), value))
+                    .collect();
+                variables.push(address(String::from("I"), chip8.index()));
+                variables.push(address(String::from("PC"), chip8.pc()));
+                variables
+            }
+            STACK => {
+                let mut variables = vec![variable("SP", chip8.stack().len().to_string(), None)];
+                for (i, &value) in chip8.stack().iter().enumerate().rev() {
+                    variables.push(address(format!("[{}]", i), value));
+                }
+                variables
+            }
+            TIMERS => vec![
+                byte(String::from("DT"), chip8.delay_timer()),
+                byte(String::from("ST"), chip8.sound_timer()),
+            ],
+            _ => Vec::new(),
+        };
+        Ok(object(&[("variables", variables.into())]))
+    }
+
+    fn read_memory(&self, arguments: &Json) -> Result<Json, String> {
+        let memory = self.machine()?.chip8.memory();
+        let start = arguments
+            .get("memoryReference")
+            .and_then(Json::as_str)
+            .and_then(debug_map::parse_address)
+            .ok_or("bad memoryReference")? as i64
+            + arguments
+                .get("offset")
+                .and_then(Json::as_f64)
+                .unwrap_or(0.0) as i64;
+        let count = arguments.get("count").and_then(Json::as_f64).unwrap_or(0.0) as i64;
+        //only whole bytes inside the 4k are readable
+        let first = start.clamp(0, MEMORY_SIZE as i64);
+        let end = (start + count).clamp(first, MEMORY_SIZE as i64);
+        let data = &memory[first as usize..end as usize];
+        Ok(object(&[
+            ("address", reference(first as u16).into()),
+            ("data", base64(data).into()),
+            ("unreadableBytes", (count - data.len() as i64).max(0).into()),
+        ]))
+    }
+
+    fn disassemble(&self, arguments: &Json) -> Result<Json, String> {
+        let machine = self.machine()?;
+        let memory = machine.chip8.memory();
+        let number = |key: &str| arguments.get(key).and_then(Json::as_f64).unwrap_or(0.0) as i64;
+        let base = arguments
+            .get("memoryReference")
+            .and_then(Json::as_str)
+            .and_then(debug_map::parse_address)
+            .ok_or("bad memoryReference")? as i64
+            + number("offset")
+            + number("instructionOffset") * 2;
+        let mut instructions = Vec::new();
+        for n in 0..number("instructionCount").max(0) {
+            let address = base + n * 2;
+            if !(0..MEMORY_SIZE as i64 - 1).contains(&address) {
+                instructions.push(object(&[
+                    ("address", format!("0x{:x}", address.max(0)).into()),
+                    ("instruction", "??".into()),
+                    ("presentationHint", "invalid".into()),
+                ]));
+                continue;
+            }
+            let address = address as u16;
+            let mut fields = vec![
+                ("address", reference(address).into()),
+                (
+                    "instructionBytes",
+                    format!(
+                        "{:02x} {:02x}",
+                        memory[address as usize],
+                        memory[address as usize + 1]
+                    )
+                    .into(),
+                ),
+                ("instruction", instruction(&machine.chip8, address).into()),
+            ];
+            if let Some((path, line)) = source_line(&machine.chip8, address) {
+                fields.push(("location", source(path)));
+                fields.push(("line", self.client_line(line).into()));
+            }
+            instructions.push(object(&fields));
+        }
+        Ok(object(&[("instructions", instructions.into())]))
+    }
+
+    //every source line is one instruction, so stepping is by instruction.
+    //next runs over a call, stepOut runs until the current call returns
+    fn step(&mut self, command: &str) -> Result<(), String> {
+        let Some(machine) = &mut self.machine else {
+            return Ok(());
+        };
+        let chip8 = &mut machine.chip8;
+        let pc = chip8.pc();
+        let depth = chip8.stack().len();
+        let opcode = chip8.memory()[pc as usize % MEMORY_SIZE] >> 4;
+        machine.step_target = match command {
+            "next" if opcode == 0x2 => Some((pc.wrapping_add(2), depth)),
+            "stepOut" => chip8.stack().last().map(|&address| (address, depth - 1)),
+            _ => None,
+        };
+        chip8.breakpoints_mut().resume_from(pc);
+        if machine.step_target.is_some() {
+            machine.running = true;
+            machine.scheduler.set_paused(false, Instant::now());
+            self.sync_breakpoints();
+            return Ok(());
+        }
+        match chip8.emulate_cycle() {
+            Ok(()) => self.stopped("step", None),
+            Err(fault) => self.fault(fault),
+        }
+    }
+
+    fn run_due_frames(&mut self) -> Result<(), String> {
+        let Some(machine) = self.machine.as_mut().filter(|m| m.running) else {
+            return Ok(());
+        };
+        let frames = machine.scheduler.frames_due(Instant::now());
+        self.run_frames(frames)
+    }
+    fn run_frames(&mut self, frames: u64) -> Result<(), String> {
+        let Some(machine) = self.machine.as_mut().filter(|m| m.running) else {
+            return Ok(());
+        };
+        for _ in 0..frames {
+            let budget = if machine.chip8.mid_frame() {
+                0
+            } else {
+                machine.scheduler.frame_budget(machine.chip8.timing())
+            };
+            if let Err(fault) = machine.chip8.run_frame(budget) {
+                return self.fault(fault);
+            }
+        }
+        Ok(())
+    }
+
+    fn fault(&mut self, fault: Fault) -> Result<(), String> {
+        let Some(machine) = &mut self.machine else {
+            return Ok(());
+        };
+        if fault.kind == FaultKind::Breakpoint {
+            if let Some((address, depth)) = machine.step_target {
+                if fault.pc == address && machine.chip8.stack().len() > depth {
+                    //the same return address in a deeper call, keep going
+                    machine.chip8.breakpoints_mut().resume_from(fault.pc);
+                    return Ok(());
+                }
+                if fault.pc == address {
+                    return self.stopped("step", None);
+                }
+            }
+            return self.stopped("breakpoint", None);
+        }
+        let mut output = format!("Fault: {}\n", fault);
+        if let Some(trace) = debugger::format_trace(&machine.chip8) {
+            output.push_str(&format!("{}\n", trace));
+        }
+        self.event(
+            "output",
+            object(&[("category", "console".into()), ("output", output.into())]),
+        )?;
+        self.stopped("exception", Some(fault.to_string()))
+    }
+
+    fn finish(&mut self) {
+        if let Some(tracer) = self
+            .machine
+            .as_mut()
+            .and_then(|machine| machine.chip8.tracer_mut())
+        {
+            tracer.flush();
+        }
+    }
+}
+
+fn scope(name: &str, reference: i64, count: usize) -> Json {
+    object(&[
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("namedVariables", count.into()),
+        ("expensive", false.into()),
+    ])
+}
+
+//addresses can be opened in the memory view
+fn variable(name: &str, value: String, address: Option<u16>) -> Json {
+    let mut fields = vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0usize.into()),
+    ];
+    if let Some(address) = address {
+        fields.push(("memoryReference", reference(address).into()));
+    }
+    object(&fields)
+}
+
+fn source(path: &Path) -> Json {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    object(&[
+        ("name", name.into()),
+        ("path", path.to_string_lossy().into_owned().into()),
+    ])
+}
+
+fn canonical(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn reference(address: u16) -> String {
+    format!("0x{:04x}", address)
+}
+
+fn instruction(chip8: &Chip8, address: u16) -> String {
+    let memory = chip8.memory();
+    if address as usize + 1 >= memory.len() {
+        return String::from("??");
+    }
+    let instruction = disassembler::disassemble_chip8(memory, address as usize).replace('\t', " ");
+    match chip8.symbols() {
+        Some(symbols) => symbols.label_operands(&instruction),
+        None => instruction,
+    }
+}
+
+//"020a draw+2: CALL digit"
+fn frame_name(chip8: &Chip8, address: u16) -> String {
+    match chip8
+        .symbols()
+        .and_then(|symbols| symbols.describe(address))
+    {
+        Some(label) => format!("{:04x} {}: {}", address, label, instruction(chip8, address)),
+        None => format!("{:04x} {}", address, instruction(chip8, address)),
+    }
+}
+
+fn source_line(chip8: &Chip8, address: u16) -> Option<(&Path, usize)> {
+    let symbols = chip8.symbols()?;
+    Some((symbols.file()?, symbols.line_of(address)?))
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PROGRAM: &str = "start:
+	MVI V0, 00
+	CALL sub
+loop:
+	JMP loop
+sub:
+	ADI V0, 01
+	S.EQ V0, 03
+	CALL sub
+	RET
+";
+
+    //a session with PROGRAM launched from its own file
+    fn launch(name: &str, stop_on_entry: bool) -> (Session<Vec<u8>>, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("chip8-dap-{}-{}.asm", name, std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+        let mut session = Session::new(&[], Vec::new());
+        let arguments = object(&[
+            ("program", path.to_string_lossy().into_owned().into()),
+            ("stopOnEntry", stop_on_entry.into()),
+        ]);
+        let sent = request(&mut session, "launch", arguments);
+        assert_eq!(
+            sent[1].get("event").and_then(Json::as_str),
+            Some("initialized")
+        );
+        request(&mut session, "configurationDone", object(&[]));
+        (session, path)
+    }
+
+    //everything the session sent back
+    fn request(session: &mut Session<Vec<u8>>, command: &str, arguments: Json) -> Vec<Json> {
+        let request = object(&[
+            ("seq", 1usize.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ]);
+        assert!(session.handle(&request).unwrap());
+        let mut input = Cursor::new(std::mem::take(&mut session.out));
+        let mut sent = Vec::new();
+        while let Some(message) = read_message(&mut input).unwrap() {
+            sent.push(message.unwrap());
+        }
+        sent
+    }
+
+    fn body(sent: &[Json]) -> Json {
+        let response = sent
+            .iter()
+            .find(|m| m.get("type").and_then(Json::as_str) == Some("response"))
+            .unwrap();
+        assert_eq!(
+            response.get("success"),
+            Some(&Json::Bool(true)),
+            "{}",
+            response
+        );
+        response.get("body").cloned().unwrap()
+    }
+
+    fn stop_reason(sent: &[Json]) -> Option<String> {
+        sent.iter()
+            .find(|m| m.get("event").and_then(Json::as_str) == Some("stopped"))
+            .and_then(|m| m.get("body")?.get("reason")?.as_str().map(String::from))
+    }
+
+    //runs frames until the machine stops, with what the session sent
+    fn run_until_stopped(session: &mut Session<Vec<u8>>) -> Vec<Json> {
+        for _ in 0..100 {
+            session.run_frames(1).unwrap();
+            if !session.machine.as_ref().unwrap().running {
+                let mut input = Cursor::new(std::mem::take(&mut session.out));
+                let mut sent = Vec::new();
+                while let Some(message) = read_message(&mut input).unwrap() {
+                    sent.push(message.unwrap());
+                }
+                return sent;
+            }
+        }
+        panic!("the machine never stopped");
+    }
+
+    fn pc(session: &Session<Vec<u8>>) -> u16 {
+        session.machine.as_ref().unwrap().chip8.pc()
+    }
+
+    fn addresses(body: &Json) -> Vec<Option<String>> {
+        body.get("breakpoints")
+            .unwrap()
+            .as_array()
+            .iter()
+            .map(|b| {
+                b.get("instructionReference")
+                    .and_then(Json::as_str)
+                    .map(String::from)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn framing() {
+        let mut input = Cursor::new(concat!(
+            "Content-Length: 15\r\n\r\n{\"seq\":1,\"a\":2}",
+            "content-type: json\r\ncontent-length:2\r\n\r\n[]",
+            "Content-Length: 3\r\n\r\n{x}",
+        ));
+        let first = read_message(&mut input).unwrap().unwrap().unwrap();
+        assert_eq!(first.get("a").and_then(Json::as_f64), Some(2.0));
+        let second = read_message(&mut input).unwrap().unwrap().unwrap();
+        assert_eq!(second, Json::Array(Vec::new()));
+        assert!(read_message(&mut input).unwrap().unwrap().is_err());
+        assert!(read_message(&mut input).unwrap().is_none());
+        //a body cut short is an error, not a message
+        let mut input = Cursor::new("Content-Length: 10\r\n\r\n{}");
+        assert!(read_message(&mut input).is_err());
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn breakpoints_by_line_address_and_name() {
+        let (mut session, path) = launch("breakpoints", true);
+        let source = object(&[("path", path.to_string_lossy().into_owned().into())]);
+        let lines = |lines: &[usize]| -> Json {
+            lines
+                .iter()
+                .map(|&line| object(&[("line", line.into())]))
+                .collect::<Vec<_>>()
+                .into()
+        };
+        //a label line breaks on the instruction after it
+        let sent = request(
+            &mut session,
+            "setBreakpoints",
+            object(&[("source", source), ("breakpoints", lines(&[6, 8, 99]))]),
+        );
+        let set = body(&sent);
+        assert_eq!(
+            addresses(&set),
+            [
+                Some(String::from("0x0206")),
+                Some(String::from("0x0208")),
+                None
+            ]
+        );
+        assert_eq!(
+            set.get("breakpoints").unwrap().as_array()[0].get("line"),
+            Some(&Json::Number(7.0))
+        );
+        let other = object(&[("path", "/elsewhere.asm".into())]);
+        let sent = request(
+            &mut session,
+            "setBreakpoints",
+            object(&[("source", other), ("breakpoints", lines(&[2]))]),
+        );
+        assert_eq!(addresses(&body(&sent)), [None]);
+
+        let sent = request(
+            &mut session,
+            "setFunctionBreakpoints",
+            object(&[(
+                "breakpoints",
+                vec![
+                    object(&[("name", "sub".into())]),
+                    object(&[("name", "nowhere".into())]),
+                ]
+                .into(),
+            )]),
+        );
+        assert_eq!(
+            addresses(&body(&sent)),
+            [Some(String::from("0x0206")), None]
+        );
+        let sent = request(
+            &mut session,
+            "setInstructionBreakpoints",
+            object(&[(
+                "breakpoints",
+                vec![object(&[
+                    ("instructionReference", "0x0202".into()),
+                    ("offset", 2usize.into()),
+                ])]
+                .into(),
+            )]),
+        );
+        assert_eq!(addresses(&body(&sent)), [Some(String::from("0x0204"))]);
+
+        //the line breakpoints went away with the other source, the first
+        //stop is the function breakpoint
+        request(&mut session, "continue", object(&[]));
+        let sent = run_until_stopped(&mut session);
+        assert_eq!(stop_reason(&sent).as_deref(), Some("breakpoint"));
+        assert_eq!(pc(&session), 0x206);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn next_runs_over_calls() {
+        let (mut session, path) = launch("next", true);
+        assert_eq!(pc(&session), 0x200);
+        let sent = request(&mut session, "next", object(&[]));
+        assert_eq!(stop_reason(&sent).as_deref(), Some("step"));
+        assert_eq!(pc(&session), 0x202);
+        //the call recurses three deep before coming back
+        let sent = request(&mut session, "next", object(&[]));
+        assert_eq!(stop_reason(&sent), None);
+        let sent = run_until_stopped(&mut session);
+        assert_eq!(stop_reason(&sent).as_deref(), Some("step"));
+        assert_eq!(pc(&session), 0x204);
+        let machine = session.machine.as_ref().unwrap();
+        assert!(machine.chip8.stack().is_empty());
+        assert_eq!(machine.chip8.registers()[0], 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn step_out_of_a_recursive_call() {
+        let (mut session, path) = launch("step-out", false);
+        let functions = |names: Vec<Json>| object(&[("breakpoints", names.into())]);
+        request(
+            &mut session,
+            "setFunctionBreakpoints",
+            functions(vec![object(&[("name", "sub".into())])]),
+        );
+        run_until_stopped(&mut session);
+        request(&mut session, "continue", object(&[]));
+        run_until_stopped(&mut session);
+        assert_eq!(pc(&session), 0x206);
+        assert_eq!(session.machine.as_ref().unwrap().chip8.stack().len(), 2);
+        //the deeper call returns to the same address first
+        request(
+            &mut session,
+            "setFunctionBreakpoints",
+            functions(Vec::new()),
+        );
+        request(&mut session, "stepOut", object(&[]));
+        let sent = run_until_stopped(&mut session);
+        assert_eq!(stop_reason(&sent).as_deref(), Some("step"));
+        assert_eq!(pc(&session), 0x20c);
+        assert_eq!(session.machine.as_ref().unwrap().chip8.stack().len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn variables_and_memory() {
+        let (mut session, path) = launch("memory", true);
+        let sent = request(
+            &mut session,
+            "variables",
+            object(&[("variablesReference", REGISTERS.into())]),
+        );
+        let variables = body(&sent);
+        let variables = variables.get("variables").unwrap().as_array();
+        assert_eq!(variables.len(), 18);
+        assert_eq!(variables[17].get("name").and_then(Json::as_str), Some("PC"));
+        assert_eq!(
+            variables[17].get("value").and_then(Json::as_str),
+            Some("0x0200")
+        );
+
+        //MVI V0, 00 is 60 00
+        let read = |session: &mut Session<Vec<u8>>, reference: &str, offset: i64, count: i64| {
+            body(&request(
+                session,
+                "readMemory",
+                object(&[
+                    ("memoryReference", reference.into()),
+                    ("offset", offset.into()),
+                    ("count", count.into()),
+                ]),
+            ))
+        };
+        let memory = read(&mut session, "0x0200", 0, 2);
+        assert_eq!(memory.get("data").and_then(Json::as_str), Some("YAA="));
+        assert_eq!(memory.get("unreadableBytes"), Some(&Json::Number(0.0)));
+        let memory = read(&mut session, "0x0ff0", 0, 100);
+        assert_eq!(memory.get("address").and_then(Json::as_str), Some("0x0ff0"));
+        assert_eq!(memory.get("data").and_then(Json::as_str).unwrap().len(), 24);
+        assert_eq!(memory.get("unreadableBytes"), Some(&Json::Number(84.0)));
+        let memory = read(&mut session, "0x0000", -8, 4);
+        assert_eq!(memory.get("data").and_then(Json::as_str), Some(""));
+        assert_eq!(memory.get("unreadableBytes"), Some(&Json::Number(4.0)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use super::file_utils;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//what the assembler knows about a program that the rom doesn't: the source
+//line of every address and the label names. `asm` saves it next to the rom
+//as text, and the loader picks it up again when the rom is loaded:
+//
+//  ; chip_8_emulator debug map
+//  rom 5d4e...                 sha1 of the rom the map was made for
+//  file game.asm               relative to the map
+//  label 0200 start
+//  line 0200 3
+#[derive(Clone, Default)]
+pub struct DebugMap {
+    file: Option<PathBuf>,
+    //(address, line) in address order, lines count from 1
+    lines: Vec<(u16, usize)>,
+    //(address, name) in address order
+    labels: Vec<(u16, String)>,
+}
+
+impl DebugMap {
+    pub fn new(mut lines: Vec<(u16, usize)>, mut labels: Vec<(u16, String)>) -> Self {
+        lines.sort();
+        labels.sort();
+        Self {
+            file: None,
+            lines,
+            labels,
+        }
+    }
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+    pub fn set_file(&mut self, file: &Path) {
+        self.file = Some(file.to_path_buf());
+    }
+
+    pub fn line_of(&self, address: u16) -> Option<usize> {
+        let index = self.lines.partition_point(|&(a, _)| a <= address);
+        let &(_, line) = self.lines.get(index.checked_sub(1)?)?;
+        Some(line)
+    }
+    //the first line from `line` on that has an address, with that address,
+    //so a breakpoint on a comment or label lands on the next instruction
+    pub fn address_of(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines
+            .iter()
+            .filter(|&&(_, l)| l >= line)
+            .min_by_key(|&&(_, l)| l)
+            .map(|&(address, l)| (address, l))
+    }
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map(|(_, name)| name.as_str())
+    }
+    //labels are case insensitive like in the assembler
+    pub fn label_address(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(name))
+            .map(|(address, _)| *address)
+    }
+    //"loop" or "loop+4" for an address at or after a label
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let index = self.labels.partition_point(|(a, _)| *a <= address);
+        let (start, name) = self.labels.get(index.checked_sub(1)?)?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+{}", name, offset),
+        })
+    }
+    //"game.asm:12"
+    pub fn location(&self, address: u16) -> Option<String> {
+        let name = self.file.as_ref()?.file_name()?.to_string_lossy();
+        Some(format!("{}:{}", name, self.line_of(address)?))
+    }
+
+    //a breakpoint location: file:line, a label, or a hex address
+    pub fn resolve(&self, location: &str) -> Result<u16, String> {
+        if let Some((file, line)) = location.rsplit_once(':') {
+            let line: usize = line
+                .parse()
+                .map_err(|_| format!("bad line number in '{}'", location))?;
+            let source = self
+                .file
+                .as_ref()
+                .filter(|source| {
+                    source.ends_with(file)
+                        || fs::canonicalize(source).ok() == fs::canonicalize(file).ok()
+                })
+                .ok_or(format!("{} is not the source of this rom", file))?;
+            return self
+                .address_of(line)
+                .map(|(address, _)| address)
+                .ok_or(format!(
+                    "no code at or after line {} of {}",
+                    line,
+                    source.display()
+                ));
+        }
+        self.label_address(location)
+            .or_else(|| parse_address(location))
+            .ok_or(format!("'{}' is not a label or hex address", location))
+    }
+
+    //puts label names in place of the $nnn operands in a line of disassembly,
+    //CALL $02d4 becomes CALL draw_score. raw DB and DW data is left alone
+    pub fn label_operands(&self, instruction: &str) -> String {
+        self.label_operands_where(instruction, |_| true)
+    }
+    //only for labels at addresses `usable` accepts, a listing can't place a
+    //label in the middle of the two bytes it shows per line
+    pub fn label_operands_where(&self, instruction: &str, usable: impl Fn(u16) -> bool) -> String {
+        if instruction.starts_with("DB") || instruction.starts_with("DW") {
+            return instruction.to_string();
+        }
+        let Some(start) = instruction.find('$') else {
+            return instruction.to_string();
+        };
+        let digits = instruction[start + 1..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(instruction.len(), |end| start + 1 + end);
+        let label = u16::from_str_radix(&instruction[start + 1..digits], 16)
+            .ok()
+            .filter(|&address| usable(address))
+            .and_then(|address| self.label_at(address));
+        match label {
+            Some(label) => format!(
+                "{}{}{}",
+                &instruction[..start],
+                label,
+                &instruction[digits..]
+            ),
+            None => instruction.to_string(),
+        }
+    }
+
+    //writes the map for `rom` to `path`, with the source relative to it
+    pub fn save(&self, path: &Path, rom: &[u8]) -> Result<(), String> {
+        let mut text = String::from("; chip_8_emulator debug map\n");
+        text.push_str(&format!("rom {}\n", file_utils::sha1_hex(rom)));
+        if let Some(file) = &self.file {
+            let directory = fs::canonicalize(directory_of(path)).unwrap_or_default();
+            let file = fs::canonicalize(file).unwrap_or_else(|_| file.clone());
+            let relative = file.strip_prefix(&directory).unwrap_or(&file);
+            text.push_str(&format!("file {}\n", relative.display()));
+        }
+        for (address, name) in &self.labels {
+            text.push_str(&format!("label {:04x} {}\n", address, name));
+        }
+        for (address, line) in &self.lines {
+            text.push_str(&format!("line {:04x} {}\n", address, line));
+        }
+        fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    //the map saved next to a rom file, if there is one made for this rom
+    pub fn find(rom_path: &str, rom: &[u8]) -> Option<DebugMap> {
+        let path = Path::new(rom_path).with_extension("map");
+        let text = fs::read_to_string(&path).ok()?;
+        match DebugMap::parse(&text, directory_of(&path)) {
+            Ok((sha1, map)) if sha1 == file_utils::sha1_hex(rom) => Some(map),
+            Ok(_) => {
+                eprintln!(
+                    "Ignoring {}, it is for a different build of the rom",
+                    path.display()
+                );
+                None
+            }
+            Err(e) => {
+                eprintln!("Ignoring {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+    //(rom sha1, map)
+    fn parse(text: &str, directory: &Path) -> Result<(String, DebugMap), String> {
+        let mut sha1 = String::new();
+        let mut file = None;
+        let mut lines = Vec::new();
+        let mut labels = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad = || format!("line {} is not part of a debug map", number + 1);
+            let (kind, rest) = line.split_once(' ').ok_or_else(bad)?;
+            match kind {
+                "rom" => sha1 = rest.trim().to_string(),
+                "file" => file = Some(directory.join(rest.trim())),
+                "label" | "line" => {
+                    let (address, value) = rest.trim().split_once(' ').ok_or_else(bad)?;
+                    let address = u16::from_str_radix(address, 16).map_err(|_| bad())?;
+                    if kind == "label" {
+                        labels.push((address, value.trim().to_string()));
+                    } else {
+                        lines.push((address, value.trim().parse().map_err(|_| bad())?));
+                    }
+                }
+                _ => return Err(bad()),
+            }
+        }
+        let mut map = DebugMap::new(lines, labels);
+        map.file = file;
+        Ok((sha1, map))
+    }
+}
+
+//"game.map" has an empty parent
+fn directory_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+//hex, with or without 0x or $ in front
+pub fn parse_address(value: &str) -> Option<u16> {
+    let value = value.trim();
+    let digits = value
+        .strip_prefix("0x")
+        .or(value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16)
+        .ok()
+        .filter(|&address| (address as usize) < super::chip8::MEMORY_SIZE)
+}
@@ -0,0 +1,195 @@
+use super::chip8::Chip8;
+use super::disassembler;
+use std::collections::BTreeSet;
+
+//one screen of machine state: registers, timers, stack and the next instruction
+pub fn format_state(chip8: &Chip8) -> String {
+    let pc = chip8.pc() as usize;
+    let memory = chip8.memory();
+    let mut next = if pc + 1 < memory.len() {
+        disassembler::disassemble_chip8(memory, pc).replace('\t', " ")
+    } else {
+        String::from("??")
+    };
+    //where that is in the source, "PC 0208 draw+4 (game.asm:31)"
+    let mut place = String::new();
+    if let Some(symbols) = chip8.symbols() {
+        next = symbols.label_operands(&next);
+        if let Some(label) = symbols.describe(pc as u16) {
+            place.push_str(&format!(" {}", label));
+        }
+        if let Some(location) = symbols.location(pc as u16) {
+            place.push_str(&format!(" ({})", location));
+        }
+    }
+    let mut state = format!(
+        "frame {}  PC {:04x}{}  {}\nI {:04x}  SP {}  DT {:02x}  ST {:02x}\n",
+        chip8.frame_count(),
+        pc,
+        place,
+        next,
+        chip8.index(),
+        chip8.stack().len(),
+        chip8.delay_timer(),
+        chip8.sound_timer()
+    );
+    for (i, value) in chip8.registers().iter().enumerate() {
+        state.push_str(&format!("V{:X} {:02x}", i, value));
+        state.push(if i % 8 == 7 { '\n' } else { ' ' });
+    }
+    let stack: Vec<String> = chip8
+        .stack()
+        .iter()
+        .map(|&a| match chip8.symbols().and_then(|s| s.describe(a)) {
+            Some(label) => format!("{:04x} {}", a, label),
+            None => format!("{:04x}", a),
+        })
+        .collect();
+    state.push_str(&format!("stack [{}]", stack.join(" ")));
+    state
+}
+
+//the steps kept by --trace ring, oldest first
+pub fn format_trace(chip8: &Chip8) -> Option<String> {
+    let steps = chip8.tracer()?.recent()?;
+    let lines: Vec<String> = steps
+        .iter()
+        .map(|step| step.to_text(chip8.symbols()))
+        .collect();
+    Some(format!("last {} steps:\n{}", lines.len(), lines.join("\n")))
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    //reads and writes
+    Access,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub length: u16,
+    pub kind: WatchKind,
+}
+
+//breakpoints and watchpoints checked by the core. a breakpoint stops before
+//the instruction at its address runs, a watchpoint after the instruction that
+//touched the memory; both come back from emulate_cycle as a Fault. the
+//--break breakpoints, the ones a debugger set and the hooks a script rides
+//on are kept apart, so none of them hides or removes the others
+#[derive(Default)]
+pub struct Breakpoints {
+    breaks: BTreeSet<u16>,
+    code: BTreeSet<u16>,
+    watches: Vec<Watchpoint>,
+    hooks: BTreeSet<u16>,
+    hook_watches: Vec<Watchpoint>,
+    //the breakpoint execution is continuing from, skipped once
+    resume_from: Option<u16>,
+    //(address, was a write) of every watched access this instruction, then
+    //(address, was a write, kind of the watchpoint) of the first one a user
+    //or debugger watchpoint asked for and of the first one at all
+    hits: Vec<(u16, bool)>,
+    user_hit: Option<(u16, bool, WatchKind)>,
+    first_hit: Option<(u16, bool, WatchKind)>,
+}
+
+impl Breakpoints {
+    pub fn is_empty(&self) -> bool {
+        self.breaks.is_empty()
+            && self.code.is_empty()
+            && self.watches.is_empty()
+            && self.hooks.is_empty()
+            && self.hook_watches.is_empty()
+    }
+    //the --break breakpoints, replacing the last ones
+    pub fn set_breaks(&mut self, addresses: impl IntoIterator<Item = u16>) {
+        self.breaks = addresses.into_iter().collect();
+    }
+    //a debugger's breakpoints
+    pub fn add(&mut self, address: u16) {
+        self.code.insert(address);
+    }
+    pub fn remove(&mut self, address: u16) {
+        self.code.remove(&address);
+    }
+    //a --break or debugger breakpoint, not a script hook
+    pub fn contains(&self, address: u16) -> bool {
+        self.breaks.contains(&address) || self.code.contains(&address)
+    }
+    pub fn add_watch(&mut self, watch: Watchpoint) {
+        if !self.watches.contains(&watch) {
+            self.watches.push(watch);
+        }
+    }
+    pub fn remove_watch(&mut self, watch: Watchpoint) {
+        self.watches.retain(|w| *w != watch);
+    }
+    //script hooks, which stay until the run ends
+    pub fn add_hook(&mut self, address: u16) {
+        self.hooks.insert(address);
+    }
+    pub fn add_hook_watch(&mut self, watch: Watchpoint) {
+        if !self.hook_watches.contains(&watch) {
+            self.hook_watches.push(watch);
+        }
+    }
+    //drops a debugger's breakpoints and watchpoints, not --break or the
+    //script hooks
+    pub fn clear(&mut self) {
+        self.code.clear();
+        self.watches.clear();
+        self.resume_from = None;
+        self.start_instruction();
+    }
+    //so that continuing from a breakpoint doesn't stop on it straight away
+    pub fn resume_from(&mut self, pc: u16) {
+        self.resume_from = Some(pc);
+    }
+    pub fn stops_at(&mut self, pc: u16) -> bool {
+        let resuming = self.resume_from.take() == Some(pc);
+        !resuming && (self.contains(pc) || self.hooks.contains(&pc))
+    }
+    //called before each instruction, the hits of the last one are gone
+    pub fn start_instruction(&mut self) {
+        self.hits.clear();
+        self.user_hit = None;
+        self.first_hit = None;
+    }
+    pub fn check_access(&mut self, address: u16, write: bool) {
+        let watched = |watches: &[Watchpoint]| {
+            watches
+                .iter()
+                .find(|w| {
+                    let kind = match w.kind {
+                        WatchKind::Read => !write,
+                        WatchKind::Write => write,
+                        WatchKind::Access => true,
+                    };
+                    kind && (w.start as u32..w.start as u32 + w.length as u32)
+                        .contains(&(address as u32))
+                })
+                .map(|w| (address, write, w.kind))
+        };
+        let user = watched(&self.watches);
+        let hit = user.or_else(|| watched(&self.hook_watches));
+        if hit.is_some() {
+            self.hits.push((address, write));
+        }
+        self.user_hit = self.user_hit.or(user);
+        self.first_hit = self.first_hit.or(hit);
+    }
+    //every watched access of the last instruction, in order
+    pub fn hits(&self) -> &[(u16, bool)] {
+        &self.hits
+    }
+    //the access a fault names, a debugger can only be told about one
+    pub fn hit(&self) -> Option<(u16, bool, WatchKind)> {
+        self.user_hit.or(self.first_hit)
+    }
+    pub fn user_hit(&self) -> Option<(u16, bool, WatchKind)> {
+        self.user_hit
+    }
+}
@@ -1,7 +1,17 @@
-use std::fmt::format;
+use super::debug_map::DebugMap;
 
-pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> usize {
+//disassembles the instruction at program_counter. words that don't decode to
+//an instruction come out as DW so the listing can be fed back to the assembler
+pub fn disassemble_chip8(code_buffer: &[u8], program_counter: usize) -> String {
+    if program_counter + 1 >= code_buffer.len() {
+        return format!("DB\t${:02x}", code_buffer[program_counter]);
+    }
     let current_op_code = code_buffer[program_counter];
+    let unknown = format!(
+        "DW\t${:02x}{:02x}",
+        code_buffer[program_counter],
+        code_buffer[program_counter + 1]
+    );
     // println!(
     //     "{:04x}\t{:02x}{:02x}",
     //     program_counter,
@@ -10,11 +20,11 @@ pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> u
     // );
     let first_half_byte = current_op_code >> 4;
     let assembly_code = match first_half_byte {
-        0x0 => match current_op_code {
-            0xe0 => String::from("CLS"),
-            0xee => String::from("RET"),
+        0x0 => match (current_op_code, code_buffer[program_counter + 1]) {
+            (0x00, 0xe0) => String::from("CLS"),
+            (0x00, 0xee) => String::from("RET"),
             _ => format!(
-                "CALL \t${:02x}{:02x}",
+                "SYS\t${:x}{:02x}",
                 code_buffer[program_counter] & 0xf,
                 code_buffer[program_counter + 1]
             ),
@@ -47,10 +57,10 @@ pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> u
                 code_buffer[program_counter + 1]
             )
         }
+        0x5 if code_buffer[program_counter + 1] & 0xf != 0 => unknown,
         0x5 => {
-            println!("--------------HERE-----------------");
             format!(
-                "S.EQ\tV{:x}, v{:x}",
+                "S.EQ\tV{:x}, V{:x}",
                 code_buffer[program_counter] & 0xf,
                 code_buffer[program_counter + 1] >> 4
             )
@@ -102,16 +112,25 @@ pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> u
                     code_buffer[program_counter] & 0xf,
                     code_buffer[program_counter + 1] >> 4
                 ),
-                0x6 => format!("SHR\tV{:x}", code_buffer[program_counter] & 0xf),
+                0x6 => shift(
+                    "SHR",
+                    code_buffer[program_counter],
+                    code_buffer[program_counter + 1],
+                ),
                 0x7 => format!(
                     "SUBN\tV{:x}, V{:x}",
                     code_buffer[program_counter] & 0xf,
                     code_buffer[program_counter + 1] >> 4
                 ),
-                0xe => format!("SHL\tV{:x}", code_buffer[program_counter] & 0xf),
-                _ => format!(""),
+                0xe => shift(
+                    "SHL",
+                    code_buffer[program_counter],
+                    code_buffer[program_counter + 1],
+                ),
+                _ => unknown,
             }
         }
+        0x9 if code_buffer[program_counter + 1] & 0xf != 0 => unknown,
         0x9 => {
             format!(
                 "S.NEQ\tV{:x}, V{:x}",
@@ -151,7 +170,7 @@ pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> u
         0xe => match code_buffer[program_counter + 1] {
             0x9e => format!("SKP\tV{:x}", code_buffer[program_counter] & 0xf),
             0xa1 => format!("SKNP\tV{:x}", code_buffer[program_counter] & 0xf),
-            _ => format!(""),
+            _ => unknown,
         },
         0xf => match code_buffer[program_counter + 1] {
             0x07 => format!("MOV\tV{:x}, DT", code_buffer[program_counter] & 0xf),
@@ -162,12 +181,62 @@ pub fn disassemble_chip8(code_buffer: &Vec<u8>, mut program_counter: usize) -> u
             0x29 => format!("SPRITECHAR\tI, V{:x}", code_buffer[program_counter] & 0xf),
             0x33 => format!("MOVBCD\t(I), V{:x}", code_buffer[program_counter] & 0xf),
             0x55 => format!("MVM\t(I), V0-V{:x}", code_buffer[program_counter] & 0xf),
-            0x65 => format!("MVM\tV0-v{:x}", code_buffer[program_counter] & 0xf),
-            _ => format!("unknown"),
+            0x65 => format!("MVM\tV0-V{:x}, (I)", code_buffer[program_counter] & 0xf),
+            _ => unknown,
         },
-        _ => format!("not impl"),
+        _ => unknown,
     };
-    println!("{}", assembly_code);
-    program_counter += 2;
-    program_counter
+    assembly_code
+}
+
+//8XY6 and 8XYE only show VY when it is set, it matters with the shift quirk
+fn shift(mnemonic: &str, higher_byte: u8, lower_byte: u8) -> String {
+    if lower_byte >> 4 == 0 {
+        format!("{}\tV{:x}", mnemonic, higher_byte & 0xf)
+    } else {
+        format!(
+            "{}\tV{:x}, V{:x}",
+            mnemonic,
+            higher_byte & 0xf,
+            lower_byte >> 4
+        )
+    }
+}
+
+//full listing of a program loaded at `origin`, one instruction per line with
+//the address and raw bytes in a comment. with a debug map the labels come
+//back, as lines of their own and in place of addresses, and the comment
+//names the source line
+pub fn disassemble_program(code_buffer: &[u8], origin: u16, symbols: Option<&DebugMap>) -> String {
+    let mut listing = String::new();
+    let mut program_counter = 0;
+    while program_counter < code_buffer.len() {
+        let end = (program_counter + 2).min(code_buffer.len());
+        let raw: String = code_buffer[program_counter..end]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let address = origin as usize + program_counter;
+        let mut instruction = disassemble_chip8(code_buffer, program_counter);
+        let mut location = String::new();
+        if let Some(symbols) = symbols {
+            if let Some(label) = symbols.label_at(address as u16) {
+                listing.push_str(&format!("{}:\n", label));
+            }
+            let end = origin as usize + code_buffer.len();
+            instruction = symbols.label_operands_where(&instruction, |a| {
+                let a = a as usize;
+                a >= origin as usize && a < end && (a - origin as usize).is_multiple_of(2)
+            });
+            if let Some(source) = symbols.location(address as u16) {
+                location = format!(" {}", source);
+            }
+        }
+        listing.push_str(&format!(
+            "\t{}\t; {:04x}: {}{}\n",
+            instruction, address, raw, location
+        ));
+        program_counter += 2;
+    }
+    listing
 }
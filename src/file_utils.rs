@@ -1,18 +1,4 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-
-pub fn read_file_to_buffer(path: &str) -> Vec<u8> {
-    println!("Reading file: {}", path.trim());
-    let f = File::open(&path).expect("no file found");
-    let mut reader = BufReader::new(f);
-    let mut buffer = Vec::new();
-
-    // Read file into vector.
-    reader
-        .read_to_end(&mut buffer)
-        .expect("Failed to read buffer");
-
-    // Read.
-    buffer
+//lowercase hex sha1, the id used for movies and per-rom settings
+pub fn sha1_hex(buffer: &[u8]) -> String {
+    sha1_smol::Sha1::from(buffer).digest().to_string()
 }
@@ -0,0 +1,554 @@
+use super::audio::Beeper;
+use super::chip8::{Chip8, VIDEO_HEIGHT, VIDEO_WIDTH};
+use super::debugger;
+use super::file_utils;
+use super::gdbstub::{GdbStub, Request};
+use super::keymap::Keymap;
+use super::loader::{Rom, RomWatcher};
+use super::memory_viewer::MemoryViewer;
+use super::movie::{Movie, MovieHeader, Player};
+use super::profiler::Profile;
+use super::recorder::Recorder;
+use super::renderer::{Backend, Effects, Renderer};
+use super::scheduler::{Scheduler, Speed};
+use super::screenshot;
+use super::settings::Settings;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use std::path::Path;
+use std::time::Instant;
+//const variables definition
+const SCREEN_WIDTH: usize = VIDEO_WIDTH as usize;
+const SCREEN_HEIGHT: usize = VIDEO_HEIGHT as usize;
+const SLOW_MOTION_SPEED: f64 = 0.25;
+//window title for roms that aren't in the database
+const WINDOW_TITLE: &str = "Chip 8 Emu";
+//movies are stored next to the rom with this extension
+const MOVIE_EXTENSION: &str = "c8m";
+//when a replay ends pause there, otherwise hand the keypad back to the player
+const PAUSE_AT_MOVIE_END: bool = true;
+
+//runs a rom in an SDL window until it is closed
+pub fn run(rom_path: &str, rom: &Rom, settings: &Settings) -> Result<(), String> {
+    let keymap = Keymap::parse(&settings.keymap)?;
+    let scale = settings.scale;
+    let window_width = SCREEN_WIDTH as u32 * scale;
+    let window_height = SCREEN_HEIGHT as u32 * scale;
+    //setup sdl
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    video_subsystem.gl_attr().set_double_buffer(true);
+    let name = settings.title.as_deref().unwrap_or(WINDOW_TITLE);
+    let window = video_subsystem
+        .window(name, window_width, window_height)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let canvas_builder = window.into_canvas();
+    let canvas_builder = match settings.renderer {
+        Backend::Software => canvas_builder.software(),
+        Backend::Accelerated => canvas_builder.accelerated(),
+    };
+    let mut canvas = canvas_builder.build().map_err(|e| e.to_string())?;
+    canvas.clear();
+    canvas.present();
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, window_width, window_height)
+        .map_err(|e| e.to_string())?;
+    let mut renderer = Renderer::new(SCREEN_WIDTH, SCREEN_HEIGHT, scale as usize);
+    renderer.effects = settings.effects;
+    renderer.palette = settings.palette;
+    let mut beeper = if settings.mute {
+        None
+    } else {
+        match Beeper::new(&sdl_context) {
+            Ok(beeper) => Some(beeper),
+            Err(e) => {
+                println!("No sound: {}", e);
+                None
+            }
+        }
+    };
+
+    let mut event_pump = sdl_context.event_pump()?;
+
+    //initialize emulator and load game rom
+    let mut chip8 = settings.create_machine(rom)?;
+    //what the bus makes read-only, a replayed movie brings its own
+    let mut protect = settings.protect.clone();
+    let mut rom = rom.program.clone();
+    let mut rom_sha1 = file_utils::sha1_hex(&rom);
+    let mut watcher = settings
+        .watch
+        .then(|| RomWatcher::new(rom_path, &rom, settings.load_address));
+    let mut scheduler = Scheduler::new(settings.instructions_per_second);
+    let normal_speed = Speed::Scaled(settings.speed);
+    scheduler.set_speed(normal_speed, Instant::now());
+    let mut slow_motion = false;
+    let mut turbo = false;
+    let mut title = String::new();
+    let movie_path = settings.movie.clone().unwrap_or_else(|| {
+        Path::new(rom_path)
+            .with_extension(MOVIE_EXTENSION)
+            .to_string_lossy()
+            .into_owned()
+    });
+    let mut recording: Option<Movie> = None;
+    let mut playback: Option<Player> = None;
+    let mut video_recording: Option<Recorder> = None;
+    let mut memory_viewer: Option<MemoryViewer> = None;
+    let mut gdb = settings.gdb.map(GdbStub::listen).transpose()?;
+    if settings.movie.is_some() {
+        let player = start_playback(
+            &movie_path,
+            &rom,
+            &rom_sha1,
+            settings,
+            &mut chip8,
+            &mut scheduler,
+        )?;
+        protect = player.header().protect.clone();
+        playback = Some(player);
+    }
+    if let Some(path) = &settings.record {
+        video_recording = Some(
+            Recorder::create(
+                path,
+                SCREEN_WIDTH,
+                SCREEN_HEIGHT,
+                scale as usize,
+                renderer.palette,
+                settings.gif_skip,
+            )
+            .map_err(|e| format!("Failed to start recording: {}", e))?,
+        );
+    }
+    if settings.debug {
+        scheduler.set_paused(true, Instant::now());
+        println!("{}", debugger::format_state(&chip8));
+    }
+    //run emulator
+    'running: loop {
+        let now = Instant::now();
+        let was_paused = scheduler.is_paused();
+        for evt in event_pump.poll_iter() {
+            //keys typed into the memory viewer are for the viewer, not the game
+            let viewer_id = memory_viewer.as_ref().map(MemoryViewer::window_id);
+            match evt {
+                Event::KeyDown {
+                    keycode: Some(key),
+                    window_id,
+                    ..
+                } if Some(window_id) == viewer_id => match key {
+                    Keycode::Escape | Keycode::Backquote => memory_viewer = None,
+                    _ => {
+                        if let Some(viewer) = &mut memory_viewer {
+                            viewer.handle_key(key, &mut chip8, scheduler.is_paused());
+                        }
+                    }
+                },
+                Event::KeyUp { window_id, .. } if Some(window_id) == viewer_id => {}
+                //with two windows open sdl doesn't send Quit for the game window
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } => {
+                    if Some(window_id) == viewer_id {
+                        memory_viewer = None;
+                    } else {
+                        break 'running;
+                    }
+                }
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => {
+                    break 'running;
+                }
+                Event::KeyDown {
+                    keycode: Some(key),
+                    keymod,
+                    repeat,
+                    ..
+                } => {
+                    if let Some(k) = keymap.key_to_button(key) {
+                        if playback.is_none() {
+                            chip8.keypress(k, true);
+                            if let Some(movie) = &mut recording {
+                                movie.record(chip8.frame_count(), k, true);
+                            }
+                        }
+                    } else if !repeat {
+                        match key {
+                            Keycode::F6 => {
+                                //go on past a breakpoint the machine stopped on
+                                if scheduler.is_paused() {
+                                    let pc = chip8.pc();
+                                    chip8.breakpoints_mut().resume_from(pc);
+                                }
+                                scheduler.set_paused(!scheduler.is_paused(), now)
+                            }
+                            Keycode::F7 => {
+                                slow_motion = !slow_motion;
+                                if !turbo {
+                                    scheduler.set_speed(base_speed(normal_speed, slow_motion), now);
+                                }
+                            }
+                            Keycode::F8 => {
+                                let pc = chip8.pc();
+                                chip8.breakpoints_mut().resume_from(pc);
+                                scheduler.advance_frame()
+                            }
+                            //memory viewer window, next to the game
+                            Keycode::Backquote => {
+                                if memory_viewer.take().is_none() {
+                                    let (x, y) = canvas.window().position();
+                                    let position = (x + window_width as i32, y);
+                                    match MemoryViewer::open(&video_subsystem, position) {
+                                        Ok(viewer) => memory_viewer = Some(viewer),
+                                        Err(e) => println!("Failed to open memory viewer: {}", e),
+                                    }
+                                }
+                            }
+                            Keycode::Tab => {
+                                turbo = true;
+                                scheduler.set_speed(settings.turbo, now);
+                            }
+                            //start or stop recording, recording always starts from a reset
+                            Keycode::F9 => {
+                                if let Some(mut movie) = recording.take() {
+                                    movie.finish(chip8.frame_count());
+                                    match movie.save(&movie_path) {
+                                        Ok(()) => println!("Saved movie: {}", movie_path),
+                                        Err(e) => println!("Failed to save movie: {}", e),
+                                    }
+                                } else if playback.is_none() {
+                                    restart(&mut chip8, &rom, &mut scheduler)?;
+                                    recording = Some(Movie::new(MovieHeader {
+                                        rom_sha1: rom_sha1.clone(),
+                                        quirks: chip8.quirks().profile,
+                                        stack_depth: chip8.quirks().stack_depth,
+                                        memory_policy: chip8.memory_policy(),
+                                        timing: chip8.timing(),
+                                        seed: chip8.seed(),
+                                        load_address: chip8.load_address(),
+                                        entry_point: chip8.entry_point(),
+                                        instructions_per_second: scheduler
+                                            .instructions_per_second(),
+                                        protect: protect.clone(),
+                                    }));
+                                }
+                            }
+                            //start or stop replaying the movie for this rom
+                            Keycode::F10 => {
+                                if playback.take().is_some() {
+                                    release_keys(&mut chip8);
+                                } else if recording.is_none() {
+                                    match start_playback(
+                                        &movie_path,
+                                        &rom,
+                                        &rom_sha1,
+                                        settings,
+                                        &mut chip8,
+                                        &mut scheduler,
+                                    ) {
+                                        Ok(player) => {
+                                            protect = player.header().protect.clone();
+                                            playback = Some(player);
+                                        }
+                                        Err(e) => println!("{}", e),
+                                    }
+                                }
+                            }
+                            //record a gif, with shift a raw rgb24 stream
+                            Keycode::F11 => {
+                                if let Some(recorder) = video_recording.take() {
+                                    match recorder.finish() {
+                                        Ok(()) => println!("Stopped recording"),
+                                        Err(e) => println!("Failed to save recording: {}", e),
+                                    }
+                                } else {
+                                    let extension =
+                                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                            "rgb"
+                                        } else {
+                                            "gif"
+                                        };
+                                    let path = screenshot::screenshot_filename(rom_path, extension);
+                                    match Recorder::create(
+                                        &path,
+                                        SCREEN_WIDTH,
+                                        SCREEN_HEIGHT,
+                                        scale as usize,
+                                        renderer.palette,
+                                        settings.gif_skip,
+                                    ) {
+                                        Ok(recorder) => {
+                                            println!("Recording to: {}", path);
+                                            video_recording = Some(recorder);
+                                        }
+                                        Err(e) => println!("Failed to start recording: {}", e),
+                                    }
+                                }
+                            }
+                            //screenshot at native resolution, with shift at window scale
+                            Keycode::F12 => {
+                                let scale = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                    scale as usize
+                                } else {
+                                    1
+                                };
+                                let path = screenshot::screenshot_filename(rom_path, "png");
+                                match screenshot::write_png(
+                                    &path,
+                                    chip8.get_display(),
+                                    SCREEN_WIDTH,
+                                    SCREEN_HEIGHT,
+                                    scale,
+                                    &renderer.palette,
+                                ) {
+                                    Ok(()) => println!("Saved screenshot: {}", path),
+                                    Err(e) => println!("Failed to save screenshot: {}", e),
+                                }
+                            }
+                            _ => toggle_effect(&mut renderer.effects, key),
+                        }
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(k) = keymap.key_to_button(key) {
+                        if playback.is_none() {
+                            chip8.keypress(k, false);
+                            if let Some(movie) = &mut recording {
+                                movie.record(chip8.frame_count(), k, false);
+                            }
+                        }
+                    } else if key == Keycode::Tab {
+                        turbo = false;
+                        scheduler.set_speed(base_speed(normal_speed, slow_motion), now);
+                    }
+                }
+                _ => {}
+            }
+        }
+        //a rebuilt rom starts over, settings and keymap stay as they are
+        if let Some(result) = watcher.as_mut().and_then(|w| w.poll(now)) {
+            match result {
+                Ok(new_rom) => {
+                    if recording.take().is_some() {
+                        println!("Stopped recording a movie of the old rom");
+                    }
+                    if playback.take().is_some() {
+                        release_keys(&mut chip8);
+                    }
+                    protect = settings.protect.clone();
+                    chip8.set_bus(Box::new(settings.create_bus(new_rom.program.len())));
+                    match restart(&mut chip8, &new_rom.program, &mut scheduler) {
+                        Ok(()) => {
+                            if let Err(e) = settings.attach_symbols(&mut chip8, &new_rom) {
+                                println!("{}", e);
+                            }
+                            rom = new_rom.program;
+                            rom_sha1 = file_utils::sha1_hex(&rom);
+                            //counts for the old rom would be misleading
+                            if chip8.profile().is_some() {
+                                chip8.set_profile(Some(Profile::new()));
+                            }
+                            println!("Reloaded {}", rom_path);
+                        }
+                        Err(e) => {
+                            println!("Failed to reload: {}", e);
+                            chip8.set_bus(Box::new(settings.create_bus(rom.len())));
+                            restart(&mut chip8, &rom, &mut scheduler)?;
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to reload: {}", e),
+            }
+        }
+        //gdb halts and resumes the machine, single steps happen inside the stub
+        if let Some(stub) = &mut gdb {
+            match stub.poll(&mut chip8) {
+                Some(Request::Halt) => scheduler.set_paused(true, now),
+                Some(Request::Resume) => scheduler.set_paused(false, now),
+                None => {}
+            }
+        }
+        let frames = scheduler.frames_due(Instant::now());
+        for _ in 0..frames {
+            //a frame a breakpoint or gdb stopped carries on where it was
+            let resuming = chip8.mid_frame();
+            if let Some(player) = playback.as_mut().filter(|_| !resuming) {
+                if player.finished(&chip8) {
+                    playback = None;
+                    release_keys(&mut chip8);
+                    if PAUSE_AT_MOVIE_END {
+                        scheduler.set_paused(true, Instant::now());
+                        break;
+                    }
+                } else {
+                    player.apply(&mut chip8);
+                }
+            }
+            //stop on the faulting instruction so it can be looked at
+            let budget = if resuming {
+                0
+            } else {
+                scheduler.frame_budget(chip8.timing())
+            };
+            if let Err(fault) = chip8.run_frame(budget) {
+                scheduler.set_paused(true, Instant::now());
+                //gdb shows the stop itself
+                if let Some(stub) = gdb.as_mut().filter(|stub| stub.is_connected()) {
+                    stub.stopped(&fault);
+                    break;
+                }
+                println!("Fault: {}", fault);
+                if let Some(trace) = debugger::format_trace(&chip8) {
+                    println!("{}", trace);
+                }
+                if !settings.debug {
+                    println!("{}", debugger::format_state(&chip8));
+                }
+                break;
+            }
+            if let Some(recorder) = &mut video_recording {
+                if let Err(e) = recorder.add_frame(chip8.get_display()) {
+                    println!("Failed to record frame: {}", e);
+                    video_recording = None;
+                }
+            }
+        }
+        if settings
+            .frames
+            .is_some_and(|limit| chip8.frame_count() >= limit)
+        {
+            break 'running;
+        }
+        if settings.debug && scheduler.is_paused() && (frames > 0 || !was_paused) {
+            println!("{}", debugger::format_state(&chip8));
+        }
+        if let Some(beeper) = &mut beeper {
+            beeper.set_playing(chip8.sound_timer() > 0 && !scheduler.is_paused());
+        }
+        let status = if playback.is_some() {
+            " - replay"
+        } else if recording.is_some() {
+            " - recording"
+        } else if video_recording.is_some() {
+            " - capturing"
+        } else {
+            ""
+        };
+        let new_title = window_title(name, &scheduler) + status;
+        if new_title != title {
+            canvas
+                .window_mut()
+                .set_title(&new_title)
+                .map_err(|e| e.to_string())?;
+            title = new_title;
+        }
+        //draw to window
+        draw_screen(&chip8, &mut renderer, &mut texture, &mut canvas);
+        if let Some(viewer) = &mut memory_viewer {
+            viewer.draw(&chip8)?;
+        }
+        std::thread::sleep(scheduler.time_until_next_frame(Instant::now()));
+    }
+    if let Some(recorder) = video_recording {
+        recorder
+            .finish()
+            .map_err(|e| format!("Failed to save recording: {}", e))?;
+    }
+    if let Some(tracer) = chip8.tracer_mut() {
+        tracer.flush();
+    }
+    settings.save_profile(&chip8, &rom)?;
+    Ok(())
+}
+fn draw_screen(
+    chip8: &Chip8,
+    renderer: &mut Renderer,
+    texture: &mut Texture,
+    canvas: &mut Canvas<Window>,
+) {
+    let pitch = renderer.pitch();
+    let pixels = renderer.render(chip8.get_display());
+    texture.update(None, pixels, pitch).unwrap();
+    canvas.copy(texture, None, None).unwrap();
+    canvas.present();
+}
+//loads a movie and restarts the machine with the settings it was recorded with
+fn start_playback(
+    movie_path: &str,
+    rom: &[u8],
+    rom_sha1: &str,
+    settings: &Settings,
+    chip8: &mut Chip8,
+    scheduler: &mut Scheduler,
+) -> Result<Player, String> {
+    let movie = Movie::load(movie_path).map_err(|e| format!("Failed to load movie: {}", e))?;
+    if movie.header.rom_sha1 != rom_sha1 {
+        return Err(String::from("Movie was recorded with a different rom"));
+    }
+    let header = &movie.header;
+    let mut quirks = header.quirks.quirks();
+    quirks.stack_depth = header.stack_depth;
+    chip8.set_quirks(quirks);
+    chip8.set_memory_policy(header.memory_policy);
+    chip8.set_timing(header.timing);
+    chip8.set_seed(header.seed);
+    chip8.set_load_address(header.load_address);
+    chip8.set_entry_point(header.entry_point);
+    //the recording's protected areas, at its load address
+    let mut settings = settings.clone();
+    header.apply(&mut settings);
+    chip8.set_bus(Box::new(settings.create_bus(rom.len())));
+    scheduler.set_instructions_per_second(header.instructions_per_second);
+    restart(chip8, rom, scheduler)?;
+    Ok(Player::new(movie))
+}
+//fresh machine with the same rom, settings and seed, used by movies
+fn restart(chip8: &mut Chip8, rom: &[u8], scheduler: &mut Scheduler) -> Result<(), String> {
+    chip8.reset();
+    scheduler.reset_cycle_carry();
+    chip8.load_program(rom)
+}
+fn release_keys(chip8: &mut Chip8) {
+    for key in 0..16 {
+        chip8.keypress(key, false);
+    }
+}
+fn base_speed(normal_speed: Speed, slow_motion: bool) -> Speed {
+    if slow_motion {
+        Speed::Scaled(SLOW_MOTION_SPEED)
+    } else {
+        normal_speed
+    }
+}
+fn window_title(name: &str, scheduler: &Scheduler) -> String {
+    if scheduler.is_paused() {
+        format!("{} - paused", name)
+    } else {
+        format!("{} - {}", name, scheduler.speed())
+    }
+}
+//runtime switches for the crt effects
+fn toggle_effect(effects: &mut Effects, key: Keycode) {
+    match key {
+        Keycode::F1 => effects.scanlines = !effects.scanlines,
+        Keycode::F2 => effects.grid = !effects.grid,
+        Keycode::F3 => effects.bloom = !effects.bloom,
+        Keycode::F4 => effects.curvature = !effects.curvature,
+        Keycode::F5 => effects.vignette = !effects.vignette,
+        _ => {}
+    }
+}
@@ -0,0 +1,736 @@
+use super::chip8::{Chip8, Fault, FaultKind, MEMORY_SIZE};
+use super::debugger::{WatchKind, Watchpoint};
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+//registers in the order of the g packet and the target description. V0-VF,
+//I and PC are sent little endian like gdb expects from most targets
+const REGISTERS: [(&str, u32); 21] = [
+    ("v0", 8),
+    ("v1", 8),
+    ("v2", 8),
+    ("v3", 8),
+    ("v4", 8),
+    ("v5", 8),
+    ("v6", 8),
+    ("v7", 8),
+    ("v8", 8),
+    ("v9", 8),
+    ("va", 8),
+    ("vb", 8),
+    ("vc", 8),
+    ("vd", 8),
+    ("ve", 8),
+    ("vf", 8),
+    ("i", 16),
+    ("pc", 16),
+    ("sp", 8),
+    ("dt", 8),
+    ("st", 8),
+];
+const PACKET_SIZE: usize = 0x4000;
+
+//what the frontend should do with its own pause state after a poll
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Request {
+    Halt,
+    Resume,
+}
+
+//gdb remote serial protocol on a local tcp port. the stub is polled once per
+//frame and never blocks; while gdb has the machine halted the frontend stays
+//paused and single steps happen here
+pub struct GdbStub {
+    listener: TcpListener,
+    session: Option<Session<TcpStream>>,
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| format!("gdb port {}: {}", port, e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("gdb port {}: {}", port, e))?;
+        println!("Waiting for gdb on 127.0.0.1:{}", port);
+        Ok(Self {
+            listener,
+            session: None,
+        })
+    }
+    pub fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+    pub fn is_halted(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| session.halted)
+    }
+
+    pub fn poll(&mut self, chip8: &mut Chip8) -> Option<Request> {
+        let Some(session) = &mut self.session else {
+            return self.accept();
+        };
+        //only the reads are non blocking, replies are written in one go
+        let received = session.connection.set_nonblocking(true).is_ok()
+            && session.receive()
+            && session.connection.set_nonblocking(false).is_ok();
+        let request = if received {
+            session.process(chip8)
+        } else {
+            None
+        };
+        if !received || !session.open {
+            return self.disconnect(chip8);
+        }
+        request
+    }
+
+    //the machine stopped by itself while gdb had it running
+    pub fn stopped(&mut self, fault: &Fault) {
+        if let Some(session) = &mut self.session {
+            session.stopped(fault);
+        }
+    }
+
+    fn accept(&mut self) -> Option<Request> {
+        let (connection, address) = self.listener.accept().ok()?;
+        println!("gdb connected from {}", address);
+        self.session = Some(Session::new(connection));
+        //gdb expects a stopped target when it attaches
+        Some(Request::Halt)
+    }
+    fn disconnect(&mut self, chip8: &mut Chip8) -> Option<Request> {
+        println!("gdb disconnected");
+        if let Some(session) = self.session.take() {
+            session.remove_points(chip8);
+        }
+        Some(Request::Resume)
+    }
+}
+
+//one gdb connection, anything that reads and writes bytes. reads must not
+//block, running out of data is WouldBlock
+struct Session<C> {
+    connection: C,
+    //false once gdb detached or the connection failed
+    open: bool,
+    //bytes received but not handled yet
+    buffer: Vec<u8>,
+    no_ack: bool,
+    halted: bool,
+    //answer to '?'
+    last_stop: String,
+    //the breakpoints and watchpoints gdb inserted, removed when it leaves
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watchpoint>,
+}
+
+impl<C: Read + Write> Session<C> {
+    fn new(connection: C) -> Self {
+        Self {
+            connection,
+            open: true,
+            buffer: Vec::new(),
+            no_ack: false,
+            halted: true,
+            last_stop: String::from("S05"),
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+        }
+    }
+    //leaves --break breakpoints and anything else gdb didn't set alone
+    fn remove_points(self, chip8: &mut Chip8) {
+        let breakpoints = chip8.breakpoints_mut();
+        for address in self.breakpoints {
+            breakpoints.remove(address);
+        }
+        for watch in self.watches {
+            breakpoints.remove_watch(watch);
+        }
+    }
+
+    //false when the connection is closed
+    fn receive(&mut self) -> bool {
+        let mut data = [0; 4096];
+        loop {
+            match self.connection.read(&mut data) {
+                Ok(0) => return false,
+                Ok(n) => self.buffer.extend_from_slice(&data[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn process(&mut self, chip8: &mut Chip8) -> Option<Request> {
+        let mut request = None;
+        while self.open {
+            let Some(packet) = self.next_packet() else {
+                break;
+            };
+            match packet {
+                Packet::Interrupt => {
+                    if !self.halted {
+                        self.halted = true;
+                        //SIGINT
+                        self.last_stop = String::from("T02");
+                        self.send(&self.last_stop.clone());
+                        request = Some(Request::Halt);
+                    }
+                }
+                Packet::Bad => self.send_raw(b"-"),
+                Packet::Data(data) => {
+                    if !self.no_ack {
+                        self.send_raw(b"+");
+                    }
+                    if let Some(r) = self.handle(&data, chip8) {
+                        request = Some(r);
+                    }
+                }
+            }
+        }
+        request
+    }
+
+    fn stopped(&mut self, fault: &Fault) {
+        if self.halted {
+            return;
+        }
+        self.halted = true;
+        self.last_stop = stop_reply(fault);
+        self.send(&self.last_stop.clone());
+    }
+
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.buffer.first()? {
+                b'$' => break,
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                //acks and line noise
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+        let end = self.buffer.iter().position(|&b| b == b'#')?;
+        if self.buffer.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if !self.no_ack && checksum != Some(checksum_of(data)) {
+            return Some(Packet::Bad);
+        }
+        Some(Packet::Data(String::from_utf8_lossy(data).into_owned()))
+    }
+
+    fn handle(&mut self, packet: &str, chip8: &mut Chip8) -> Option<Request> {
+        let mut chars = packet.chars();
+        let command = chars.next();
+        let arguments = chars.as_str();
+        let reply = match command {
+            Some('?') => self.last_stop.clone(),
+            Some('g') => {
+                let values: Vec<String> = (0..REGISTERS.len())
+                    .map(|n| read_register(chip8, n))
+                    .collect();
+                values.concat()
+            }
+            Some('G') => {
+                let mut offset = 0;
+                for (n, (_, bits)) in REGISTERS.iter().enumerate() {
+                    let length = *bits as usize / 4;
+                    if let Some(value) = arguments.get(offset..offset + length) {
+                        write_register(chip8, n, value);
+                    }
+                    offset += length;
+                }
+                String::from("OK")
+            }
+            Some('p') => match usize::from_str_radix(arguments, 16) {
+                Ok(n) if n < REGISTERS.len() => read_register(chip8, n),
+                _ => String::from("E01"),
+            },
+            Some('P') => match arguments
+                .split_once('=')
+                .and_then(|(n, value)| Some((usize::from_str_radix(n, 16).ok()?, value)))
+            {
+                Some((n, value)) if n < REGISTERS.len() && write_register(chip8, n, value) => {
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            //gdb is fine with short reads, the hex reply has to fit a packet
+            Some('m') => match parse_range(arguments) {
+                Some((start, length)) if start < MEMORY_SIZE => {
+                    let end = start
+                        .saturating_add(length.min(PACKET_SIZE / 2))
+                        .min(MEMORY_SIZE);
+                    chip8.memory()[start..end]
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect()
+                }
+                _ => String::from("E01"),
+            },
+            Some('M') => {
+                let written = arguments.split_once(':').and_then(|(range, data)| {
+                    let (start, length) = parse_range(range)?;
+                    let bytes = decode_hex(data)?;
+                    if bytes.len() != length || start.checked_add(length)? > MEMORY_SIZE {
+                        return None;
+                    }
+                    for (i, byte) in bytes.into_iter().enumerate() {
+                        chip8.poke((start + i) as u16, byte);
+                    }
+                    Some(())
+                });
+                String::from(if written.is_some() { "OK" } else { "E01" })
+            }
+            Some(command @ ('Z' | 'z')) => match parse_breakpoint(arguments) {
+                Some((kind, address, length)) => {
+                    let breakpoints = chip8.breakpoints_mut();
+                    let watch = Watchpoint {
+                        start: address,
+                        length: length.max(1),
+                        kind: match kind {
+                            2 => WatchKind::Write,
+                            3 => WatchKind::Read,
+                            _ => WatchKind::Access,
+                        },
+                    };
+                    match (kind, command == 'Z') {
+                        (0 | 1, true) => {
+                            breakpoints.add(address);
+                            self.breakpoints.insert(address);
+                        }
+                        (0 | 1, false) => {
+                            breakpoints.remove(address);
+                            self.breakpoints.remove(&address);
+                        }
+                        (_, true) => {
+                            breakpoints.add_watch(watch);
+                            if !self.watches.contains(&watch) {
+                                self.watches.push(watch);
+                            }
+                        }
+                        (_, false) => {
+                            breakpoints.remove_watch(watch);
+                            self.watches.retain(|w| *w != watch);
+                        }
+                    }
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            Some('c') => {
+                let pc = chip8.pc();
+                chip8.breakpoints_mut().resume_from(pc);
+                self.halted = false;
+                //the reply comes when the machine stops
+                return Some(Request::Resume);
+            }
+            Some('s') => {
+                let pc = chip8.pc();
+                chip8.breakpoints_mut().resume_from(pc);
+                self.last_stop = match chip8.emulate_cycle() {
+                    Ok(()) => String::from("T05"),
+                    Err(fault) => stop_reply(&fault),
+                };
+                self.last_stop.clone()
+            }
+            Some('D') => {
+                self.send("OK");
+                self.open = false;
+                return None;
+            }
+            Some('k') => {
+                self.open = false;
+                return None;
+            }
+            Some('H' | 'T') => String::from("OK"),
+            //None when query already replied
+            Some('q' | 'Q') => self.query(packet)?,
+            _ => String::new(),
+        };
+        self.send(&reply);
+        None
+    }
+
+    //None when the reply has already been sent
+    fn query(&mut self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            ));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(range) else {
+                return Some(String::from("E01"));
+            };
+            let description = target_description();
+            //one byte of the packet goes to the m/l prefix
+            let end = offset
+                .saturating_add(length.min(PACKET_SIZE - 1))
+                .min(description.len());
+            return Some(match description.get(offset.min(end)..end) {
+                Some(chunk) if end < description.len() => format!("m{}", chunk),
+                Some(chunk) => format!("l{}", chunk),
+                None => String::from("E01"),
+            });
+        }
+        let reply = match packet {
+            //acknowledged with acks, everything after without
+            "QStartNoAckMode" => {
+                self.send("OK");
+                self.no_ack = true;
+                return None;
+            }
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        };
+        Some(String::from(reply))
+    }
+
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send_raw(packet.as_bytes());
+    }
+    fn send_raw(&mut self, data: &[u8]) {
+        if self.open && self.connection.write_all(data).is_err() {
+            self.open = false;
+        }
+    }
+}
+
+enum Packet {
+    Data(String),
+    //ctrl-c from gdb
+    Interrupt,
+    Bad,
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn stop_reply(fault: &Fault) -> String {
+    match fault.kind {
+        FaultKind::Breakpoint => String::from("T05swbreak:;"),
+        FaultKind::Watchpoint { address, watch, .. } => {
+            let kind = match watch {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T05{}:{:x};", kind, address)
+        }
+        //SIGSEGV for everything the program did wrong
+        _ => String::from("T0b"),
+    }
+}
+
+fn read_register(chip8: &Chip8, n: usize) -> String {
+    match n {
+        0..=15 => format!("{:02x}", chip8.registers()[n]),
+        16 => hex_le(chip8.index()),
+        17 => hex_le(chip8.pc()),
+        18 => format!("{:02x}", chip8.stack().len()),
+        19 => format!("{:02x}", chip8.delay_timer()),
+        _ => format!("{:02x}", chip8.sound_timer()),
+    }
+}
+
+fn write_register(chip8: &mut Chip8, n: usize, value: &str) -> bool {
+    let Some(bytes) = decode_hex(value) else {
+        return false;
+    };
+    let byte = bytes.first().copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(1).copied().unwrap_or(0)]);
+    match n {
+        0..=15 => chip8.set_register(n, byte),
+        16 => chip8.set_index(word),
+        17 => chip8.set_pc(word),
+        //only popping makes sense, there is nothing to push
+        18 => chip8.truncate_stack(byte as usize),
+        19 => chip8.set_delay_timer(byte),
+        _ => chip8.set_sound_timer(byte),
+    }
+    true
+}
+
+fn hex_le(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+//"addr,length" in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (start, length) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(start, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+//"type,addr,kind" from Z and z packets, kind is the length for watchpoints
+fn parse_breakpoint(text: &str) -> Option<(u8, u16, u16)> {
+    let mut parts = text.split(',');
+    let kind = parts.next()?.parse().ok()?;
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let length = u16::from_str_radix(parts.next()?.split(';').next()?, 16).ok()?;
+    (kind <= 4 && (address as usize) < MEMORY_SIZE).then_some((kind, address, length))
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.chip8.core\">\n",
+    );
+    for (name, bits) in REGISTERS {
+        let kind = match name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ => "uint8",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>\n",
+            name, bits, kind
+        ));
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //V0=5, V1=7, I=0x300, store V0-V1, loop
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0x61, 0x07, 0xa3, 0x00, 0xf1, 0x55, 0x12, 0x08];
+
+    //plays back what gdb would send and keeps what the stub answers
+    #[derive(Default)]
+    struct Scripted {
+        input: Vec<u8>,
+        position: usize,
+        output: Vec<u8>,
+    }
+    impl Read for Scripted {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let rest = &self.input[self.position..];
+            if rest.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = rest.len().min(buffer.len());
+            buffer[..n].copy_from_slice(&rest[..n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+    impl Write for Scripted {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.output.extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&PROGRAM).unwrap();
+        chip8
+    }
+
+    //sends the packets and returns the request and the reply packets
+    fn exchange(
+        session: &mut Session<Scripted>,
+        chip8: &mut Chip8,
+        packets: &[&str],
+    ) -> (Option<Request>, Vec<String>) {
+        for packet in packets {
+            let framed = format!("${}#{:02x}", packet, checksum_of(packet.as_bytes()));
+            session
+                .connection
+                .input
+                .extend_from_slice(framed.as_bytes());
+        }
+        assert!(session.receive());
+        let request = session.process(chip8);
+        (request, sent(session))
+    }
+
+    fn sent(session: &mut Session<Scripted>) -> Vec<String> {
+        let output = String::from_utf8(std::mem::take(&mut session.connection.output)).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|packet| {
+                let end = packet.rfind('#').unwrap();
+                String::from(&packet[..end])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn supported_and_registers() {
+        let mut chip8 = machine();
+        chip8.set_register(0xa, 0x12);
+        chip8.set_index(0x345);
+        let mut session = Session::new(Scripted::default());
+        let (request, replies) = exchange(&mut session, &mut chip8, &["qSupported:swbreak+", "g"]);
+        assert_eq!(request, None);
+        assert!(replies[0].starts_with("PacketSize=4000;"));
+        let registers = &replies[1];
+        assert_eq!(registers.len(), 16 * 2 + 4 + 4 + 3 * 2);
+        assert_eq!(&registers[20..22], "12");
+        assert_eq!(&registers[32..36], "4503");
+        assert_eq!(&registers[36..40], "0002");
+    }
+
+    #[test]
+    fn memory_reads_and_writes() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (_, replies) = exchange(
+            &mut session,
+            &mut chip8,
+            &["m200,4", "M300,2:abcd", "Mfff,2:0000", "M300,2:ab"],
+        );
+        assert_eq!(replies, ["60056107", "OK", "E01", "E01"]);
+        assert_eq!(&chip8.memory()[0x300..0x302], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn huge_ranges_are_clamped() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (_, replies) = exchange(
+            &mut session,
+            &mut chip8,
+            &[
+                "m0,ffffffffffffffff",
+                "mffffffffffffffff,1",
+                "Mffffffffffffffff,1:00",
+                "qXfer:features:read:target.xml:ffffffffffffffff,ffffffffffffffff",
+            ],
+        );
+        assert_eq!(replies[0].len(), MEMORY_SIZE * 2);
+        assert_eq!(replies[1..], ["E01", "E01", "l"]);
+    }
+
+    #[test]
+    fn non_ascii_packets_are_unsupported() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (_, replies) = exchange(&mut session, &mut chip8, &["é", "\u{00ff}m200,2"]);
+        assert_eq!(replies, ["", ""]);
+    }
+
+    #[test]
+    fn single_step() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (request, replies) = exchange(&mut session, &mut chip8, &["s", "s"]);
+        assert_eq!(request, None);
+        assert_eq!(replies, ["T05", "T05"]);
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.registers()[1], 7);
+    }
+
+    #[test]
+    fn breakpoint_stops_after_continue() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (request, replies) = exchange(&mut session, &mut chip8, &["Z0,204,2", "c"]);
+        assert_eq!(request, Some(Request::Resume));
+        assert_eq!(replies, ["OK"]);
+        assert!(!session.halted);
+        let fault = chip8.run_frame(100).unwrap_err();
+        assert_eq!(fault.pc, 0x204);
+        session.stopped(&fault);
+        assert!(session.halted);
+        assert_eq!(sent(&mut session), ["T05swbreak:;"]);
+        //continuing steps over the breakpoint it stopped on
+        let (_, replies) = exchange(&mut session, &mut chip8, &["z0,204,2", "c"]);
+        assert_eq!(replies, ["OK"]);
+        assert!(chip8.run_frame(100).is_ok());
+    }
+
+    #[test]
+    fn write_watchpoint_stops_after_the_store() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        let (request, replies) = exchange(&mut session, &mut chip8, &["Z2,301,1", "c"]);
+        assert_eq!(request, Some(Request::Resume));
+        assert_eq!(replies, ["OK"]);
+        let fault = chip8.run_frame(100).unwrap_err();
+        assert_eq!(chip8.pc(), 0x208);
+        session.stopped(&fault);
+        assert_eq!(sent(&mut session), ["T05watch:301;"]);
+        assert_eq!(&chip8.memory()[0x300..0x302], [5, 7]);
+    }
+
+    #[test]
+    fn access_watchpoints_are_awatch() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        exchange(&mut session, &mut chip8, &["Z4,300,1", "c"]);
+        let fault = chip8.run_frame(100).unwrap_err();
+        session.stopped(&fault);
+        assert_eq!(sent(&mut session), ["T05awatch:300;"]);
+    }
+
+    #[test]
+    fn leaving_keeps_other_breakpoints() {
+        let mut chip8 = machine();
+        chip8.breakpoints_mut().set_breaks([0x208]);
+        let mut session = Session::new(Scripted::default());
+        exchange(
+            &mut session,
+            &mut chip8,
+            &["Z0,204,2", "Z0,208,2", "Z2,300,2", "D"],
+        );
+        session.remove_points(&mut chip8);
+        //only the --break one is left
+        let fault = chip8.run_frame(100).unwrap_err();
+        assert_eq!((fault.pc, fault.kind), (0x208, FaultKind::Breakpoint));
+    }
+
+    #[test]
+    fn interrupt_and_detach() {
+        let mut chip8 = machine();
+        let mut session = Session::new(Scripted::default());
+        exchange(&mut session, &mut chip8, &["c"]);
+        session.connection.input.push(0x03);
+        assert!(session.receive());
+        assert_eq!(session.process(&mut chip8), Some(Request::Halt));
+        assert_eq!(sent(&mut session), ["T02"]);
+        let (_, replies) = exchange(&mut session, &mut chip8, &["D", "g"]);
+        assert_eq!(replies, ["OK"]);
+        assert!(!session.open);
+    }
+}
@@ -0,0 +1,512 @@
+use super::chip8::{Chip8, MEMORY_SIZE, VIDEO_HEIGHT, VIDEO_WIDTH};
+use super::debug_map::{self, DebugMap};
+use super::loader::Rom;
+use super::scheduler::Scheduler;
+use super::settings::Settings;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::panic;
+use std::str::FromStr;
+use std::thread;
+
+//how a number is stored in memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Byte,
+    //big endian, like addresses in chip-8 code
+    Word,
+    //three decimal digits, the way FX33 writes scores
+    Bcd,
+}
+
+//a number at a memory location, "bcd:2f2" or "byte:lives" with a debug map
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reading {
+    pub encoding: Encoding,
+    pub location: String,
+}
+
+//reward is how much the reading went up since the last step, or down
+//when negated: "bcd:2f2" or "-byte:2f6"
+#[derive(Clone, PartialEq, Debug)]
+pub struct RewardTerm {
+    pub reading: Reading,
+    pub negated: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+//an episode ends when the reading compares true: "byte:lives=0"
+#[derive(Clone, PartialEq, Debug)]
+pub struct DoneRule {
+    pub reading: Reading,
+    pub comparison: Comparison,
+    pub value: i64,
+}
+
+//a Reading with its location looked up
+#[derive(Clone, Copy)]
+struct Probe {
+    encoding: Encoding,
+    address: u16,
+}
+
+impl Probe {
+    fn new(reading: &Reading, symbols: Option<&DebugMap>) -> Result<Probe, String> {
+        let address = match symbols {
+            Some(symbols) => symbols.resolve(&reading.location)?,
+            None => debug_map::parse_address(&reading.location).ok_or(format!(
+                "'{}' is not a hex address and there is no debug map",
+                reading.location
+            ))?,
+        };
+        Ok(Probe {
+            encoding: reading.encoding,
+            address,
+        })
+    }
+    fn read(self, memory: &[u8]) -> i64 {
+        let byte = |offset: usize| memory[(self.address as usize + offset) % MEMORY_SIZE] as i64;
+        match self.encoding {
+            Encoding::Byte => byte(0),
+            Encoding::Word => byte(0) << 8 | byte(1),
+            Encoding::Bcd => byte(0) * 100 + byte(1) * 10 + byte(2),
+        }
+    }
+}
+
+//one game as a reinforcement learning environment: reset(seed), then
+//step(keys) until done. keys has bit n set to hold down key n. every step
+//runs --frame-skip frames, and with --sticky P each of those frames keeps
+//the previous keys instead with probability P
+pub struct Env {
+    chip8: Chip8,
+    scheduler: Scheduler,
+    program: Vec<u8>,
+    frame_skip: u32,
+    sticky: f64,
+    //cut episodes off after this many frames
+    frames: Option<u64>,
+    reward: Vec<(Probe, bool)>,
+    done: Vec<(Probe, Comparison, i64)>,
+    //for sticky keys, seeded by reset
+    rng: StdRng,
+    keys: u16,
+    score: i64,
+    finished: bool,
+    //what ended the episode, if the machine faulted
+    fault: Option<String>,
+}
+
+impl Env {
+    pub fn new(rom: &Rom, settings: &Settings) -> Result<Env, String> {
+        //instances run bare, thousands of traces or profiles would be noise
+        let mut settings = settings.clone();
+        settings.trace = None;
+        settings.profile = None;
+        settings.breaks.clear();
+        let chip8 = settings.create_machine(rom)?;
+        let symbols = rom.symbols.as_deref();
+        let reward = settings
+            .reward
+            .iter()
+            .map(|term| Ok((Probe::new(&term.reading, symbols)?, term.negated)))
+            .collect::<Result<_, String>>()?;
+        let done = settings
+            .done
+            .iter()
+            .map(|rule| {
+                Ok((
+                    Probe::new(&rule.reading, symbols)?,
+                    rule.comparison,
+                    rule.value,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        let mut env = Env {
+            chip8,
+            scheduler: Scheduler::new(settings.instructions_per_second),
+            program: rom.program.clone(),
+            frame_skip: settings.frame_skip,
+            sticky: settings.sticky,
+            frames: settings.frames,
+            reward,
+            done,
+            rng: StdRng::seed_from_u64(0),
+            keys: 0,
+            score: 0,
+            finished: false,
+            fault: None,
+        };
+        env.reset(settings.seed.unwrap_or(0));
+        Ok(env)
+    }
+    //starts a new episode, the same seed plays out the same way
+    pub fn reset(&mut self, seed: u64) -> &[bool] {
+        self.chip8.set_seed(seed);
+        self.chip8.reset();
+        self.scheduler.reset_cycle_carry();
+        //the rom fitted when the machine was made
+        let _ = self.chip8.load_program(&self.program);
+        self.rng = StdRng::seed_from_u64(!seed);
+        self.keys = 0;
+        self.score = self.score();
+        self.finished = false;
+        self.fault = None;
+        self.chip8.get_display()
+    }
+    //(observation, reward, done). a done episode stays done until reset
+    pub fn step(&mut self, keys: u16) -> (&[bool], f64, bool) {
+        if self.finished {
+            return (self.chip8.get_display(), 0.0, true);
+        }
+        for _ in 0..self.frame_skip {
+            if self.sticky == 0.0 || !self.rng.gen_bool(self.sticky) {
+                self.keys = keys;
+            }
+            for key in 0..16 {
+                self.chip8.keypress(key, self.keys & 1 << key != 0);
+            }
+            let budget = self.scheduler.frame_budget(self.chip8.timing());
+            if let Err(fault) = self.chip8.run_frame(budget) {
+                self.fault = Some(fault.to_string());
+                self.finished = true;
+                break;
+            }
+            if self.is_done() {
+                self.finished = true;
+                break;
+            }
+        }
+        let score = self.score();
+        let reward = (score - self.score) as f64;
+        self.score = score;
+        (self.chip8.get_display(), reward, self.finished)
+    }
+    //why a done episode ended early, until the next reset
+    pub fn fault(&self) -> Option<&str> {
+        self.fault.as_deref()
+    }
+    pub fn machine(&self) -> &Chip8 {
+        &self.chip8
+    }
+    fn score(&self) -> i64 {
+        let memory = self.chip8.memory();
+        self.reward
+            .iter()
+            .map(|&(probe, negated)| match negated {
+                true => -probe.read(memory),
+                false => probe.read(memory),
+            })
+            .sum()
+    }
+    fn is_done(&self) -> bool {
+        let memory = self.chip8.memory();
+        self.frames
+            .is_some_and(|limit| self.chip8.frame_count() >= limit)
+            || self
+                .done
+                .iter()
+                .any(|&(probe, comparison, value)| comparison.holds(probe.read(memory), value))
+    }
+}
+
+//runs --envs instances for a learner in another process, stepping them on
+//as many threads as there are cores. one command per line on stdin:
+//
+//  reset SEED [N]    all instances, the nth with SEED+n, or just instance N
+//  step KEYS...      hex key masks, one per instance
+//  quit
+//
+//the first line out is "chip8-gym <instances> 64 32". reset answers with one
+//observation per instance it reset, step with "REWARD DONE OBSERVATION" per
+//instance, followed by "fault MESSAGE" while an episode that the machine
+//faulted in stays done. an observation is the screen as hex, a row at a time
+//with the leftmost pixel in the top bit. bad commands get "error MESSAGE"
+pub fn serve(rom: &Rom, settings: &Settings) -> Result<(), String> {
+    let mut envs = (0..settings.envs)
+        .map(|_| Env::new(rom, settings))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut send = |lines: &[String]| -> Result<(), String> {
+        for line in lines {
+            writeln!(out, "{}", line).map_err(|e| format!("stdout: {}", e))?;
+        }
+        out.flush().map_err(|e| format!("stdout: {}", e))
+    };
+    send(&[format!(
+        "chip8-gym {} {} {}",
+        envs.len(),
+        VIDEO_WIDTH,
+        VIDEO_HEIGHT
+    )])?;
+    for line in io::stdin().lock().lines() {
+        let line = line.map_err(|e| format!("stdin: {}", e))?;
+        let mut words = line.split_whitespace();
+        let command = words.next();
+        let args: Vec<&str> = words.collect();
+        let result = match command {
+            Some("reset") => reset(&mut envs, &args),
+            Some("step") => step(&mut envs, &args),
+            Some("quit") => break,
+            Some(command) => Err(format!("unknown command '{}'", command)),
+            None => continue,
+        };
+        send(&result.unwrap_or_else(|e| vec![format!("error {}", e)]))?;
+    }
+    Ok(())
+}
+
+fn reset(envs: &mut [Env], args: &[&str]) -> Result<Vec<String>, String> {
+    let seed: u64 = match args.first() {
+        Some(seed) => seed.parse().map_err(|_| format!("bad seed '{}'", seed))?,
+        None => return Err(String::from("reset needs a seed")),
+    };
+    match args.get(1) {
+        Some(n) => {
+            let env = n
+                .parse()
+                .ok()
+                .and_then(|n: usize| envs.get_mut(n))
+                .ok_or(format!("there is no instance {}", n))?;
+            Ok(vec![screen_to_hex(env.reset(seed))])
+        }
+        None => Ok(envs
+            .iter_mut()
+            .enumerate()
+            .map(|(n, env)| screen_to_hex(env.reset(seed.wrapping_add(n as u64))))
+            .collect()),
+    }
+}
+
+fn step(envs: &mut [Env], args: &[&str]) -> Result<Vec<String>, String> {
+    if args.len() != envs.len() {
+        return Err(format!(
+            "step needs {} key masks, got {}",
+            envs.len(),
+            args.len()
+        ));
+    }
+    let keys = args
+        .iter()
+        .map(|mask| u16::from_str_radix(mask, 16).map_err(|_| format!("bad key mask '{}'", mask)))
+        .collect::<Result<Vec<_>, _>>()?;
+    //a chunk of instances per thread, the first chunk on this one
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    let chunk = envs.len().div_ceil(threads).max(1);
+    Ok(thread::scope(|scope| {
+        let mut chunks = envs.chunks_mut(chunk).zip(keys.chunks(chunk));
+        let first = chunks.next();
+        let others: Vec<_> = chunks
+            .map(|(envs, keys)| scope.spawn(move || step_chunk(envs, keys)))
+            .collect();
+        let mut lines = first.map_or_else(Vec::new, |(envs, keys)| step_chunk(envs, keys));
+        for other in others {
+            lines.extend(other.join().unwrap_or_else(|e| panic::resume_unwind(e)));
+        }
+        lines
+    }))
+}
+
+fn step_chunk(envs: &mut [Env], keys: &[u16]) -> Vec<String> {
+    envs.iter_mut()
+        .zip(keys)
+        .map(|(env, &keys)| {
+            let (observation, reward, done) = env.step(keys);
+            let reply = format!("{} {} {}", reward, done as u8, screen_to_hex(observation));
+            match env.fault() {
+                Some(fault) => format!("{} fault {}", reply, fault),
+                None => reply,
+            }
+        })
+        .collect()
+}
+
+fn screen_to_hex(video: &[bool]) -> String {
+    video
+        .chunks(8)
+        .map(|pixels| {
+            let byte = pixels.iter().fold(0u8, |byte, &lit| byte << 1 | lit as u8);
+            format!("{:02x}", byte)
+        })
+        .collect()
+}
+
+impl Comparison {
+    fn holds(self, left: i64, right: i64) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl FromStr for Reading {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (encoding, location) = s.split_once(':').ok_or(format!(
+            "'{}' should be byte:, word: or bcd: and a location",
+            s
+        ))?;
+        let encoding = match encoding.trim().to_lowercase().as_str() {
+            "byte" => Encoding::Byte,
+            "word" => Encoding::Word,
+            "bcd" => Encoding::Bcd,
+            _ => return Err(format!("unknown encoding: {}", encoding)),
+        };
+        Ok(Reading {
+            encoding,
+            location: location.trim().to_string(),
+        })
+    }
+}
+
+impl FromStr for RewardTerm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Ok(match s.strip_prefix('-') {
+            Some(reading) => RewardTerm {
+                reading: reading.parse()?,
+                negated: true,
+            },
+            None => RewardTerm {
+                reading: s.trim_start_matches('+').parse()?,
+                negated: false,
+            },
+        })
+    }
+}
+
+impl FromStr for DoneRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //two character operators first so "<=" isn't read as "<"
+        let operators = [
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("=", Comparison::Equal),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        let (reading, comparison, value) = operators
+            .iter()
+            .find_map(|&(operator, comparison)| {
+                s.split_once(operator)
+                    .map(|(reading, value)| (reading, comparison, value))
+            })
+            .ok_or(format!("'{}' needs a comparison like byte:2f6=0", s))?;
+        let value = value.trim();
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| format!("bad number '{}'", value))?;
+        Ok(DoneRule {
+            reading: reading.parse()?,
+            comparison,
+            value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings() {
+        let reading: Reading = " Word : score".parse().unwrap();
+        assert_eq!(reading.encoding, Encoding::Word);
+        assert_eq!(reading.location, "score");
+        assert!("nibble:2f2".parse::<Reading>().is_err());
+        assert!("2f2".parse::<Reading>().is_err());
+    }
+
+    #[test]
+    fn reward_terms() {
+        let term: RewardTerm = "-byte:lives".parse().unwrap();
+        assert!(term.negated);
+        assert_eq!(term.reading.encoding, Encoding::Byte);
+        let term: RewardTerm = "+bcd:2f2".parse().unwrap();
+        assert!(!term.negated);
+        assert_eq!(term.reading.location, "2f2");
+    }
+
+    #[test]
+    fn done_rules() {
+        let rule: DoneRule = "bcd:score>=100".parse().unwrap();
+        assert_eq!(rule.comparison, Comparison::GreaterOrEqual);
+        assert_eq!(rule.value, 100);
+        let rule: DoneRule = "byte:2f6 = 0x10".parse().unwrap();
+        assert_eq!(rule.comparison, Comparison::Equal);
+        assert_eq!(rule.reading.location, "2f6");
+        assert_eq!(rule.value, 16);
+        let rule: DoneRule = "byte:2f6!=0".parse().unwrap();
+        assert_eq!(rule.comparison, Comparison::NotEqual);
+        assert!("byte:2f6".parse::<DoneRule>().is_err());
+        assert!("byte:2f6<lots".parse::<DoneRule>().is_err());
+    }
+
+    #[test]
+    fn reset_is_deterministic() {
+        //draw a 0 at a random spot, forever
+        let rom = Rom {
+            program: vec![
+                0xc0, 0x3f, 0xc1, 0x1f, 0x62, 0x00, 0xf2, 0x29, 0xd0, 0x15, 0x12, 0x00,
+            ],
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let mut settings = Settings::default();
+        settings.set("sticky", "0.5").unwrap();
+        settings.set("frame-skip", "2").unwrap();
+        let play = |env: &mut Env, seed| {
+            let mut screens = vec![env.reset(seed).to_vec()];
+            for keys in [0x1, 0x2, 0x4, 0x8] {
+                screens.push(env.step(keys).0.to_vec());
+            }
+            screens
+        };
+        let mut env = Env::new(&rom, &settings).unwrap();
+        let first = play(&mut env, 7);
+        assert!(first.last().unwrap().contains(&true));
+        play(&mut env, 8);
+        assert_eq!(play(&mut env, 7), first);
+        let mut other = Env::new(&rom, &settings).unwrap();
+        assert_eq!(play(&mut other, 7), first);
+    }
+
+    #[test]
+    fn faults_end_the_episode_until_reset() {
+        //returns with nothing on the stack
+        let rom = Rom {
+            program: vec![0x00, 0xee],
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let mut env = Env::new(&rom, &Settings::default()).unwrap();
+        assert_eq!(env.fault(), None);
+        assert!(env.step(0).2);
+        assert!(env.fault().unwrap().contains("stack underflow"));
+        let replies = step_chunk(std::slice::from_mut(&mut env), &[0]);
+        assert!(replies[0].starts_with("0 1 "));
+        assert!(replies[0].contains(" fault stack underflow"));
+        env.reset(0);
+        assert_eq!(env.fault(), None);
+    }
+}
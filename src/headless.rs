@@ -0,0 +1,263 @@
+use super::chip8::{Chip8, Fault, FaultKind, VIDEO_HEIGHT, VIDEO_WIDTH};
+use super::debugger;
+use super::file_utils;
+use super::gdbstub::GdbStub;
+use super::loader::Rom;
+use super::movie::{Movie, Player};
+use super::recorder::Recorder;
+use super::scheduler::Scheduler;
+use super::script::Script;
+use super::settings::Settings;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+//frames run by the test subcommand when --frames isn't given, 10 seconds
+const DEFAULT_TEST_FRAMES: u64 = 600;
+//how often a halted machine looks for gdb packets
+const GDB_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+//runs a rom as fast as possible without opening a window, replaying a movie,
+//for a fixed number of frames, until a script stops it or under gdb,
+//optionally capturing every frame
+pub fn run(rom: &Rom, settings: &Settings) -> Result<(), String> {
+    let mut settings = settings.clone();
+    let mut player = None;
+    if let Some(movie_path) = &settings.movie {
+        let movie = Movie::load(movie_path).map_err(|e| format!("Failed to load movie: {}", e))?;
+        if movie.header.rom_sha1 != file_utils::sha1_hex(&rom.program) {
+            return Err(String::from("Movie was recorded with a different rom"));
+        }
+        movie.header.apply(&mut settings);
+        player = Some(Player::new(movie));
+    } else if settings.frames.is_none() && settings.script.is_none() && settings.gdb.is_none() {
+        return Err(String::from(
+            "Headless runs need --frames, --movie, --script or --gdb",
+        ));
+    }
+    //without any other limit the run ends when gdb detaches
+    let until_detach = settings.frames.is_none() && player.is_none() && settings.script.is_none();
+    //scripts read and change the machine from their hooks
+    let machine = Rc::new(RefCell::new(settings.create_machine(rom)?));
+    let mut script = match &settings.script {
+        Some(path) => Some(Script::load(path, machine.clone(), &settings)?),
+        None => None,
+    };
+    let mut scheduler = Scheduler::new(settings.instructions_per_second);
+    let mut gdb = settings.gdb.map(GdbStub::listen).transpose()?;
+    let mut attached = false;
+
+    let mut recorder = match &settings.record {
+        Some(path) => Some(
+            Recorder::create(
+                path,
+                VIDEO_WIDTH as usize,
+                VIDEO_HEIGHT as usize,
+                settings.scale as usize,
+                settings.palette,
+                settings.gif_skip,
+            )
+            .map_err(|e| format!("Failed to start recording: {}", e))?,
+        ),
+        None => None,
+    };
+    loop {
+        if settings
+            .frames
+            .is_some_and(|limit| machine.borrow().frame_count() >= limit)
+            || script.as_ref().is_some_and(Script::stopped)
+        {
+            break;
+        }
+        //nothing runs before gdb attaches or while it has the machine halted
+        if let Some(stub) = &mut gdb {
+            stub.poll(&mut machine.borrow_mut());
+            if stub.is_connected() {
+                attached = true;
+            } else if attached && until_detach {
+                break;
+            }
+            if !attached || stub.is_halted() {
+                thread::sleep(GDB_POLL_INTERVAL);
+                continue;
+            }
+        }
+        //gdb stopped the last frame halfway, it finishes once gdb continues
+        //without the movie or the script starting it again
+        let resuming = machine.borrow().mid_frame();
+        if !resuming {
+            if let Some(player) = &mut player {
+                if player.finished(&machine.borrow()) {
+                    break;
+                }
+                player.apply(&mut machine.borrow_mut());
+            }
+            if let Some(script) = &mut script {
+                script.start_frame();
+            }
+        }
+        match run_frame(&machine, &mut scheduler, script.as_mut(), gdb.as_mut()) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(fault) => {
+                let chip8 = machine.borrow();
+                if let Some(trace) = debugger::format_trace(&chip8) {
+                    println!("{}", trace);
+                }
+                return Err(format!("Fault in frame {}: {}", chip8.frame_count(), fault));
+            }
+        }
+        if let Some(script) = &mut script {
+            script.end_frame()?;
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder
+                .add_frame(machine.borrow().get_display())
+                .map_err(|e| format!("Failed to record frame: {}", e))?;
+        }
+    }
+    let mut chip8 = machine.borrow_mut();
+    if let Some(recorder) = recorder {
+        recorder
+            .finish()
+            .map_err(|e| format!("Failed to save recording: {}", e))?;
+    }
+    if let Some(tracer) = chip8.tracer_mut() {
+        tracer.flush();
+    }
+    settings.save_profile(&chip8, &rom.program)?;
+    println!("Ran {} frames", chip8.frame_count());
+    if let Some(path) = &settings.record {
+        println!("Recorded to {}", path);
+    }
+    Ok(())
+}
+
+//runs a rom for a while with no input and prints the final screen, handy for
+//checking test roms that draw their results
+pub fn test(rom: &Rom, settings: &Settings) -> Result<(), String> {
+    if settings.script.is_some() {
+        return Err(String::from("--script drives run, not test"));
+    }
+    let frames = settings.frames.unwrap_or(DEFAULT_TEST_FRAMES);
+    let machine = RefCell::new(settings.create_machine(rom)?);
+    let mut scheduler = Scheduler::new(settings.instructions_per_second);
+    while machine.borrow().frame_count() < frames {
+        if let Err(fault) = run_frame(&machine, &mut scheduler, None, None) {
+            let chip8 = machine.borrow();
+            println!("Fault in frame {}: {}", chip8.frame_count(), fault);
+            if let Some(trace) = debugger::format_trace(&chip8) {
+                println!("{}", trace);
+            }
+            break;
+        }
+    }
+    let mut chip8 = machine.borrow_mut();
+    if let Some(tracer) = chip8.tracer_mut() {
+        tracer.flush();
+    }
+    settings.save_profile(&chip8, &rom.program)?;
+    println!("{}", screen_to_text(&chip8));
+    Ok(())
+}
+
+//the script runs its hooks for every stop and an attached gdb gets the
+//breakpoints and watchpoints the user set, false when gdb stopped the frame.
+//without anyone to resume them, user breakpoints print the machine state and
+//the frame carries on from there
+fn run_frame(
+    machine: &RefCell<Chip8>,
+    scheduler: &mut Scheduler,
+    mut script: Option<&mut Script>,
+    mut gdb: Option<&mut GdbStub>,
+) -> Result<bool, Fault> {
+    let mut budget = if machine.borrow().mid_frame() {
+        0
+    } else {
+        scheduler.frame_budget(machine.borrow().timing())
+    };
+    loop {
+        let result = machine.borrow_mut().run_frame(budget);
+        budget = 0;
+        let fault = match result {
+            Err(fault) => fault,
+            Ok(()) => return Ok(true),
+        };
+        if let Some(script) = script.as_mut() {
+            script.handle(&fault);
+        }
+        //a stop only the script's hooks asked for
+        let hooked_only = match fault.kind {
+            FaultKind::Breakpoint => !machine.borrow().breakpoints().contains(fault.pc),
+            FaultKind::Watchpoint { .. } => machine.borrow().breakpoints().user_hit().is_none(),
+            _ => false,
+        };
+        if !hooked_only {
+            if let Some(stub) = gdb.as_deref_mut().filter(|stub| stub.is_connected()) {
+                stub.stopped(&fault);
+                return Ok(false);
+            }
+        }
+        match fault.kind {
+            FaultKind::Breakpoint => {
+                if !hooked_only {
+                    let chip8 = machine.borrow();
+                    println!("Breakpoint in frame {}", chip8.frame_count());
+                    println!("{}", debugger::format_state(&chip8));
+                }
+                machine.borrow_mut().breakpoints_mut().resume_from(fault.pc);
+            }
+            FaultKind::Watchpoint { .. } if hooked_only => {}
+            _ => return Err(fault),
+        }
+    }
+}
+
+fn screen_to_text(chip8: &Chip8) -> String {
+    chip8
+        .get_display()
+        .chunks(VIDEO_WIDTH as usize)
+        .map(|row| row.iter().map(|&lit| if lit { '#' } else { '.' }).collect())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn hooks_and_user_breakpoints_share_an_address() {
+        //V0 = 1, then V0 += 1 at 202 forever
+        let rom = Rom {
+            program: vec![0x60, 0x01, 0x70, 0x01, 0x12, 0x02],
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let mut settings = Settings::default();
+        settings.set("break", "202").unwrap();
+        let machine = Rc::new(RefCell::new(settings.create_machine(&rom).unwrap()));
+        let path = std::env::temp_dir().join(format!("chip8-headless-{}.rhai", std::process::id()));
+        fs::write(&path, "on_pc(0x202, || set_v(14, v(14) + 1));").unwrap();
+        let mut script = Script::load(path.to_str().unwrap(), machine.clone(), &settings).unwrap();
+        fs::remove_file(path).unwrap();
+        let mut scheduler = Scheduler::new(settings.instructions_per_second);
+        for _ in 0..3 {
+            script.start_frame();
+            assert_eq!(
+                run_frame(&machine, &mut scheduler, Some(&mut script), None),
+                Ok(true)
+            );
+            script.end_frame().unwrap();
+        }
+        //the hook ran once every time the instruction did, and the user's
+        //breakpoint is still there
+        let chip8 = machine.borrow();
+        assert_eq!(chip8.frame_count(), 3);
+        assert!(chip8.registers()[0] > 10);
+        assert_eq!(chip8.registers()[14], chip8.registers()[0] - 1);
+        assert!(chip8.breakpoints().contains(0x202));
+    }
+}
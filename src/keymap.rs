@@ -0,0 +1,49 @@
+use sdl2::keyboard::Keycode;
+
+//host keys for chip8 keys 0 to F, laid out so the 4x4 chip8 keypad sits on
+//the left of the keyboard:
+//  1 2 3 C      1 2 3 4
+//  4 5 6 D  ->  Q W E R
+//  7 8 9 E      A S D F
+//  A 0 B F      Z X C V
+const QWERTY: [&str; 16] = [
+    "X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V",
+];
+const QWERTZ: [&str; 16] = [
+    "X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Y", "C", "4", "R", "F", "V",
+];
+const DVORAK: [&str; 16] = [
+    "Q", "1", "2", "3", "'", ",", ".", "A", "O", "E", ";", "J", "4", "P", "U", "K",
+];
+
+pub struct Keymap {
+    keys: [Keycode; 16],
+}
+
+impl Keymap {
+    //a layout name, 16 characters for keys 0 to F ("x123qweasdzc4rfv") or 16
+    //comma separated SDL key names for keys that aren't a single character
+    pub fn parse(spec: &str) -> Result<Keymap, String> {
+        let names: Vec<String> = match spec {
+            "qwerty" => QWERTY.iter().map(|s| s.to_string()).collect(),
+            "qwertz" => QWERTZ.iter().map(|s| s.to_string()).collect(),
+            "dvorak" => DVORAK.iter().map(|s| s.to_string()).collect(),
+            _ if spec.contains(',') => spec.split(',').map(|s| s.trim().to_string()).collect(),
+            _ => spec.chars().map(|c| c.to_string()).collect(),
+        };
+        if names.len() != 16 {
+            return Err(format!(
+                "keymap '{}' must name 16 keys, or be qwerty, qwertz or dvorak",
+                spec
+            ));
+        }
+        let mut keys = [Keycode::X; 16];
+        for (key, name) in keys.iter_mut().zip(&names) {
+            *key = Keycode::from_name(name).ok_or(format!("unknown key '{}' in keymap", name))?;
+        }
+        Ok(Keymap { keys })
+    }
+    pub fn key_to_button(&self, key: Keycode) -> Option<usize> {
+        self.keys.iter().position(|k| *k == key)
+    }
+}
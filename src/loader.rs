@@ -0,0 +1,371 @@
+use super::assembler;
+use super::cartridge;
+use super::debug_map::DebugMap;
+use super::file_utils;
+use super::octo;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+//how often --watch looks at the rom file
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+//a program ready for load_program, plus any settings that came with it
+pub struct Rom {
+    pub program: Vec<u8>,
+    pub settings: Vec<(String, String)>,
+    //labels and source lines, from assembling .asm source or from the map
+    //`asm` saved next to the rom
+    pub symbols: Option<Arc<DebugMap>>,
+}
+
+//reads a rom from a file, or from stdin when the path is "-". the format is
+//picked by magic bytes or extension: zip archives holding one rom, Octo
+//cartridge gifs, hex text as pasted in forums, assembly (.asm) and Octo
+//(.8o) source, or a raw binary. source is built to be loaded at `origin`
+pub fn load(path: &str, origin: u16) -> Result<Rom, String> {
+    let data = if path == "-" {
+        let mut data = Vec::new();
+        io::stdin()
+            .read_to_end(&mut data)
+            .map_err(|e| format!("stdin: {}", e))?;
+        data
+    } else {
+        fs::read(path).map_err(|e| format!("{}: {}", path, e))?
+    };
+    let mut rom = load_data(path, data, origin).map_err(|e| format!("{}: {}", path, e))?;
+    if rom.symbols.is_none() && path != "-" {
+        rom.symbols = DebugMap::find(path, &rom.program).map(Arc::new);
+    }
+    Ok(rom)
+}
+
+fn load_data(name: &str, data: Vec<u8>, origin: u16) -> Result<Rom, String> {
+    let extension = Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if data.starts_with(b"PK\x03\x04") {
+        let (inner_name, inner_data) = unzip_single(&data)?;
+        return load_data(&inner_name, inner_data, origin);
+    }
+    if cartridge::is_cartridge(&data) {
+        let cartridge = cartridge::load(&data, origin)?;
+        return Ok(Rom {
+            program: cartridge.program,
+            settings: cartridge.settings,
+            symbols: None,
+        });
+    }
+    let mut symbols = None;
+    let program = if extension == "asm" || extension == "8o" {
+        let source = std::str::from_utf8(&data).map_err(|_| "source is not utf-8")?;
+        if extension == "asm" {
+            let (program, mut map) = assembler::assemble_with_map(source, origin)?;
+            map.set_file(Path::new(name));
+            symbols = Some(Arc::new(map));
+            program
+        } else {
+            octo::compile(source, origin)?
+        }
+    } else if extension == "hex" || extension == "txt" || looks_like_hex(&data) {
+        parse_hex(&data)?
+    } else {
+        data
+    };
+    Ok(Rom {
+        program,
+        settings: Vec::new(),
+        symbols,
+    })
+}
+
+fn looks_like_hex(data: &[u8]) -> bool {
+    data.iter().any(u8::is_ascii_hexdigit)
+        && data
+            .iter()
+            .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace() || b"xX,".contains(b))
+}
+
+//"00 e0 a2 2a", "0x00, 0xe0" and "00e0a22a" all work
+fn parse_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    let text = std::str::from_utf8(data).map_err(|_| "hex rom is not text")?;
+    let mut digits = String::new();
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let token = token
+            .strip_prefix("0x")
+            .or(token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if let Some(c) = token.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(format!("'{}' in hex rom is not a hex digit", c));
+        }
+        //a lone byte written as "e" means 0e
+        if token.len() == 1 {
+            digits.push('0');
+        }
+        digits.push_str(token);
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(String::from("hex rom has an odd number of digits"));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+//the one rom in a zip archive, ignoring directories and mac metadata
+fn unzip_single(data: &[u8]) -> Result<(String, Vec<u8>), String> {
+    let u16_at = |offset: usize| -> Result<usize, String> {
+        data.get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .ok_or(String::from("zip file is cut short"))
+    };
+    let u32_at = |offset: usize| -> Result<usize, String> {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or(String::from("zip file is cut short"))
+    };
+    //end of central directory record, it can be followed by a comment
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&offset| data[offset..].starts_with(b"PK\x05\x06"))
+        .ok_or("zip file has no central directory")?;
+    let entry_count = u16_at(end + 10)?;
+    let mut offset = u32_at(end + 16)?;
+    let mut roms = Vec::new();
+    for _ in 0..entry_count {
+        if !data
+            .get(offset..)
+            .is_some_and(|d| d.starts_with(b"PK\x01\x02"))
+        {
+            return Err(String::from("bad zip central directory"));
+        }
+        let method = u16_at(offset + 10)?;
+        let compressed_size = u32_at(offset + 20)?;
+        let size = u32_at(offset + 24)?;
+        let name_length = u16_at(offset + 28)?;
+        let extra_length = u16_at(offset + 30)?;
+        let comment_length = u16_at(offset + 32)?;
+        let local_header = u32_at(offset + 42)?;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .ok_or("zip file is cut short")?;
+        offset += 46 + name_length + extra_length + comment_length;
+
+        let file_name = name.rsplit('/').next().unwrap_or("");
+        if file_name.is_empty() || file_name.starts_with('.') || name.starts_with("__MACOSX/") {
+            continue;
+        }
+        let start = local_header + 30 + u16_at(local_header + 26)? + u16_at(local_header + 28)?;
+        let compressed = data
+            .get(start..start + compressed_size)
+            .ok_or("zip file is cut short")?;
+        let contents = match method {
+            0 => compressed.to_vec(),
+            8 => miniz_oxide::inflate::decompress_to_vec(compressed)
+                .map_err(|_| format!("{} in zip is corrupt", name))?,
+            _ => return Err(format!("{} in zip uses an unsupported compression", name)),
+        };
+        if contents.len() != size {
+            return Err(format!("{} in zip is corrupt", name));
+        }
+        roms.push((name, contents));
+    }
+    match roms.len() {
+        1 => Ok(roms.remove(0)),
+        0 => Err(String::from("zip file has no rom in it")),
+        n => Err(format!("zip file has {} files, expected one rom", n)),
+    }
+}
+
+//polls a rom file for --watch, hands back the new rom when its contents change
+pub struct RomWatcher {
+    path: String,
+    origin: u16,
+    modified: Option<SystemTime>,
+    sha1: String,
+    next_check: Instant,
+}
+
+impl RomWatcher {
+    pub fn new(path: &str, program: &[u8], origin: u16) -> Self {
+        Self {
+            path: path.to_string(),
+            origin,
+            modified: modified(path),
+            sha1: file_utils::sha1_hex(program),
+            next_check: Instant::now() + WATCH_INTERVAL,
+        }
+    }
+    pub fn poll(&mut self, now: Instant) -> Option<Result<Rom, String>> {
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + WATCH_INTERVAL;
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        //editors touch files without changing them, only report real changes
+        match load(&self.path, self.origin) {
+            Ok(rom) => {
+                let sha1 = file_utils::sha1_hex(&rom.program);
+                if sha1 == self.sha1 {
+                    return None;
+                }
+                self.sha1 = sha1;
+                Some(Ok(rom))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 4] = [0x00, 0xe0, 0xa2, 0x2a];
+
+    //an archive of (name, contents, compression method) entries, crcs left at 0
+    fn zip(entries: &[(&str, &[u8], u16)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, contents, method) in entries {
+            let stored = match method {
+                8 => miniz_oxide::deflate::compress_to_vec(contents, 6),
+                _ => contents.to_vec(),
+            };
+            let fields = |data: &mut Vec<u8>| {
+                data.extend_from_slice(&method.to_le_bytes());
+                data.extend_from_slice(&[0; 8]);
+                data.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+                data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+                data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                data.extend_from_slice(&[0; 2]);
+            };
+            directory.extend_from_slice(b"PK\x01\x02\x14\x00\x14\x00\x00\x00");
+            fields(&mut directory);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(b"PK\x03\x04\x14\x00\x00\x00");
+            fields(&mut data);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&stored);
+        }
+        let offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00");
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(b"\x07\x00comment");
+        data
+    }
+
+    fn load(name: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+        load_data(name, data.to_vec(), 0x200).map(|rom| rom.program)
+    }
+
+    #[test]
+    fn zips() {
+        let archive = zip(&[
+            ("roms/", b"", 0),
+            ("__MACOSX/roms/._game.ch8", b"resource fork", 0),
+            ("roms/.DS_Store", b"finder", 0),
+            ("roms/game.ch8", &ROM, 8),
+        ]);
+        assert_eq!(load("game.zip", &archive).unwrap(), ROM);
+        let stored = zip(&[("game.ch8", &ROM, 0)]);
+        assert_eq!(load("game.zip", &stored).unwrap(), ROM);
+        //the format of what's inside goes by its own name
+        let hex = zip(&[("game.hex", b"00 e0 a2 2a", 8)]);
+        assert_eq!(load("game.zip", &hex).unwrap(), ROM);
+        let source = zip(&[("game.8o", b": main jump main", 0)]);
+        assert_eq!(load("game.zip", &source).unwrap(), [0x12, 0x02, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn malformed_zips() {
+        let error = |data: &[u8]| load("game.zip", data).err().unwrap();
+        assert_eq!(
+            error(&zip(&[("a.ch8", &ROM, 0), ("b.ch8", &ROM, 8)])),
+            "zip file has 2 files, expected one rom"
+        );
+        assert_eq!(
+            error(&zip(&[("roms/", b"", 0)])),
+            "zip file has no rom in it"
+        );
+        assert_eq!(
+            error(&zip(&[("game.ch8", &ROM, 12)])),
+            "game.ch8 in zip uses an unsupported compression"
+        );
+        //stored bytes that claim to be deflated
+        let mut archive = zip(&[("game.ch8", &ROM, 0)]);
+        let directory = archive.len() - 22 - 7 - 46 - 8;
+        archive[8] = 8;
+        archive[directory + 10] = 8;
+        assert_eq!(error(&archive), "game.ch8 in zip is corrupt");
+        let archive = zip(&[("game.ch8", &ROM, 0)]);
+        assert_eq!(
+            error(&archive[..archive.len() / 2]),
+            "zip file has no central directory"
+        );
+        //the directory pointing past the end of the file
+        let mut archive = archive;
+        let end = archive.len() - 7 - 22;
+        archive[end + 16..end + 20].copy_from_slice(&0xffffu32.to_le_bytes());
+        assert_eq!(error(&archive), "bad zip central directory");
+    }
+
+    #[test]
+    fn hex_text() {
+        assert_eq!(load("game.hex", b"00 e0 a2 2a\n").unwrap(), ROM);
+        assert_eq!(load("game.txt", b"0x00, 0xE0,\r\n0xa2,0X2A").unwrap(), ROM);
+        //recognised without an extension, and single digits are whole bytes
+        assert_eq!(load("game", b"00e0a22a").unwrap(), ROM);
+        assert_eq!(load("game", b"e 1 ff").unwrap(), [0x0e, 0x01, 0xff]);
+        assert_eq!(
+            load("game.hex", b"00e0a").err().unwrap(),
+            "hex rom has an odd number of digits"
+        );
+        assert_eq!(
+            load("game.hex", b"00 e0 zz").err().unwrap(),
+            "'z' in hex rom is not a hex digit"
+        );
+        assert_eq!(
+            load("game.hex", &[0xff, 0x00]).err().unwrap(),
+            "hex rom is not text"
+        );
+        //binary that isn't all hex digits stays as it is
+        assert_eq!(load("game", &ROM).unwrap(), ROM);
+    }
+
+    #[test]
+    fn octo_source() {
+        assert_eq!(
+            load("game.8o", b": main\n  v0 := 1\n  jump main\n").unwrap(),
+            [0x12, 0x02, 0x60, 0x01, 0x12, 0x02]
+        );
+        assert_eq!(
+            load("game.8o", &[0xff]).err().unwrap(),
+            "source is not utf-8"
+        );
+        assert_eq!(
+            load("game.8o", b": main\n  jump nowhere").err().unwrap(),
+            "line 2: undefined label 'nowhere'"
+        );
+        assert!(load("game.8o", b"").is_err());
+    }
+}
@@ -1,152 +1,158 @@
+pub mod assembler;
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
 pub mod chip8;
+pub mod cli;
+pub mod config;
+pub mod dap;
+pub mod debug_map;
+pub mod debugger;
 pub mod disassembler;
 pub mod file_utils;
-use chip8::OpCode;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::video::{self, Window};
+pub mod frontend;
+pub mod gdbstub;
+pub mod gym;
+pub mod headless;
+pub mod json;
+pub mod keymap;
+pub mod loader;
+pub mod memory_viewer;
+pub mod movie;
+pub mod octo;
+pub mod profiler;
+pub mod recorder;
+pub mod renderer;
+pub mod romdb;
+pub mod scheduler;
+pub mod screenshot;
+pub mod script;
+pub mod settings;
+pub mod trace;
+pub mod tracediff;
+use cli::Command;
+use settings::Settings;
 use std::env;
-use std::time::{Duration, SystemTime};
-//const variables definition
-const SCALE: u32 = 10 as u32;
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-const WINDOW_WIDTH: u32 = (SCREEN_WIDTH as u32) * SCALE;
-const WINDOW_HEIGHT: u32 = (SCREEN_HEIGHT as u32) * SCALE;
-const FRAME_TIME: u128 = 1000 / 60;
-const TICKS_PER_FRAME: usize = 10;
+use std::fs;
+use std::path::Path;
+use std::process;
 
 fn main() {
-    println!("{}", std::env::current_dir().unwrap().to_str().unwrap());
-    let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        println!("Help: cargo run path/to/game");
-        return;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run_command(command) {
+        eprintln!("{}", e);
+        process::exit(1);
     }
-    println!("PATH: {}", args[1]);
-    //setup sdl
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    video_subsystem.gl_attr().set_double_buffer(true);
-    let window = video_subsystem
-        .window("Chip 8 Emu", WINDOW_WIDTH, WINDOW_HEIGHT)
-        .position_centered()
-        .build()
-        .unwrap();
-    let mut canvas = window.into_canvas().accelerated().build().unwrap();
-    canvas.clear();
-    canvas.present();
-
-    let mut event_pump = sdl_context.event_pump().unwrap();
-
-    //initialize emulator
-    let mut chip8 = chip8::chip8::new();
-    //load game rom
-    chip8.load_rom(&args[1]);
-    //run emulator
-    'running: loop {
-        let frame_start_time = SystemTime::now();
-        // let initial_cycle_time = SystemTime::now();
-        for evt in event_pump.poll_event() {
-            match evt {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => {
-                    break 'running;
-                }
-                Event::KeyDown {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(k) = key2btn(key) {
-                        chip8.keypress(k, true)
-                    }
-                }
-                Event::KeyUp {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(k) = key2btn(key) {
-                        chip8.keypress(k, false)
-                    }
-                }
-                _ => {}
+}
+fn run_command(command: Command) -> Result<(), String> {
+    match command {
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Version => println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        Command::Run {
+            rom: path,
+            overrides,
+        } => {
+            let rom = loader::load(&path, Settings::source_origin(&overrides)?)?;
+            let settings = Settings::for_rom(&rom, &overrides)?;
+            if settings.watch && path == "-" {
+                return Err(String::from("--watch needs a rom file, not stdin"));
+            }
+            if settings.headless || settings.script.is_some() {
+                headless::run(&rom, &settings)?;
+            } else {
+                //screenshots and movies are named after the rom
+                let name = if path == "-" { "stdin" } else { &path };
+                frontend::run(name, &rom, &settings)?;
+            }
+        }
+        Command::Test {
+            rom: path,
+            overrides,
+        } => {
+            let rom = loader::load(&path, Settings::source_origin(&overrides)?)?;
+            headless::test(&rom, &Settings::for_rom(&rom, &overrides)?)?
+        }
+        Command::Disasm {
+            rom,
+            output,
+            overrides,
+        } => {
+            let rom = loader::load(&rom, Settings::source_origin(&overrides)?)?;
+            let origin = Settings::for_rom(&rom, &overrides)?.load_address;
+            let listing =
+                disassembler::disassemble_program(&rom.program, origin, rom.symbols.as_deref());
+            match output {
+                Some(path) => fs::write(&path, listing).map_err(|e| format!("{}: {}", path, e))?,
+                None => print!("{}", listing),
             }
         }
-        // let final_cycle_time = SystemTime::now();
-        // let diff = initial_cycle_time.duration_since(final_cycle_time).unwrap();
-        // if diff.as_millis() < FRAME_TIME as u128 {
-        //     let difference: u64 = (FRAME_TIME - diff.as_millis()) as u64;
-        //     std::thread::sleep(Duration::from_millis(difference));
-        // }
-        for _ in 0..TICKS_PER_FRAME {
-            chip8.emulate_cycle();
+        Command::Asm {
+            source,
+            output,
+            overrides,
+        } => {
+            let text = fs::read_to_string(&source).map_err(|e| format!("{}: {}", source, e))?;
+            let origin = Settings::source_origin(&overrides)?;
+            let (program, mut map) = assembler::assemble_with_map(&text, origin)
+                .map_err(|e| format!("{}: {}", source, e))?;
+            map.set_file(Path::new(&source));
+            let path = output.unwrap_or_else(|| {
+                Path::new(&source)
+                    .with_extension("ch8")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            //the map goes first so --watch never sees the new rom without it
+            let map_path = Path::new(&path).with_extension("map");
+            map.save(&map_path, &program)?;
+            fs::write(&path, &program).map_err(|e| format!("{}: {}", path, e))?;
+            println!(
+                "Assembled {} bytes to {}, debug map in {}",
+                program.len(),
+                path,
+                map_path.display()
+            );
         }
-        chip8.tick_timers();
-        //draw to window
-        draw_screen(&chip8, &mut canvas);
-        let frame_end_time = SystemTime::now();
-        let dif = frame_end_time
-            .duration_since(frame_start_time)
-            .unwrap()
-            .as_millis();
-        if dif < FRAME_TIME {
-            // std::thread::sleep(Duration::from_millis((FRAME_TIME - dif) as u64));
-            sdl_context
-                .timer()
-                .unwrap()
-                .delay(FRAME_TIME as u32 - dif as u32);
+        Command::Gym {
+            rom: path,
+            overrides,
+        } => {
+            let rom = loader::load(&path, Settings::source_origin(&overrides)?)?;
+            gym::serve(&rom, &Settings::for_rom(&rom, &overrides)?)?
         }
-    }
-}
-// fn main() {
-//     let test = OpCode {
-//         higher_byte: 0xA2,
-//         lower_byte: 0xec,
-//     };
-//     println!("Test opcode struct: {:01x}", test.lower_byte >> 4);
-// }
-fn draw_screen(chip8: &chip8::chip8, canvas: &mut Canvas<Window>) {
-    //clear canvas
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-
-    let screen_buf = chip8.get_display();
-    //draw with white color
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
-    for (i, pixel) in screen_buf.iter().enumerate() {
-        if *pixel {
-            //convert i array wich is row to 2d
-            let x = (i % SCREEN_WIDTH) as u32;
-            let y = (i / SCREEN_WIDTH) as u32;
-            let rect = Rect::new((x * SCALE) as i32, (y * SCALE) as i32, SCALE, SCALE);
-            canvas.fill_rect(rect).unwrap();
+        Command::TraceDiff { a, b } => tracediff::diff(&a, &b)?,
+        Command::Dap { overrides } => dap::serve(&overrides)?,
+        Command::Info { rom } => {
+            let loaded = loader::load(&rom, Settings::source_origin(&[])?)?;
+            let settings = Settings::for_rom(&loaded, &[])?;
+            let program = loaded.program;
+            println!("{:<8} {}", "file", rom);
+            println!("{:<8} {} bytes", "size", program.len());
+            let rom_sha1 = file_utils::sha1_hex(&program);
+            println!("{:<8} {}", "sha1", rom_sha1);
+            if let Some(info) = romdb::lookup(&rom_sha1) {
+                for key in ["title", "author", "platform", "quirks", "ips"] {
+                    if let Some(value) = info.get(key) {
+                        println!("{:<8} {}", key, value);
+                    }
+                }
+            }
+            //memory from the load address to the end of the 4k address space
+            let space = chip8::MEMORY_SIZE - settings.load_address as usize;
+            if program.len() > space {
+                println!(
+                    "too big, at most {} bytes fit at {:#05x}",
+                    space, settings.load_address
+                );
+            }
         }
     }
-    canvas.present();
-}
-//
-fn key2btn(key: Keycode) -> Option<usize> {
-    match key {
-        Keycode::Num1 => Some(0x1),
-        Keycode::Num2 => Some(0x2),
-        Keycode::Num3 => Some(0x3),
-        Keycode::Num4 => Some(0xC),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::R => Some(0xD),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::F => Some(0xE),
-        Keycode::Z => Some(0xA),
-        Keycode::X => Some(0x0),
-        Keycode::C => Some(0xB),
-        Keycode::V => Some(0xF),
-        _ => None,
-    }
+    Ok(())
 }
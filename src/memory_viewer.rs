@@ -0,0 +1,287 @@
+use super::chip8::{Chip8, FONTSET, MEMORY_SIZE};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+
+//text is drawn with the chip-8 font, 4x5 glyphs, with a gap around each
+const GLYPH_WIDTH: i32 = 4;
+const GLYPH_HEIGHT: i32 = 5;
+const CELL_WIDTH: i32 = GLYPH_WIDTH + 1;
+const CELL_HEIGHT: i32 = GLYPH_HEIGHT + 2;
+//window pixels per font pixel
+const TEXT_SCALE: i32 = 2;
+//"0200  00 e0 ..." is 4 + 2 + 16 * 3 - 1 characters
+const COLUMNS: i32 = 53;
+//registers, timers, keys and stack above the dump
+const HEADER_ROWS: i32 = 6;
+const DUMP_ROWS: u16 = 16;
+const BYTES_PER_ROW: u16 = 16;
+
+const BACKGROUND: Color = Color::RGB(16, 16, 24);
+const TEXT: Color = Color::RGB(170, 170, 170);
+const DIM: Color = Color::RGB(70, 70, 80);
+const PC_COLOR: Color = Color::RGB(255, 210, 60);
+const INDEX_COLOR: Color = Color::RGB(80, 200, 255);
+const STACK_COLOR: Color = Color::RGB(230, 110, 230);
+const CURSOR: Color = Color::RGB(90, 90, 140);
+
+//letters the labels need that the hex digit font doesn't have, same format
+//as FONTSET: one byte per row, high nybble is the pixels
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        c @ ('0'..='9' | 'A'..='F') => {
+            let digit = c.to_digit(16).unwrap_or(0) as usize;
+            let mut glyph = [0; 5];
+            glyph.copy_from_slice(&FONTSET[digit * 5..digit * 5 + 5]);
+            glyph
+        }
+        'I' => [0xe0, 0x40, 0x40, 0x40, 0xe0],
+        'K' => [0x90, 0xa0, 0xc0, 0xa0, 0x90],
+        'P' => [0xf0, 0x90, 0xf0, 0x80, 0x80],
+        'S' => [0x70, 0x80, 0x60, 0x10, 0xe0],
+        'T' => [0xf0, 0x40, 0x40, 0x40, 0x40],
+        'V' => [0x90, 0x90, 0x90, 0x60, 0x60],
+        ':' => [0x00, 0x40, 0x00, 0x40, 0x00],
+        '-' => [0x00, 0x00, 0xf0, 0x00, 0x00],
+        _ => [0; 5],
+    }
+}
+
+//what typed hex digits change
+#[derive(Clone, Copy, PartialEq)]
+enum Cursor {
+    Memory(u16),
+    //0-F for V0-VF, 16 for I
+    Register(usize),
+}
+
+//a second window with a hex dump of memory and the machine registers. while
+//the emulator is paused hex digits edit the byte or register under the
+//cursor, shifting in from the right; tab switches between memory and
+//registers, arrows and page up/down move, home goes back to following pc
+pub struct MemoryViewer {
+    canvas: Canvas<Window>,
+    //first address shown in the dump
+    top: u16,
+    follow_pc: bool,
+    cursor: Cursor,
+    //colored runs of text queued for the next present
+    text: Vec<(i32, i32, String, Color)>,
+}
+
+impl MemoryViewer {
+    pub fn open(video: &VideoSubsystem, position: (i32, i32)) -> Result<Self, String> {
+        let width = COLUMNS * CELL_WIDTH * TEXT_SCALE + 2 * CELL_WIDTH * TEXT_SCALE;
+        let height = (HEADER_ROWS + DUMP_ROWS as i32 + 2) * CELL_HEIGHT * TEXT_SCALE;
+        let window = video
+            .window("Memory", width as u32, height as u32)
+            .position(position.0, position.1)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(Self {
+            canvas,
+            top: 0,
+            follow_pc: true,
+            cursor: Cursor::Memory(0),
+            text: Vec::new(),
+        })
+    }
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn handle_key(&mut self, key: Keycode, chip8: &mut Chip8, paused: bool) {
+        let last = MEMORY_SIZE as u16 - 1;
+        match (key, self.cursor) {
+            (Keycode::Tab, Cursor::Memory(_)) => self.cursor = Cursor::Register(0),
+            (Keycode::Tab, Cursor::Register(_)) => {
+                self.cursor = Cursor::Memory(self.top);
+            }
+            (Keycode::Home, _) => self.follow_pc = true,
+            (Keycode::PageUp, _) => self.scroll(-((DUMP_ROWS * BYTES_PER_ROW) as i32)),
+            (Keycode::PageDown, _) => self.scroll((DUMP_ROWS * BYTES_PER_ROW) as i32),
+            (Keycode::Left, Cursor::Memory(a)) => self.move_cursor(a.saturating_sub(1)),
+            (Keycode::Right, Cursor::Memory(a)) => self.move_cursor((a + 1).min(last)),
+            (Keycode::Up, Cursor::Memory(a)) => self.move_cursor(a.saturating_sub(BYTES_PER_ROW)),
+            (Keycode::Down, Cursor::Memory(a)) => self.move_cursor((a + BYTES_PER_ROW).min(last)),
+            (Keycode::Left | Keycode::Up, Cursor::Register(r)) => {
+                self.cursor = Cursor::Register(r.saturating_sub(1))
+            }
+            (Keycode::Right | Keycode::Down, Cursor::Register(r)) => {
+                self.cursor = Cursor::Register((r + 1).min(16))
+            }
+            _ => {
+                let Some(digit) = hex_digit(key) else {
+                    return;
+                };
+                if !paused {
+                    println!("Pause with F6 to edit memory or registers");
+                    return;
+                }
+                match self.cursor {
+                    Cursor::Memory(address) => {
+                        let value = chip8.memory()[address as usize] << 4 | digit;
+                        chip8.poke(address, value);
+                    }
+                    Cursor::Register(16) => {
+                        chip8.set_index((chip8.index() << 4 | digit as u16) & 0xfff)
+                    }
+                    Cursor::Register(r) => chip8.set_register(r, chip8.registers()[r] << 4 | digit),
+                }
+            }
+        }
+    }
+    fn move_cursor(&mut self, address: u16) {
+        self.cursor = Cursor::Memory(address);
+        self.follow_pc = false;
+        let shown = DUMP_ROWS * BYTES_PER_ROW;
+        if address < self.top {
+            self.top = address & !(BYTES_PER_ROW - 1);
+        } else if address >= self.top + shown {
+            self.top = (address & !(BYTES_PER_ROW - 1)) + BYTES_PER_ROW - shown;
+        }
+    }
+    fn scroll(&mut self, amount: i32) {
+        let last_top = (MEMORY_SIZE as u16 - DUMP_ROWS * BYTES_PER_ROW) as i32;
+        self.top = (self.top as i32 + amount).clamp(0, last_top) as u16;
+        self.follow_pc = false;
+    }
+
+    pub fn draw(&mut self, chip8: &Chip8) -> Result<(), String> {
+        if self.follow_pc {
+            let shown = DUMP_ROWS * BYTES_PER_ROW;
+            let row = chip8.pc() & !(BYTES_PER_ROW - 1);
+            self.top = row
+                .saturating_sub(4 * BYTES_PER_ROW)
+                .min(MEMORY_SIZE as u16 - shown);
+        }
+        let registers = chip8.registers();
+        for half in 0..2 {
+            for i in 0..8 {
+                let r = half * 8 + i;
+                self.print(
+                    i as i32 * 7,
+                    half as i32,
+                    &format!("V{:X} {:02X}", r, registers[r]),
+                    TEXT,
+                );
+            }
+        }
+        let pc = chip8.pc();
+        let index = chip8.index();
+        self.print(0, 2, &format!("PC {:04X}", pc), PC_COLOR);
+        self.print(9, 2, &format!("I {:04X}", index), INDEX_COLOR);
+        self.print(
+            17,
+            2,
+            &format!(
+                "DT {:02X}  ST {:02X}  SP {:X}",
+                chip8.delay_timer(),
+                chip8.sound_timer(),
+                chip8.stack().len()
+            ),
+            TEXT,
+        );
+        self.print(0, 3, "K", TEXT);
+        for (key, &pressed) in chip8.keypad().iter().enumerate() {
+            let color = if pressed { PC_COLOR } else { DIM };
+            self.print(2 + key as i32 * 2, 3, &format!("{:X}", key), color);
+        }
+        let stack: Vec<String> = chip8.stack().iter().map(|a| format!("{:04X}", a)).collect();
+        self.print(0, 4, &format!("STACK {}", stack.join(" ")), STACK_COLOR);
+
+        let memory = chip8.memory();
+        for row in 0..DUMP_ROWS {
+            let start = self.top + row * BYTES_PER_ROW;
+            let y = HEADER_ROWS + row as i32;
+            self.print(0, y, &format!("{:04X}", start), DIM);
+            for column in 0..BYTES_PER_ROW {
+                let address = start + column;
+                let color = if address == pc || address == pc.wrapping_add(1) {
+                    PC_COLOR
+                } else if address == index {
+                    INDEX_COLOR
+                } else if chip8.stack().contains(&address) {
+                    STACK_COLOR
+                } else {
+                    TEXT
+                };
+                let x = 6 + column as i32 * 3;
+                self.print(x, y, &format!("{:02X}", memory[address as usize]), color);
+            }
+        }
+        self.present()
+    }
+    fn print(&mut self, column: i32, row: i32, text: &str, color: Color) {
+        self.text.push((column, row, text.to_string(), color));
+    }
+    fn present(&mut self) -> Result<(), String> {
+        self.canvas.set_draw_color(BACKGROUND);
+        self.canvas.clear();
+        let pixel = TEXT_SCALE;
+        let cell_x = |column: i32| (column + 1) * CELL_WIDTH * pixel;
+        let cell_y = |row: i32| (row + 1) * CELL_HEIGHT * pixel;
+        //the cursor is a block behind the text
+        let (column, row, width) = match self.cursor {
+            Cursor::Register(16) => (9, 2, 6),
+            Cursor::Register(r) => ((r % 8) as i32 * 7, (r / 8) as i32, 5),
+            Cursor::Memory(address)
+                if (self.top..self.top + DUMP_ROWS * BYTES_PER_ROW).contains(&address) =>
+            {
+                let offset = address - self.top;
+                (
+                    6 + (offset % BYTES_PER_ROW) as i32 * 3,
+                    HEADER_ROWS + (offset / BYTES_PER_ROW) as i32,
+                    2,
+                )
+            }
+            Cursor::Memory(_) => (0, 0, 0),
+        };
+        if width > 0 {
+            self.canvas.set_draw_color(CURSOR);
+            self.canvas.fill_rect(Rect::new(
+                cell_x(column) - pixel,
+                cell_y(row) - pixel,
+                (width * CELL_WIDTH * pixel + pixel) as u32,
+                ((GLYPH_HEIGHT + 2) * pixel) as u32,
+            ))?;
+        }
+        for (column, row, text, color) in self.text.drain(..) {
+            let mut rects = Vec::new();
+            for (i, c) in text.chars().enumerate() {
+                let x = cell_x(column + i as i32);
+                let y = cell_y(row);
+                for (line, bits) in glyph(c).iter().enumerate() {
+                    for bit in 0..GLYPH_WIDTH {
+                        if bits & (0x80 >> bit) != 0 {
+                            rects.push(Rect::new(
+                                x + bit * pixel,
+                                y + line as i32 * pixel,
+                                pixel as u32,
+                                pixel as u32,
+                            ));
+                        }
+                    }
+                }
+            }
+            self.canvas.set_draw_color(color);
+            self.canvas.fill_rects(&rects)?;
+        }
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+fn hex_digit(key: Keycode) -> Option<u8> {
+    let name = key.name();
+    let name = name.strip_prefix("Keypad ").unwrap_or(&name);
+    if name.len() == 1 {
+        name.chars().next()?.to_digit(16).map(|d| d as u8)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,345 @@
+use super::bus::Protect;
+use super::chip8::{self, Chip8, MemoryPolicy, QuirkProfile, StackDepth, Timing};
+use super::settings::Settings;
+use std::fs;
+use std::io;
+
+//movie files are plain text: a header with everything needed to rebuild the
+//machine, then one line per key transition
+//
+//  CHIP8MOVIE 1
+//  rom_sha1 0123456789abcdef0123456789abcdef01234567
+//  quirks modern
+//  stack 16
+//  memory wrap
+//  timing fixed
+//  seed 42
+//  ips 600
+//  origin 200
+//  entry 200
+//  protect font,rom
+//  length 1800
+//  events
+//  12 5 down
+//  20 5 up
+const MAGIC: &str = "CHIP8MOVIE 1";
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MovieHeader {
+    pub rom_sha1: String,
+    pub quirks: QuirkProfile,
+    pub stack_depth: StackDepth,
+    pub memory_policy: MemoryPolicy,
+    pub timing: Timing,
+    pub seed: u64,
+    pub instructions_per_second: u32,
+    pub load_address: u16,
+    pub entry_point: u16,
+    //--protect changes which writes fault
+    pub protect: Vec<Protect>,
+}
+
+impl MovieHeader {
+    //a replay only matches when the machine is set up like the recording
+    pub fn apply(&self, settings: &mut Settings) {
+        settings.quirks = self.quirks;
+        settings.stack_depth = Some(self.stack_depth);
+        settings.memory_policy = self.memory_policy;
+        settings.timing = self.timing;
+        settings.seed = Some(self.seed);
+        settings.load_address = self.load_address;
+        settings.entry_point = Some(self.entry_point);
+        settings.instructions_per_second = self.instructions_per_second;
+        settings.protect = self.protect.clone();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyEvent {
+    //frame the transition is applied before
+    pub frame: u64,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Movie {
+    pub header: MovieHeader,
+    pub events: Vec<KeyEvent>,
+    //frames covered by the movie, playback ends here
+    pub length: u64,
+}
+
+impl Movie {
+    pub fn new(header: MovieHeader) -> Self {
+        Self {
+            header,
+            events: Vec::new(),
+            length: 0,
+        }
+    }
+    pub fn record(&mut self, frame: u64, key: usize, pressed: bool) {
+        self.events.push(KeyEvent {
+            frame,
+            key,
+            pressed,
+        });
+    }
+    pub fn finish(&mut self, frame: u64) {
+        self.length = frame;
+    }
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        text.push_str(MAGIC);
+        text.push('\n');
+        text.push_str(&format!("rom_sha1 {}\n", self.header.rom_sha1));
+        text.push_str(&format!("quirks {}\n", self.header.quirks));
+        text.push_str(&format!("stack {}\n", self.header.stack_depth));
+        text.push_str(&format!("memory {}\n", self.header.memory_policy));
+        text.push_str(&format!("timing {}\n", self.header.timing));
+        text.push_str(&format!("seed {}\n", self.header.seed));
+        text.push_str(&format!("ips {}\n", self.header.instructions_per_second));
+        text.push_str(&format!("origin {:x}\n", self.header.load_address));
+        text.push_str(&format!("entry {:x}\n", self.header.entry_point));
+        let protect: Vec<String> = self.header.protect.iter().map(Protect::to_string).collect();
+        if !protect.is_empty() {
+            text.push_str(&format!("protect {}\n", protect.join(",")));
+        }
+        text.push_str(&format!("length {}\n", self.length));
+        text.push_str("events\n");
+        for event in &self.events {
+            let state = if event.pressed { "down" } else { "up" };
+            text.push_str(&format!("{} {:x} {}\n", event.frame, event.key, state));
+        }
+        text
+    }
+    pub fn load(path: &str) -> io::Result<Movie> {
+        let text = fs::read_to_string(path)?;
+        Movie::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err(String::from("not a chip8 movie file"));
+        }
+        let mut rom_sha1 = None;
+        let mut quirks: Option<QuirkProfile> = None;
+        let mut stack_depth = None;
+        let mut memory_policy = MemoryPolicy::Wrap;
+        let mut timing = None;
+        let mut seed = None;
+        let mut ips = None;
+        let mut length = None;
+        //files from before these were configurable always used 0x200
+        let mut origin = chip8::DEFAULT_LOAD_ADDRESS;
+        let mut entry = None;
+        let mut protect = Vec::new();
+        for line in lines.by_ref() {
+            if line == "events" {
+                break;
+            }
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or(format!("bad header line: {}", line))?;
+            match key {
+                "rom_sha1" => rom_sha1 = Some(value.to_string()),
+                "quirks" => quirks = Some(value.parse()?),
+                "stack" => stack_depth = Some(value.parse()?),
+                "memory" => memory_policy = value.parse()?,
+                "timing" => timing = Some(value.parse()?),
+                "seed" => seed = Some(value.parse().map_err(|_| "bad seed")?),
+                "ips" => ips = Some(value.parse().map_err(|_| "bad ips")?),
+                "length" => length = Some(value.parse().map_err(|_| "bad length")?),
+                "origin" => origin = u16::from_str_radix(value, 16).map_err(|_| "bad origin")?,
+                "entry" => entry = Some(u16::from_str_radix(value, 16).map_err(|_| "bad entry")?),
+                "protect" => {
+                    protect = value.split(',').map(str::parse).collect::<Result<_, _>>()?
+                }
+                //unknown keys are ignored so newer files still load
+                _ => {}
+            }
+        }
+        let quirks = quirks.ok_or("missing quirks")?;
+        let header = MovieHeader {
+            rom_sha1: rom_sha1.ok_or("missing rom_sha1")?,
+            quirks,
+            //older files used the profile's depth
+            stack_depth: stack_depth.unwrap_or(quirks.quirks().stack_depth),
+            memory_policy,
+            timing: timing.ok_or("missing timing")?,
+            seed: seed.ok_or("missing seed")?,
+            instructions_per_second: ips.ok_or("missing ips")?,
+            load_address: origin,
+            entry_point: entry.unwrap_or(origin),
+            protect,
+        };
+        let mut events = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("bad event line: {}", line));
+            }
+            let frame = fields[0].parse().map_err(|_| "bad event frame")?;
+            let key = usize::from_str_radix(fields[1], 16).map_err(|_| "bad event key")?;
+            if key > 0xF {
+                return Err(format!("bad event key: {}", fields[1]));
+            }
+            let pressed = match fields[2] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(format!("bad event state: {}", fields[2])),
+            };
+            events.push(KeyEvent {
+                frame,
+                key,
+                pressed,
+            });
+        }
+        Ok(Movie {
+            header,
+            events,
+            length: length.ok_or("missing length")?,
+        })
+    }
+}
+
+//feeds a movie's input back into the machine frame by frame
+pub struct Player {
+    movie: Movie,
+    next_event: usize,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            next_event: 0,
+        }
+    }
+    pub fn header(&self) -> &MovieHeader {
+        &self.movie.header
+    }
+    //call before running each frame
+    pub fn apply(&mut self, chip8: &mut Chip8) {
+        let frame = chip8.frame_count();
+        while let Some(event) = self.movie.events.get(self.next_event) {
+            if event.frame > frame {
+                break;
+            }
+            chip8.keypress(event.key, event.pressed);
+            self.next_event += 1;
+        }
+    }
+    pub fn finished(&self, chip8: &Chip8) -> bool {
+        chip8.frame_count() >= self.movie.length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_utils;
+    use crate::loader::Rom;
+    use crate::scheduler::Scheduler;
+
+    #[test]
+    fn text_round_trip() {
+        let mut movie = Movie::new(MovieHeader {
+            rom_sha1: String::from("b232ef880bd6060fb45fa6effed7edf0ae95670e"),
+            quirks: QuirkProfile::Schip,
+            stack_depth: StackDepth::Unlimited,
+            memory_policy: MemoryPolicy::Fault,
+            timing: Timing::Vip,
+            seed: 42,
+            instructions_per_second: 1000,
+            load_address: 0x600,
+            entry_point: 0x602,
+            protect: vec![Protect::Font, Protect::Rom],
+        });
+        movie.record(0, 0xf, true);
+        movie.record(12, 0xf, false);
+        movie.record(12, 3, true);
+        movie.finish(90);
+        assert_eq!(Movie::parse(&movie.to_text()).unwrap(), movie);
+        assert!(Movie::parse(&movie.to_text().replace("font,rom", "ram")).is_err());
+        //older files without the line protected nothing
+        movie.header.protect.clear();
+        assert!(!movie.to_text().contains("protect"));
+        assert_eq!(Movie::parse(&movie.to_text()).unwrap(), movie);
+    }
+
+    #[test]
+    fn replays_match_the_recording() {
+        let rom = Rom {
+            program: vec![
+                0xc0, 0xff, //200 V0 = random
+                0xf1, 0x0a, //202 V1 = next key
+                0x80, 0x14, //204 V0 += V1
+                0xa3, 0x00, //206 I = 300
+                0xf0, 0x33, //208 bcd of V0
+                0xf2, 0x65, //20a load V0..V2 from it
+                0xd0, 0x15, //20c draw
+                0x73, 0x01, //20e V3 += 1
+                0x12, 0x00, //210 again
+            ],
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let mut settings = Settings::default();
+        settings.set("seed", "1234").unwrap();
+        settings.set("protect", "font").unwrap();
+        let mut recorded = settings.create_machine(&rom).unwrap();
+        let mut scheduler = Scheduler::new(settings.instructions_per_second);
+        let mut movie = Movie::new(MovieHeader {
+            rom_sha1: file_utils::sha1_hex(&rom.program),
+            quirks: recorded.quirks().profile,
+            stack_depth: recorded.quirks().stack_depth,
+            memory_policy: recorded.memory_policy(),
+            timing: recorded.timing(),
+            seed: recorded.seed(),
+            instructions_per_second: settings.instructions_per_second,
+            load_address: recorded.load_address(),
+            entry_point: recorded.entry_point(),
+            protect: settings.protect.clone(),
+        });
+        let presses = [(10, 5, true), (12, 5, false), (30, 9, true), (31, 9, false)];
+        for frame in 0..60 {
+            for &(at, key, pressed) in presses.iter().filter(|(at, _, _)| *at == frame) {
+                recorded.keypress(key, pressed);
+                movie.record(at, key, pressed);
+            }
+            recorded
+                .run_frame(scheduler.frame_budget(recorded.timing()))
+                .unwrap();
+        }
+        movie.finish(60);
+
+        //replayed the way headless does it, from the saved text
+        let movie = Movie::parse(&movie.to_text()).unwrap();
+        let mut settings = Settings::default();
+        movie.header.apply(&mut settings);
+        assert_eq!(settings.protect, [Protect::Font]);
+        let mut replayed = settings.create_machine(&rom).unwrap();
+        let mut scheduler = Scheduler::new(settings.instructions_per_second);
+        let mut player = Player::new(movie);
+        while !player.finished(&replayed) {
+            player.apply(&mut replayed);
+            replayed
+                .run_frame(scheduler.frame_budget(replayed.timing()))
+                .unwrap();
+        }
+        assert_eq!(replayed.frame_count(), recorded.frame_count());
+        assert_eq!(replayed.pc(), recorded.pc());
+        assert_eq!(replayed.index(), recorded.index());
+        assert_eq!(replayed.registers(), recorded.registers());
+        assert_eq!(replayed.stack(), recorded.stack());
+        assert_eq!(replayed.delay_timer(), recorded.delay_timer());
+        assert_eq!(replayed.memory(), recorded.memory());
+        assert_eq!(replayed.get_display(), recorded.get_display());
+        //FX0A got past both presses, once per loop while they were held
+        assert!(recorded.registers()[3] >= 2);
+    }
+}
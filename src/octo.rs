@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+
+//compiler for the core of the Octo language, enough for the programs found in
+//Octo cartridges that stick to plain chip-8. macros, :calc and the schip and
+//xo-chip extensions are reported as errors.
+
+const MEMORY_END: usize = 0x1000;
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+struct Compiler<'a> {
+    //where the program is loaded
+    origin: u16,
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    rom: Vec<u8>,
+    labels: HashMap<&'a str, u16>,
+    constants: HashMap<&'a str, u16>,
+    aliases: HashMap<&'a str, u16>,
+    //rom offset of a word whose address is a label that wasn't defined yet,
+    //with the label and the line it was used on
+    fixups: Vec<(usize, &'a str, usize)>,
+    //open begin/else blocks, rom offset of their pending jump
+    blocks: Vec<usize>,
+    //open loops, start address and pending while jumps
+    loops: Vec<(u16, Vec<usize>)>,
+}
+
+pub fn compile(source: &str, origin: u16) -> Result<Vec<u8>, String> {
+    let tokens = source
+        .lines()
+        .enumerate()
+        .flat_map(|(number, line)| {
+            let code = line.split('#').next().unwrap_or("");
+            code.split_whitespace().map(move |text| Token {
+                text,
+                line: number + 1,
+            })
+        })
+        .collect();
+    let mut compiler = Compiler {
+        origin,
+        tokens,
+        position: 0,
+        //room for the jump to main
+        rom: vec![0, 0],
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        loops: Vec::new(),
+    };
+    compiler.fixups.push((0, "main", 0));
+    compiler.rom[0] = 0x10;
+    while compiler.position < compiler.tokens.len() {
+        let line = compiler.tokens[compiler.position].line;
+        compiler
+            .statement()
+            .map_err(|e| format!("line {}: {}", line, e))?;
+    }
+    compiler.finish()
+}
+
+impl<'a> Compiler<'a> {
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if !self.blocks.is_empty() {
+            return Err(String::from("begin without end"));
+        }
+        if !self.loops.is_empty() {
+            return Err(String::from("loop without again"));
+        }
+        if !self.labels.contains_key("main") {
+            return Err(String::from("program has no main label"));
+        }
+        for (offset, name, line) in std::mem::take(&mut self.fixups) {
+            let address = *self
+                .labels
+                .get(name)
+                .ok_or(format!("line {}: undefined label '{}'", line, name))?;
+            self.patch(offset, address);
+        }
+        if self.origin as usize + self.rom.len() > MEMORY_END {
+            return Err(String::from("program does not fit in memory"));
+        }
+        Ok(self.rom)
+    }
+    fn here(&self) -> u16 {
+        self.origin + self.rom.len() as u16
+    }
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .ok_or("unexpected end of program")?;
+        self.position += 1;
+        Ok(token.text)
+    }
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.text)
+    }
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(format!("expected '{}', got '{}'", expected, token))
+        }
+    }
+    fn line(&self) -> usize {
+        self.tokens[self.position.saturating_sub(1)].line
+    }
+    fn emit(&mut self, word: u16) {
+        self.rom.extend_from_slice(&word.to_be_bytes());
+    }
+    //word with a 12 bit address, patched later if the label isn't known yet
+    fn emit_address(&mut self, opcode: u16, target: &'a str) -> Result<(), String> {
+        if let Some(address) = self.labels.get(target).or(self.constants.get(target)) {
+            self.emit(opcode | (address & 0xfff));
+        } else if let Some(address) = parse_number(target) {
+            if address > 0xfff {
+                return Err(format!("address '{}' is out of range", target));
+            }
+            self.emit(opcode | address);
+        } else if is_name(target) {
+            let line = self.line();
+            self.fixups.push((self.rom.len(), target, line));
+            self.emit(opcode);
+        } else {
+            return Err(format!("bad address '{}'", target));
+        }
+        Ok(())
+    }
+    fn register(&self, token: &str) -> Option<u16> {
+        if let Some(register) = self.aliases.get(token) {
+            return Some(*register);
+        }
+        match token.strip_prefix(['v', 'V']) {
+            Some(digit) if digit.len() == 1 => u16::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+    fn expect_register(&mut self) -> Result<u16, String> {
+        let token = self.next()?;
+        self.register(token)
+            .ok_or(format!("expected a register, got '{}'", token))
+    }
+    fn value(&mut self, max: u16) -> Result<u16, String> {
+        let token = self.next()?;
+        let value = match self.constants.get(token) {
+            Some(value) => Some(*value),
+            None => parse_number(token),
+        }
+        .ok_or(format!("expected a number, got '{}'", token))?;
+        if value > max {
+            return Err(format!("'{}' is out of range", token));
+        }
+        Ok(value)
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token {
+            ":" => {
+                let name = self.next()?;
+                if !is_name(name) || self.labels.insert(name, self.here()).is_some() {
+                    return Err(format!("bad or duplicate label '{}'", name));
+                }
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value(0xffff)?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name, register);
+            }
+            ":org" => {
+                let address = self.value(0xfff)? as usize;
+                if address < self.origin as usize + self.rom.len() {
+                    return Err(String::from(":org can only move forward"));
+                }
+                self.rom.resize(address - self.origin as usize, 0);
+            }
+            ":byte" => {
+                let value = self.value(0xff)?;
+                self.rom.push(value as u8);
+            }
+            ":call" => {
+                let target = self.next()?;
+                self.emit_address(0x2000, target)?;
+            }
+            //debugger hints, nothing to emit
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "clear" => self.emit(0x00e0),
+            "return" | ";" => self.emit(0x00ee),
+            "jump" => {
+                let target = self.next()?;
+                self.emit_address(0x1000, target)?;
+            }
+            "jump0" => {
+                let target = self.next()?;
+                self.emit_address(0xb000, target)?;
+            }
+            "native" => {
+                let target = self.next()?;
+                self.emit_address(0x0000, target)?;
+            }
+            "bcd" => {
+                let x = self.expect_register()?;
+                self.emit(0xf033 | x << 8);
+            }
+            "save" => {
+                let x = self.expect_register()?;
+                self.emit(0xf055 | x << 8);
+            }
+            "load" => {
+                let x = self.expect_register()?;
+                self.emit(0xf065 | x << 8);
+            }
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let n = self.value(0xf)?;
+                self.emit(0xd000 | x << 8 | y << 4 | n);
+            }
+            "delay" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                self.emit(0xf015 | x << 8);
+            }
+            "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                self.emit(0xf018 | x << 8);
+            }
+            "i" => self.index_statement()?,
+            "if" => self.if_statement()?,
+            "else" => {
+                let pending = self.blocks.pop().ok_or("else without begin")?;
+                //the end of the if part jumps over the else part
+                self.blocks.push(self.rom.len());
+                self.emit(0x1000);
+                self.patch(pending, self.here());
+            }
+            "end" => {
+                let pending = self.blocks.pop().ok_or("end without begin")?;
+                self.patch(pending, self.here());
+            }
+            "loop" => self.loops.push((self.here(), Vec::new())),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(String::from("while outside a loop"));
+                }
+                //skip the exit jump while the condition holds
+                let skip = self.condition()?;
+                self.emit(skip);
+                let pending = self.rom.len();
+                self.emit(0x1000);
+                if let Some((_, exits)) = self.loops.last_mut() {
+                    exits.push(pending);
+                }
+            }
+            "again" => {
+                let (start, exits) = self.loops.pop().ok_or("again without loop")?;
+                self.emit(0x1000 | start);
+                for pending in exits {
+                    self.patch(pending, self.here());
+                }
+            }
+            _ if self.register(token).is_some() => self.register_statement(token)?,
+            _ if parse_number(token).is_some() || self.constants.contains_key(token) => {
+                self.position -= 1;
+                let value = self.value(0xff)?;
+                self.rom.push(value as u8);
+            }
+            _ if token.starts_with(':') => {
+                return Err(format!("'{}' is not supported", token));
+            }
+            //a bare label is a subroutine call
+            _ if is_name(token) => self.emit_address(0x2000, token)?,
+            _ => return Err(format!("unexpected '{}'", token)),
+        }
+        Ok(())
+    }
+    fn patch(&mut self, offset: usize, address: u16) {
+        self.rom[offset] |= (address >> 8) as u8 & 0xf;
+        self.rom[offset + 1] |= address as u8;
+    }
+    fn index_statement(&mut self) -> Result<(), String> {
+        match self.next()? {
+            ":=" => {
+                if self.peek() == Some("hex") {
+                    self.next()?;
+                    let x = self.expect_register()?;
+                    self.emit(0xf029 | x << 8);
+                } else {
+                    let target = self.next()?;
+                    self.emit_address(0xa000, target)?;
+                }
+            }
+            "+=" => {
+                let x = self.expect_register()?;
+                self.emit(0xf01e | x << 8);
+            }
+            other => return Err(format!("unexpected '{}' after i", other)),
+        }
+        Ok(())
+    }
+    fn register_statement(&mut self, target: &str) -> Result<(), String> {
+        let x = self.register(target).unwrap_or(0) << 8;
+        let operator = self.next()?;
+        let operand = self.peek().ok_or("unexpected end of program")?;
+        if let Some(y) = self.register(operand) {
+            self.next()?;
+            let y = y << 4;
+            let opcode = match operator {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800e,
+                _ => return Err(format!("unexpected '{}'", operator)),
+            };
+            self.emit(opcode | x | y);
+            return Ok(());
+        }
+        match (operator, operand) {
+            (":=", "random") => {
+                self.next()?;
+                let mask = self.value(0xff)?;
+                self.emit(0xc000 | x | mask);
+            }
+            (":=", "delay") => {
+                self.next()?;
+                self.emit(0xf007 | x);
+            }
+            (":=", "key") => {
+                self.next()?;
+                self.emit(0xf00a | x);
+            }
+            (":=", _) => {
+                let value = self.value(0xff)?;
+                self.emit(0x6000 | x | value);
+            }
+            ("+=", _) => {
+                let value = self.value(0xff)?;
+                self.emit(0x7000 | x | value);
+            }
+            ("-=", _) => {
+                let value = self.value(0xff)?;
+                self.emit(0x7000 | x | (0x100 - value) & 0xff);
+            }
+            _ => return Err(format!("unexpected '{} {}'", operator, operand)),
+        }
+        Ok(())
+    }
+    fn if_statement(&mut self) -> Result<(), String> {
+        let skip_if = self.condition()?;
+        match self.next()? {
+            //skip the statement when the condition is false
+            "then" => self.emit(negate(skip_if)),
+            "begin" => {
+                //skip the jump past the block when the condition is true
+                self.emit(skip_if);
+                self.blocks.push(self.rom.len());
+                self.emit(0x1000);
+            }
+            other => return Err(format!("expected then or begin, got '{}'", other)),
+        }
+        Ok(())
+    }
+    //the skip instruction that skips when the condition is true
+    fn condition(&mut self) -> Result<u16, String> {
+        let x = self.expect_register()? << 8;
+        let operator = self.next()?;
+        if operator == "key" {
+            return Ok(0xe09e | x);
+        }
+        if operator == "-key" {
+            return Ok(0xe0a1 | x);
+        }
+        let operand = self.peek().ok_or("unexpected end of program")?;
+        let word = match (operator, self.register(operand)) {
+            ("==", Some(y)) => 0x5000 | x | y << 4,
+            ("!=", Some(y)) => 0x9000 | x | y << 4,
+            ("==", None) => return Ok(0x3000 | x | self.value(0xff)?),
+            ("!=", None) => return Ok(0x4000 | x | self.value(0xff)?),
+            _ => return Err(format!("condition '{}' is not supported", operator)),
+        };
+        self.next()?;
+        Ok(word)
+    }
+}
+
+//swaps a skip instruction for the one with the opposite condition
+fn negate(skip: u16) -> u16 {
+    match skip & 0xf000 {
+        0x3000 => skip ^ 0x7000,
+        0x4000 => skip ^ 0x7000,
+        0x5000 => skip ^ 0xc000,
+        0x9000 => skip ^ 0xc000,
+        _ if skip & 0xff == 0x9e => skip & 0xff00 | 0xa1,
+        _ => skip & 0xff00 | 0x9e,
+    }
+}
+
+fn is_name(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_number(token: &str) -> Option<u16> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    if negative {
+        //negative numbers are bytes, -1 is 0xff
+        (value <= 0x80).then(|| (0x100 - value) & 0xff)
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles() {
+        let source = "
+            : main
+              :alias x v1
+              :const speed 3
+              i := dot        # forward label
+              loop
+                x += speed
+                if x == 9 then x := 0
+                while x != 6
+              again
+              sprite v0 x 1
+              return
+            : dot
+              0x80
+        ";
+        assert_eq!(
+            compile(source, 0x200).unwrap(),
+            [
+                0x12, 0x02, 0xa2, 0x14, 0x71, 0x03, 0x41, 0x09, 0x61, 0x00, 0x41, 0x06, 0x12, 0x10,
+                0x12, 0x04, 0xd0, 0x11, 0x00, 0xee, 0x80
+            ]
+        );
+        let blocks = ": main if v2 != v3 begin v0 := key else v0 -= 1 end ;";
+        assert_eq!(
+            compile(blocks, 0x200).unwrap(),
+            [0x12, 0x02, 0x92, 0x30, 0x12, 0x0a, 0xf0, 0x0a, 0x12, 0x0c, 0x70, 0xff, 0x00, 0xee]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let error = |source: &str| compile(source, 0x200).err().unwrap();
+        assert_eq!(error("v0 := 1"), "program has no main label");
+        assert_eq!(
+            error(": main\n\n  jump nowhere"),
+            "line 3: undefined label 'nowhere'"
+        );
+        assert_eq!(
+            error(": main\n  v0 := 300"),
+            "line 2: '300' is out of range"
+        );
+        assert_eq!(
+            error(": main : main"),
+            "line 1: bad or duplicate label 'main'"
+        );
+        assert_eq!(error(": main if v0 == 1 begin"), "begin without end");
+        assert_eq!(error(": main loop"), "loop without again");
+        assert_eq!(error(": main else"), "line 1: else without begin");
+        assert_eq!(error(": main :macro"), "line 1: ':macro' is not supported");
+        assert_eq!(error(": main v0 :="), "line 1: unexpected end of program");
+        assert_eq!(
+            error(": main :org 0xfff 1 2"),
+            "program does not fit in memory"
+        );
+    }
+}
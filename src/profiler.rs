@@ -0,0 +1,251 @@
+use super::chip8::MEMORY_SIZE;
+use super::disassembler;
+use std::fs;
+
+//rows in each section of the report
+const TOP_COUNT: usize = 16;
+
+//executions per address and per opcode class, collected by the core while
+//--profile is on
+pub struct Profile {
+    hits: Vec<u64>,
+    classes: [u64; 16],
+    instructions: u64,
+    frames: u64,
+    //instructions counted when the current frame started
+    frame_start: u64,
+    fewest_per_frame: u64,
+    most_per_frame: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; MEMORY_SIZE],
+            classes: [0; 16],
+            instructions: 0,
+            frames: 0,
+            frame_start: 0,
+            fewest_per_frame: u64::MAX,
+            most_per_frame: 0,
+        }
+    }
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        self.hits[pc as usize % MEMORY_SIZE] += 1;
+        self.classes[(opcode >> 12) as usize] += 1;
+        self.instructions += 1;
+    }
+    pub fn end_frame(&mut self) {
+        let count = self.instructions - self.frame_start;
+        self.fewest_per_frame = self.fewest_per_frame.min(count);
+        self.most_per_frame = self.most_per_frame.max(count);
+        self.frame_start = self.instructions;
+        self.frames += 1;
+    }
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits[address as usize % MEMORY_SIZE]
+    }
+
+    //the text table: per frame statistics, opcode classes, hot spots, hot
+    //loops and the parts of the rom that never ran
+    pub fn report(&self, rom: &[u8], origin: u16) -> String {
+        let mut report = String::new();
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+        report.push_str(&format!(
+            "{} instructions over {} frames\n",
+            self.instructions, self.frames
+        ));
+        if self.frames > 0 {
+            report.push_str(&format!(
+                "per frame: fewest {}, mean {:.1}, most {}\n",
+                self.fewest_per_frame,
+                self.instructions as f64 / self.frames as f64,
+                self.most_per_frame
+            ));
+        }
+
+        report.push_str("\nopcode class       count       %\n");
+        for (class, &count) in self.classes.iter().enumerate() {
+            if count > 0 {
+                report.push_str(&format!(
+                    "{:X}xxx        {:>12} {:>6.2}\n",
+                    class,
+                    count,
+                    percent(count)
+                ));
+            }
+        }
+
+        let memory = self.memory_image(rom, origin);
+        report.push_str("\nhot spots\naddress         hits       %  instruction\n");
+        let mut spots: Vec<usize> = (0..MEMORY_SIZE).filter(|&a| self.hits[a] > 0).collect();
+        spots.sort_by_key(|&a| std::cmp::Reverse(self.hits[a]));
+        for &address in spots.iter().take(TOP_COUNT) {
+            report.push_str(&format!(
+                "{:04x}    {:>12} {:>6.2}  {}\n",
+                address,
+                self.hits[address],
+                percent(self.hits[address]),
+                instruction(&memory, address)
+            ));
+        }
+
+        report.push_str("\nhot loops\nrange              instructions       %\n");
+        let mut loops = self.loops(&memory);
+        loops.sort_by_key(|&(_, _, count)| std::cmp::Reverse(count));
+        for &(start, end, count) in loops.iter().take(TOP_COUNT) {
+            report.push_str(&format!(
+                "{:04x}-{:04x}   {:>16} {:>6.2}\n",
+                start,
+                end,
+                count,
+                percent(count)
+            ));
+        }
+
+        let covered = self.covered();
+        let rom_range = origin as usize..(origin as usize + rom.len()).min(MEMORY_SIZE);
+        let covered_bytes = rom_range.clone().filter(|&a| covered[a]).count();
+        report.push_str(&format!(
+            "\nexecuted {} of {} rom bytes ({:.1}%), never executed:\n",
+            covered_bytes,
+            rom_range.len(),
+            100.0 * covered_bytes as f64 / rom_range.len().max(1) as f64
+        ));
+        //data looks the same as dead code here, sprites will show up too
+        let mut address = rom_range.start;
+        while address < rom_range.end {
+            if covered[address] {
+                address += 1;
+                continue;
+            }
+            let start = address;
+            while address < rom_range.end && !covered[address] {
+                address += 1;
+            }
+            report.push_str(&format!(
+                "{:04x}-{:04x}  {} bytes\n",
+                start,
+                address - 1,
+                address - start
+            ));
+        }
+        report
+    }
+
+    //the disassembler's listing with the hit count of every instruction
+    //appended to its comment, so it still assembles. lines follow the
+    //executed addresses, a byte that execution jumps past gets a DB line of
+    //its own so code at odd addresses decodes and gets its counts
+    pub fn annotated_listing(&self, rom: &[u8], origin: u16) -> String {
+        let hits = |address: usize| self.hits.get(address).copied().unwrap_or(0);
+        let mut annotated = String::new();
+        let mut offset = 0;
+        while offset < rom.len() {
+            let address = origin as usize + offset;
+            let length = if hits(address) == 0 && hits(address + 1) > 0 {
+                1
+            } else {
+                2.min(rom.len() - offset)
+            };
+            let line = disassembler::disassemble_program(
+                &rom[offset..offset + length],
+                address as u16,
+                None,
+            );
+            let count = match hits(address) {
+                0 => String::from("-"),
+                count => count.to_string(),
+            };
+            annotated.push_str(&format!("{}\t{:>10}\n", line.trim_end(), count));
+            offset += length;
+        }
+        annotated
+    }
+
+    //bytes that were part of an executed instruction
+    fn covered(&self) -> Vec<bool> {
+        let mut covered = vec![false; MEMORY_SIZE];
+        for address in (0..MEMORY_SIZE).filter(|&a| self.hits[a] > 0) {
+            covered[address] = true;
+            covered[(address + 1) % MEMORY_SIZE] = true;
+        }
+        covered
+    }
+
+    //(start, end, instructions run inside) for every executed jump back to
+    //an earlier address, the usual shape of a chip-8 loop
+    fn loops(&self, memory: &[u8]) -> Vec<(u16, u16, u64)> {
+        (0..MEMORY_SIZE - 1)
+            .filter(|&a| self.hits[a] > 0 && memory[a] >> 4 == 0x1)
+            .filter_map(|a| {
+                let target = ((memory[a] as usize & 0xf) << 8) | memory[a + 1] as usize;
+                (target <= a).then(|| {
+                    let count = self.hits[target..=a].iter().sum();
+                    (target as u16, a as u16, count)
+                })
+            })
+            .collect()
+    }
+
+    //the rom as it was loaded, for decoding the instructions the report names
+    fn memory_image(&self, rom: &[u8], origin: u16) -> Vec<u8> {
+        let mut memory = vec![0; MEMORY_SIZE];
+        let start = origin as usize;
+        let end = (start + rom.len()).min(MEMORY_SIZE);
+        memory[start..end].copy_from_slice(&rom[..end - start]);
+        memory
+    }
+
+    //a listing (.asm or .lst) or otherwise the text report
+    pub fn save(&self, path: &str, rom: &[u8], origin: u16) -> Result<(), String> {
+        let text = if path.ends_with(".asm") || path.ends_with(".lst") {
+            self.annotated_listing(rom, origin)
+        } else {
+            self.report(rom, origin)
+        };
+        fs::write(path, text).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+fn instruction(memory: &[u8], address: usize) -> String {
+    if address + 1 < memory.len() {
+        disassembler::disassemble_chip8(memory, address).replace('\t', " ")
+    } else {
+        String::from("??")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn odd_aligned_code() {
+        //jump over a padding byte to code at an odd address
+        let rom = [0x12, 0x03, 0x00, 0x60, 0x05, 0x12, 0x05];
+        let mut profile = Profile::new();
+        profile.record(0x200, 0x1203);
+        profile.record(0x203, 0x6005);
+        for _ in 0..3 {
+            profile.record(0x205, 0x1205);
+        }
+        let listing = profile.annotated_listing(&rom, 0x200);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "\tJMP\t$0203\t; 0200: 1203\t         1",
+                "\tDB\t$00\t; 0202: 00\t         -",
+                "\tMVI\tV0, 05\t; 0203: 6005\t         1",
+                "\tJMP\t$0205\t; 0205: 1205\t         3",
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use super::renderer::Palette;
+use super::scheduler::TIMER_HZ;
+use super::screenshot;
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        //only every nth frame is kept
+        frame_skip: u64,
+        //last sampled frame and the frame it appeared on, written out once we
+        //know how long it stays on screen
+        pending: Option<(Vec<u8>, u64)>,
+    },
+    //RGB24 frames back to back, described by a sidecar text file
+    Raw {
+        file: BufWriter<File>,
+        path: String,
+    },
+}
+
+//captures every emulated frame into an animated gif or a raw video stream,
+//picked from the output file extension. some browsers play gif delays under
+//2/100s slowly, a gif_frame_skip of 3 or more avoids those
+pub struct Recorder {
+    output: Output,
+    width: usize,
+    height: usize,
+    scale: usize,
+    palette: Palette,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn create(
+        path: &str,
+        width: usize,
+        height: usize,
+        scale: usize,
+        palette: Palette,
+        gif_frame_skip: u64,
+    ) -> io::Result<Self> {
+        let is_gif = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+        let output = if is_gif {
+            let global_palette = [palette.background, palette.foreground].concat();
+            let mut encoder = gif::Encoder::new(
+                BufWriter::new(File::create(path)?),
+                (width * scale) as u16,
+                (height * scale) as u16,
+                &global_palette,
+            )
+            .map_err(io::Error::other)?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(io::Error::other)?;
+            Output::Gif {
+                encoder,
+                frame_skip: gif_frame_skip.max(1),
+                pending: None,
+            }
+        } else {
+            Output::Raw {
+                file: BufWriter::new(File::create(path)?),
+                path: path.to_string(),
+            }
+        };
+        Ok(Self {
+            output,
+            width,
+            height,
+            scale,
+            palette,
+            frames: 0,
+        })
+    }
+    //call once per emulated frame
+    pub fn add_frame(&mut self, video: &[bool]) -> io::Result<()> {
+        let frame_number = self.frames;
+        self.frames += 1;
+        match &mut self.output {
+            Output::Gif {
+                encoder,
+                frame_skip,
+                pending,
+            } => {
+                if !frame_number.is_multiple_of(*frame_skip) {
+                    return Ok(());
+                }
+                let indexed = indexed_pixels(video, self.width, self.height, self.scale);
+                //identical frames just make the previous one stay longer
+                if pending
+                    .as_ref()
+                    .is_some_and(|(previous, _)| *previous == indexed)
+                {
+                    return Ok(());
+                }
+                if let Some((previous, shown_at)) = pending.take() {
+                    write_gif_frame(
+                        encoder,
+                        self.width,
+                        self.height,
+                        self.scale,
+                        &previous,
+                        gif_delay(shown_at, frame_number),
+                    )?;
+                }
+                *pending = Some((indexed, frame_number));
+                Ok(())
+            }
+            Output::Raw { file, .. } => file.write_all(&screenshot::video_to_rgb(
+                video,
+                self.width,
+                self.height,
+                self.scale,
+                &self.palette,
+            )),
+        }
+    }
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Gif {
+                mut encoder,
+                pending,
+                ..
+            } => {
+                if let Some((previous, shown_at)) = pending {
+                    write_gif_frame(
+                        &mut encoder,
+                        self.width,
+                        self.height,
+                        self.scale,
+                        &previous,
+                        gif_delay(shown_at, self.frames),
+                    )?;
+                }
+                encoder.into_inner()?.flush()
+            }
+            Output::Raw { mut file, path } => {
+                file.flush()?;
+                let (width, height) = (self.width * self.scale, self.height * self.scale);
+                let sidecar = format!(
+                    "format rgb24\nwidth {}\nheight {}\nfps {}\nframes {}\n\
+                     # ffmpeg -f rawvideo -pixel_format rgb24 -video_size {}x{} -framerate {} -i {} out.mp4\n",
+                    width, height, TIMER_HZ, self.frames, width, height, TIMER_HZ, path
+                );
+                fs::write(format!("{}.txt", path), sidecar)
+            }
+        }
+    }
+}
+
+//gif delays are in 1/100s, rounding the start and end instead of each frame
+//keeps a 60hz recording from drifting
+fn gif_delay(from_frame: u64, to_frame: u64) -> u16 {
+    let centiseconds = |frame: u64| (frame * 100 + TIMER_HZ / 2) / TIMER_HZ;
+    (centiseconds(to_frame) - centiseconds(from_frame)).min(u16::MAX as u64) as u16
+}
+
+//0 for background and 1 for foreground, matching the global palette
+fn indexed_pixels(video: &[bool], width: usize, height: usize, scale: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(width * scale * height * scale);
+    for y in 0..height * scale {
+        for x in 0..width * scale {
+            pixels.push(video[(y / scale) * width + x / scale] as u8);
+        }
+    }
+    pixels
+}
+
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    width: usize,
+    height: usize,
+    scale: usize,
+    pixels: &[u8],
+    delay: u16,
+) -> io::Result<()> {
+    let frame = gif::Frame {
+        width: (width * scale) as u16,
+        height: (height * scale) as u16,
+        buffer: Cow::Borrowed(pixels),
+        delay,
+        ..gif::Frame::default()
+    };
+    encoder.write_frame(&frame).map_err(io::Error::other)
+}
@@ -0,0 +1,238 @@
+use std::str::FromStr;
+
+//software renderer: turns the chip8 video buffer into an RGB24 image that the
+//frontend uploads to a streaming texture. every post-processing effect runs on
+//the CPU so it works the same on accelerated and software SDL renderers.
+
+#[derive(Clone, Copy)]
+pub struct Palette {
+    pub foreground: [u8; 3],
+    pub background: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            foreground: [255, 255, 255],
+            background: [0, 0, 0],
+        }
+    }
+}
+
+//a named palette or "RRGGBB,RRGGBB" for foreground and background
+impl FromStr for Palette {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let named = |foreground: u32, background: u32| Palette {
+            foreground: rgb(foreground),
+            background: rgb(background),
+        };
+        match s {
+            "white" => Ok(Palette::default()),
+            "green" => Ok(named(0x33ff66, 0x0a1a0f)),
+            "amber" => Ok(named(0xffb000, 0x1a1000)),
+            "gameboy" => Ok(named(0x0f380f, 0x9bbc0f)),
+            "inverted" => Ok(named(0x000000, 0xffffff)),
+            _ => {
+                let parse = |hex: &str| {
+                    u32::from_str_radix(hex.trim().trim_start_matches('#'), 16)
+                        .ok()
+                        .filter(|_| hex.trim().trim_start_matches('#').len() == 6)
+                };
+                match s.split_once(',') {
+                    Some((fg, bg)) => match (parse(fg), parse(bg)) {
+                        (Some(fg), Some(bg)) => Ok(named(fg, bg)),
+                        _ => Err(format!("invalid palette colors '{}'", s)),
+                    },
+                    None => Err(format!(
+                        "unknown palette '{}', use white, green, amber, gameboy, inverted or RRGGBB,RRGGBB",
+                        s
+                    )),
+                }
+            }
+        }
+    }
+}
+
+fn rgb(color: u32) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+//how SDL draws the finished frame, the effects are computed on the CPU either
+//way so software works on machines without a GPU
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    Accelerated,
+    Software,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accelerated" => Ok(Backend::Accelerated),
+            "software" => Ok(Backend::Software),
+            _ => Err(format!("unknown renderer: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Effects {
+    pub scanlines: bool,
+    pub grid: bool,
+    pub bloom: bool,
+    pub curvature: bool,
+    pub vignette: bool,
+}
+
+//how dark scanlines and grid lines get (0 = black, 1 = untouched)
+const SCANLINE_INTENSITY: f32 = 0.6;
+const GRID_INTENSITY: f32 = 0.75;
+//how much of the foreground color leaks around lit pixels
+const BLOOM_STRENGTH: f32 = 0.45;
+//barrel distortion amount, 0 means a flat screen
+const CURVATURE: f32 = 0.08;
+//darkening at the corners of the screen
+const VIGNETTE_STRENGTH: f32 = 0.35;
+
+pub struct Renderer {
+    width: usize,
+    height: usize,
+    scale: usize,
+    pub palette: Palette,
+    pub effects: Effects,
+    //RGB24 output, (width * scale) x (height * scale)
+    pixels: Vec<u8>,
+    //blurred copy of the video buffer used for bloom, at native resolution
+    glow: Vec<f32>,
+}
+
+impl Renderer {
+    pub fn new(width: usize, height: usize, scale: usize) -> Self {
+        Self {
+            width,
+            height,
+            scale,
+            palette: Palette::default(),
+            effects: Effects::default(),
+            pixels: vec![0; width * scale * height * scale * 3],
+            glow: vec![0.0; width * height],
+        }
+    }
+    pub fn output_width(&self) -> usize {
+        self.width * self.scale
+    }
+    pub fn output_height(&self) -> usize {
+        self.height * self.scale
+    }
+    //bytes per row of the output image
+    pub fn pitch(&self) -> usize {
+        self.output_width() * 3
+    }
+    pub fn render(&mut self, video: &[bool]) -> &[u8] {
+        if self.effects.bloom {
+            self.compute_glow(video);
+        }
+        let out_w = self.output_width();
+        let out_h = self.output_height();
+        let fg = self.palette.foreground.map(|c| c as f32);
+        let bg = self.palette.background.map(|c| c as f32);
+
+        for oy in 0..out_h {
+            for ox in 0..out_w {
+                //normalized screen coordinates in -1..1
+                let mut u = (ox as f32 + 0.5) / out_w as f32 * 2.0 - 1.0;
+                let mut v = (oy as f32 + 0.5) / out_h as f32 * 2.0 - 1.0;
+                if self.effects.curvature {
+                    let r2 = u * u + v * v;
+                    u *= 1.0 + CURVATURE * r2;
+                    v *= 1.0 + CURVATURE * r2;
+                }
+                let out_index = (oy * out_w + ox) * 3;
+                if !(-1.0..1.0).contains(&u) || !(-1.0..1.0).contains(&v) {
+                    //bent off the edge of the tube
+                    self.pixels[out_index..out_index + 3].copy_from_slice(&[0, 0, 0]);
+                    continue;
+                }
+                let (sx, sy) = unit_to_output(u, v, out_w, out_h);
+                let x = sx / self.scale;
+                let y = sy / self.scale;
+
+                let lit = video[y * self.width + x];
+                let mut color = if lit { fg } else { bg };
+
+                if self.effects.bloom && !lit {
+                    let glow = self.glow[y * self.width + x] * BLOOM_STRENGTH;
+                    for (channel, fg_channel) in color.iter_mut().zip(fg) {
+                        *channel += (fg_channel - *channel).max(0.0) * glow;
+                    }
+                }
+                let mut factor = 1.0;
+                if self.effects.grid && self.scale >= 3 {
+                    let cx = sx % self.scale;
+                    let cy = sy % self.scale;
+                    if cx == self.scale - 1 || cy == self.scale - 1 {
+                        factor *= GRID_INTENSITY;
+                    }
+                }
+                if self.effects.scanlines && oy % 2 == 1 {
+                    factor *= SCANLINE_INTENSITY;
+                }
+                if self.effects.vignette {
+                    factor *= 1.0 - VIGNETTE_STRENGTH * (u * u + v * v) * 0.5;
+                }
+                for (out, channel) in self.pixels[out_index..out_index + 3].iter_mut().zip(color) {
+                    *out = (channel * factor).clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+        &self.pixels
+    }
+    //two passes of a 3x3 box blur over the lit pixels
+    fn compute_glow(&mut self, video: &[bool]) {
+        let (w, h) = (self.width, self.height);
+        let mut source: Vec<f32> = video.iter().map(|&p| if p { 1.0 } else { 0.0 }).collect();
+        for _ in 0..2 {
+            for y in 0..h {
+                for x in 0..w {
+                    let mut sum = 0.0;
+                    let mut count = 0.0;
+                    for dy in -1i32..=1 {
+                        for dx in -1i32..=1 {
+                            let nx = x as i32 + dx;
+                            let ny = y as i32 + dy;
+                            if nx >= 0 && ny >= 0 && (nx as usize) < w && (ny as usize) < h {
+                                sum += source[ny as usize * w + nx as usize];
+                                count += 1.0;
+                            }
+                        }
+                    }
+                    self.glow[y * w + x] = sum / count;
+                }
+            }
+            source.copy_from_slice(&self.glow);
+        }
+    }
+}
+
+//position in scaled (undistorted) output space for coordinates in -1..1. u or
+//v just under 1.0 can round up to the edge in f32
+fn unit_to_output(u: f32, v: f32, out_w: usize, out_h: usize) -> (usize, usize) {
+    let sx = ((u + 1.0) * 0.5 * out_w as f32) as usize;
+    let sy = ((v + 1.0) * 0.5 * out_h as f32) as usize;
+    (sx.min(out_w - 1), sy.min(out_h - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges_stay_on_the_screen() {
+        let below_one = 1.0 - f32::EPSILON / 2.0;
+        assert_eq!(unit_to_output(below_one, below_one, 640, 320), (639, 319));
+        assert_eq!(unit_to_output(-1.0, -1.0, 640, 320), (0, 0));
+        assert_eq!(unit_to_output(0.0, 0.0, 640, 320), (320, 160));
+    }
+}
@@ -0,0 +1,43 @@
+use super::config::{Config, Entries};
+use super::settings::Settings;
+
+//bundled metadata for known roms, keyed by sha1 like the config file
+const DATABASE: &str = include_str!("romdb.ini");
+//keys that describe a rom instead of configuring the emulator
+const METADATA_KEYS: [&str; 2] = ["author", "platform"];
+
+pub struct RomInfo {
+    entries: Entries,
+}
+
+pub fn lookup(rom_sha1: &str) -> Option<RomInfo> {
+    let database = Config::parse_with(DATABASE, |key, value| {
+        if METADATA_KEYS.contains(&key) {
+            Ok(())
+        } else {
+            Settings::default().set(key, value)
+        }
+    })
+    .expect("bundled rom database is invalid");
+    database.rom_entries(rom_sha1).map(|entries| RomInfo {
+        entries: entries.clone(),
+    })
+}
+
+impl RomInfo {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, k, _)| k == key)
+            .map(|(_, _, value)| value.as_str())
+    }
+    //recommended settings for this rom, title included
+    pub fn apply(&self, settings: &mut Settings) -> Result<(), String> {
+        for (_, key, value) in &self.entries {
+            if !METADATA_KEYS.contains(&key.as_str()) {
+                settings.set(key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use super::chip8::{self, Timing};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+//the delay and sound timers always count down at 60hz
+pub const TIMER_HZ: u64 = 60;
+//after a long stall (window drag, breakpoint in a debugger) don't try to
+//replay every missed frame, just drop them
+const MAX_CATCH_UP_FRAMES: u64 = 5;
+//frames run per update when unthrottled, before going back to the event loop
+const UNTHROTTLED_BATCH: u64 = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    //multiple of real time, 1.0 is normal speed
+    Scaled(f64),
+    //as fast as the host can go
+    Unthrottled,
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Scaled(factor) => write!(f, "{}%", (factor * 100.0).round()),
+            Speed::Unthrottled => write!(f, "unthrottled"),
+        }
+    }
+}
+
+//decides how many 60hz frames are due based on a monotonic clock and how many
+//instructions each of those frames should run
+pub struct Scheduler {
+    instructions_per_second: u32,
+    //fraction of an instruction left over from the previous frame, in
+    //sixtieths so that it adds up exactly
+    cycle_carry: u64,
+    speed: Speed,
+    paused: bool,
+    //frame advance requested while paused
+    step_pending: bool,
+    //emulated time is counted from here, reset whenever the speed changes
+    start: Instant,
+    frames_emitted: u64,
+}
+
+impl Scheduler {
+    pub fn new(instructions_per_second: u32) -> Self {
+        Self {
+            instructions_per_second,
+            cycle_carry: 0,
+            speed: Speed::Scaled(1.0),
+            paused: false,
+            step_pending: false,
+            start: Instant::now(),
+            frames_emitted: 0,
+        }
+    }
+    pub fn instructions_per_second(&self) -> u32 {
+        self.instructions_per_second
+    }
+    pub fn set_instructions_per_second(&mut self, instructions_per_second: u32) {
+        self.instructions_per_second = instructions_per_second;
+        self.cycle_carry = 0;
+    }
+    //start counting instructions from a whole frame again, so a restarted
+    //machine sees exactly the same per-frame budgets
+    pub fn reset_cycle_carry(&mut self) {
+        self.cycle_carry = 0;
+    }
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+    pub fn set_speed(&mut self, speed: Speed, now: Instant) {
+        self.speed = speed;
+        self.rebase(now);
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    pub fn set_paused(&mut self, paused: bool, now: Instant) {
+        self.paused = paused;
+        self.step_pending = false;
+        self.rebase(now);
+    }
+    //run exactly one more frame, only meaningful while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.step_pending = true;
+        }
+    }
+    //number of frames that should have run by `now` but haven't yet
+    pub fn frames_due(&mut self, now: Instant) -> u64 {
+        if self.paused {
+            let step = self.step_pending;
+            self.step_pending = false;
+            return step as u64;
+        }
+        let factor = match self.speed {
+            Speed::Scaled(factor) => factor,
+            Speed::Unthrottled => return UNTHROTTLED_BATCH,
+        };
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        let target = (elapsed * TIMER_HZ as f64 * factor) as u64;
+        let mut due = target.saturating_sub(self.frames_emitted);
+        if due > MAX_CATCH_UP_FRAMES {
+            due = MAX_CATCH_UP_FRAMES;
+            self.frames_emitted = target - due;
+        }
+        self.frames_emitted += due;
+        due
+    }
+    //instructions to execute in the next frame, keeping the fractional part
+    //so that e.g. 700 ips doesn't silently become 660
+    pub fn cycles_for_frame(&mut self) -> u32 {
+        let sixtieths = self.instructions_per_second as u64 + self.cycle_carry;
+        self.cycle_carry = sixtieths % TIMER_HZ;
+        (sixtieths / TIMER_HZ) as u32
+    }
+    //what to pass to Chip8::run_frame for the next frame
+    pub fn frame_budget(&mut self, timing: Timing) -> u32 {
+        match timing {
+            Timing::Fixed => self.cycles_for_frame(),
+            Timing::Vip => chip8::VIP_CYCLES_PER_FRAME,
+        }
+    }
+    //how long the frontend can sleep before another frame is due
+    pub fn time_until_next_frame(&self, now: Instant) -> Duration {
+        if self.paused {
+            //nothing to run, just keep polling input at the normal rate
+            return Duration::from_secs(1) / TIMER_HZ as u32;
+        }
+        let factor = match self.speed {
+            Speed::Scaled(factor) => factor,
+            Speed::Unthrottled => return Duration::ZERO,
+        };
+        let next_secs = (self.frames_emitted + 1) as f64 / (TIMER_HZ as f64 * factor);
+        let next = self.start + Duration::from_secs_f64(next_secs);
+        next.saturating_duration_since(now)
+    }
+    fn rebase(&mut self, now: Instant) {
+        self.start = now;
+        self.frames_emitted = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn fractional_instructions_carry_over() {
+        let mut scheduler = Scheduler::new(700);
+        let budgets: Vec<u32> = (0..60)
+            .map(|_| scheduler.frame_budget(Timing::Fixed))
+            .collect();
+        assert!(budgets.iter().all(|&b| b == 11 || b == 12));
+        assert_eq!(budgets.iter().sum::<u32>(), 700);
+        //a restart begins on a whole frame again
+        scheduler.frame_budget(Timing::Fixed);
+        scheduler.reset_cycle_carry();
+        assert_eq!(scheduler.frame_budget(Timing::Fixed), 11);
+        assert_eq!(
+            scheduler.frame_budget(Timing::Vip),
+            chip8::VIP_CYCLES_PER_FRAME
+        );
+        let mut scheduler = Scheduler::new(600);
+        assert!((0..60).all(|_| scheduler.cycles_for_frame() == 10));
+    }
+
+    #[test]
+    fn frames_follow_the_clock() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(600);
+        scheduler.set_speed(Speed::Scaled(1.0), start);
+        assert_eq!(scheduler.frames_due(start), 0);
+        assert_eq!(scheduler.frames_due(at(start, 60)), 3);
+        assert_eq!(scheduler.frames_due(at(start, 60)), 0);
+        let wait = scheduler.time_until_next_frame(at(start, 60));
+        assert!(wait > Duration::from_millis(6) && wait < Duration::from_millis(7));
+        scheduler.set_speed(Speed::Scaled(2.0), start);
+        assert_eq!(scheduler.frames_due(at(start, 40)), 4);
+        scheduler.set_speed(Speed::Unthrottled, start);
+        assert_eq!(scheduler.frames_due(start), UNTHROTTLED_BATCH);
+        assert_eq!(scheduler.time_until_next_frame(start), Duration::ZERO);
+    }
+
+    #[test]
+    fn stalls_only_catch_up_a_few_frames() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(600);
+        scheduler.set_speed(Speed::Scaled(1.0), start);
+        assert_eq!(scheduler.frames_due(at(start, 60)), 3);
+        //a second went by, 57 frames are late and most are dropped
+        assert_eq!(scheduler.frames_due(at(start, 1000)), MAX_CATCH_UP_FRAMES);
+        assert_eq!(scheduler.frames_due(at(start, 1000)), 0);
+        //and the frames after it keep their normal pace
+        assert_eq!(scheduler.frames_due(at(start, 1025)), 1);
+        assert_eq!(scheduler.frames_due(at(start, 1060)), 2);
+    }
+
+    #[test]
+    fn paused_runs_single_frames() {
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(600);
+        scheduler.set_paused(true, start);
+        assert_eq!(scheduler.frames_due(at(start, 1000)), 0);
+        scheduler.advance_frame();
+        assert_eq!(scheduler.frames_due(at(start, 1000)), 1);
+        assert_eq!(scheduler.frames_due(at(start, 2000)), 0);
+        //unpausing drops a pending step and starts the clock from there
+        scheduler.advance_frame();
+        scheduler.set_paused(false, at(start, 2000));
+        assert_eq!(scheduler.frames_due(at(start, 2000)), 0);
+        assert_eq!(scheduler.frames_due(at(start, 2020)), 1);
+        //advancing only means something while paused
+        scheduler.advance_frame();
+        assert_eq!(scheduler.frames_due(at(start, 2020)), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use super::renderer::Palette;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//expands the 1-bit video buffer to RGB24, each chip8 pixel becomes a
+//scale x scale block
+pub fn video_to_rgb(
+    video: &[bool],
+    width: usize,
+    height: usize,
+    scale: usize,
+    palette: &Palette,
+) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(width * scale * height * scale * 3);
+    for y in 0..height * scale {
+        for x in 0..width * scale {
+            let lit = video[(y / scale) * width + x / scale];
+            let color = if lit {
+                palette.foreground
+            } else {
+                palette.background
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    pixels
+}
+
+pub fn write_png(
+    path: &str,
+    video: &[bool],
+    width: usize,
+    height: usize,
+    scale: usize,
+    palette: &Palette,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        (width * scale) as u32,
+        (height * scale) as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&video_to_rgb(video, width, height, scale, palette))?;
+    Ok(())
+}
+
+//binary PPM (P6), needs nothing but std so it works in any headless build
+pub fn write_ppm(
+    path: &str,
+    video: &[bool],
+    width: usize,
+    height: usize,
+    scale: usize,
+    palette: &Palette,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", width * scale, height * scale)?;
+    file.write_all(&video_to_rgb(video, width, height, scale, palette))?;
+    file.flush()
+}
+
+//binary PBM (P4), one bit per pixel with 1 meaning black, so lit pixels are
+//written as 0 to come out white like on screen
+pub fn write_pbm(
+    path: &str,
+    video: &[bool],
+    width: usize,
+    height: usize,
+    scale: usize,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let out_width = width * scale;
+    write!(file, "P4\n{} {}\n", out_width, height * scale)?;
+    let mut row = vec![0u8; out_width.div_ceil(8)];
+    for y in 0..height * scale {
+        row.fill(0);
+        for x in 0..out_width {
+            if !video[(y / scale) * width + x / scale] {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        file.write_all(&row)?;
+    }
+    file.flush()
+}
+
+//<rom name>-<yyyymmdd>-<hhmmss>.<extension> in the current directory, in UTC
+pub fn screenshot_filename(rom_path: &str, extension: &str) -> String {
+    let rom_name = Path::new(rom_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("chip8"));
+    format!("{}-{}.{}", rom_name, timestamp(), extension)
+}
+
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let time_of_day = seconds % 86400;
+    //days since epoch to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
@@ -0,0 +1,540 @@
+use super::chip8::{Chip8, Fault, FaultKind, MEMORY_SIZE, VIDEO_HEIGHT, VIDEO_WIDTH};
+use super::debug_map;
+use super::debugger::{WatchKind, Watchpoint};
+use super::screenshot;
+use super::settings::Settings;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST};
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+//how long tap(key) holds a key down, long enough for roms that poll the
+//keypad every few frames
+const TAP_FRAMES: u64 = 4;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+//Rhai scripts for --script. the top level of the script runs once before the
+//first frame and sets up hooks, which are functions or closures:
+//
+//  on_frame(f)              after every frame
+//  on_pc(at, f)             before the instruction at an address runs
+//  on_write(at, f)          after an instruction writes to an address, with
+//  on_write(at, length, f)  the address and the value written, () when a
+//                           device took it. FX33 and FX55 call it for every
+//                           address they write
+//
+//addresses are numbers, or strings holding a label, game.asm:LINE or hex
+//when the rom has a debug map. hooks can use:
+//
+//  frame() pc() i() v(n) stack() delay_timer() sound_timer() peek(at)
+//  bcd(at) pixel(x, y), set_pc(at) set_i(at) set_v(n, value) poke(at, value)
+//  set_delay_timer(value) set_sound_timer(value), press(key) release(key)
+//  tap(key) tap(key, frames), screenshot(path) assert(ok) assert(ok, message)
+//  stop()
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    //(address, hook)
+    pc: Vec<(u16, FnPtr)>,
+    //(first address, length, hook)
+    write: Vec<(u16, u16, FnPtr)>,
+    //(key, frame it is released before)
+    taps: Vec<(usize, u64)>,
+    stop: bool,
+}
+
+pub struct Script {
+    path: String,
+    engine: Engine,
+    ast: AST,
+    machine: Rc<RefCell<Chip8>>,
+    hooks: Rc<RefCell<Hooks>>,
+    //the first error a hook ran into, reported when the frame ends
+    error: Option<String>,
+}
+
+impl Script {
+    //compiles the script and runs its top level
+    pub fn load(
+        path: &str,
+        machine: Rc<RefCell<Chip8>>,
+        settings: &Settings,
+    ) -> Result<Script, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let mut engine = Engine::new();
+        register_state(&mut engine, &machine);
+        register_input(&mut engine, &machine, &hooks);
+        register_hooks(&mut engine, &machine, &hooks);
+        register_checks(&mut engine, &machine, &hooks, settings);
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        engine
+            .run_ast(&ast)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Script {
+            path: path.to_string(),
+            engine,
+            ast,
+            machine,
+            hooks,
+            error: None,
+        })
+    }
+    pub fn stopped(&self) -> bool {
+        self.hooks.borrow().stop
+    }
+    //lets go of the keys tap() pressed once their time is up
+    pub fn start_frame(&mut self) {
+        let mut chip8 = self.machine.borrow_mut();
+        let frame = chip8.frame_count();
+        self.hooks.borrow_mut().taps.retain(|&(key, until)| {
+            if frame >= until {
+                chip8.keypress(key, false);
+            }
+            frame < until
+        });
+    }
+    //runs the hooks for a stop, which can also be a user breakpoint or
+    //watchpoint. every hooked address an instruction wrote gets its hooks
+    pub fn handle(&mut self, fault: &Fault) {
+        match fault.kind {
+            FaultKind::Breakpoint => {
+                let hooks: Vec<FnPtr> = self
+                    .hooks
+                    .borrow()
+                    .pc
+                    .iter()
+                    .filter(|(address, _)| *address == fault.pc)
+                    .map(|(_, hook)| hook.clone())
+                    .collect();
+                for hook in &hooks {
+                    self.call(hook, ());
+                }
+            }
+            FaultKind::Watchpoint { .. } => {
+                let writes: Vec<u16> = self
+                    .machine
+                    .borrow()
+                    .breakpoints()
+                    .hits()
+                    .iter()
+                    .filter(|(_, write)| *write)
+                    .map(|&(address, _)| address)
+                    .collect();
+                for address in writes {
+                    let hooks: Vec<FnPtr> = self
+                        .hooks
+                        .borrow()
+                        .write
+                        .iter()
+                        .filter(|(start, length, _)| {
+                            (*start as u32..*start as u32 + *length as u32)
+                                .contains(&(address as u32))
+                        })
+                        .map(|(_, _, hook)| hook.clone())
+                        .collect();
+                    //what the program would read back, through the bus's mirrors
+                    let value = match self.machine.borrow().peek(address) {
+                        Some(value) => Dynamic::from(value as i64),
+                        None => Dynamic::UNIT,
+                    };
+                    for hook in &hooks {
+                        self.call(hook, (address as i64, value.clone()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    //the per frame hooks, then the first error of any hook this frame
+    pub fn end_frame(&mut self) -> Result<(), String> {
+        let hooks = self.hooks.borrow().frame.clone();
+        for hook in &hooks {
+            self.call(hook, ());
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+    //after an error the rest of the frame's hooks are skipped
+    fn call(&mut self, hook: &FnPtr, args: impl FuncArgs) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = hook.call::<Dynamic>(&self.engine, &self.ast, args) {
+            self.error = Some(format!(
+                "{} in frame {}: {}",
+                self.path,
+                self.machine.borrow().frame_count(),
+                e
+            ));
+        }
+    }
+}
+
+fn register_state(engine: &mut Engine, machine: &Rc<RefCell<Chip8>>) {
+    let m = machine.clone();
+    engine.register_fn("frame", move || m.borrow().frame_count() as i64);
+    let m = machine.clone();
+    engine.register_fn("pc", move || m.borrow().pc() as i64);
+    let m = machine.clone();
+    engine.register_fn("i", move || m.borrow().index() as i64);
+    let m = machine.clone();
+    engine.register_fn("v", move |n: i64| -> ScriptResult<i64> {
+        Ok(m.borrow().registers()[register(n)?] as i64)
+    });
+    let m = machine.clone();
+    engine.register_fn("stack", move || -> Array {
+        m.borrow()
+            .stack()
+            .iter()
+            .map(|&a| Dynamic::from(a as i64))
+            .collect()
+    });
+    let m = machine.clone();
+    engine.register_fn("delay_timer", move || m.borrow().delay_timer() as i64);
+    let m = machine.clone();
+    engine.register_fn("sound_timer", move || m.borrow().sound_timer() as i64);
+    let m = machine.clone();
+    engine.register_fn("peek", move |at: Dynamic| -> ScriptResult<i64> {
+        let address = address(&m, at)?;
+        peek(&m.borrow(), address)
+    });
+    //a number stored as three decimal digits, the way FX33 writes scores
+    let m = machine.clone();
+    engine.register_fn("bcd", move |at: Dynamic| -> ScriptResult<i64> {
+        let address = address(&m, at)?;
+        let chip8 = m.borrow();
+        let digit = |offset: u16| peek(&chip8, address.wrapping_add(offset));
+        Ok(digit(0)? * 100 + digit(1)? * 10 + digit(2)?)
+    });
+    let m = machine.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| {
+        let x = x.rem_euclid(VIDEO_WIDTH as i64) as usize;
+        let y = y.rem_euclid(VIDEO_HEIGHT as i64) as usize;
+        m.borrow().get_display()[y * VIDEO_WIDTH as usize + x]
+    });
+
+    let m = machine.clone();
+    engine.register_fn("set_pc", move |at: Dynamic| -> ScriptResult<()> {
+        let address = address(&m, at)?;
+        m.borrow_mut().set_pc(address);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("set_i", move |at: Dynamic| -> ScriptResult<()> {
+        let address = address(&m, at)?;
+        m.borrow_mut().set_index(address);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("set_v", move |n: i64, value: i64| -> ScriptResult<()> {
+        m.borrow_mut().set_register(register(n)?, byte(value)?);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("set_delay_timer", move |value: i64| -> ScriptResult<()> {
+        m.borrow_mut().set_delay_timer(byte(value)?);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("set_sound_timer", move |value: i64| -> ScriptResult<()> {
+        m.borrow_mut().set_sound_timer(byte(value)?);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("poke", move |at: Dynamic, value: i64| -> ScriptResult<()> {
+        let address = address(&m, at)?;
+        m.borrow_mut().poke(address, byte(value)?);
+        Ok(())
+    });
+}
+
+fn register_input(engine: &mut Engine, machine: &Rc<RefCell<Chip8>>, hooks: &Rc<RefCell<Hooks>>) {
+    let m = machine.clone();
+    engine.register_fn("press", move |k: i64| -> ScriptResult<()> {
+        m.borrow_mut().keypress(key(k)?, true);
+        Ok(())
+    });
+    let m = machine.clone();
+    engine.register_fn("release", move |k: i64| -> ScriptResult<()> {
+        m.borrow_mut().keypress(key(k)?, false);
+        Ok(())
+    });
+    let (m, h) = (machine.clone(), hooks.clone());
+    let tap = move |k: i64, frames: i64| -> ScriptResult<()> {
+        let k = key(k)?;
+        let mut chip8 = m.borrow_mut();
+        chip8.keypress(k, true);
+        let until = chip8.frame_count() + frames.max(1) as u64;
+        h.borrow_mut().taps.push((k, until));
+        Ok(())
+    };
+    let tap_default = tap.clone();
+    engine.register_fn("tap", tap);
+    engine.register_fn("tap", move |k: i64| tap_default(k, TAP_FRAMES as i64));
+}
+
+fn register_hooks(engine: &mut Engine, machine: &Rc<RefCell<Chip8>>, hooks: &Rc<RefCell<Hooks>>) {
+    let h = hooks.clone();
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        h.borrow_mut().frame.push(hook)
+    });
+    //the hooks ride on the machine's breakpoints and watchpoints, the run
+    //hands those stops to handle()
+    let (m, h) = (machine.clone(), hooks.clone());
+    engine.register_fn(
+        "on_pc",
+        move |at: Dynamic, hook: FnPtr| -> ScriptResult<()> {
+            let address = address(&m, at)?;
+            m.borrow_mut().breakpoints_mut().add_hook(address);
+            h.borrow_mut().pc.push((address, hook));
+            Ok(())
+        },
+    );
+    let (m, h) = (machine.clone(), hooks.clone());
+    let on_write = move |at: Dynamic, length: i64, hook: FnPtr| -> ScriptResult<()> {
+        let start = address(&m, at)?;
+        let length = u16::try_from(length)
+            .ok()
+            .filter(|&length| length > 0 && start as usize + length as usize <= MEMORY_SIZE)
+            .ok_or_else(|| format!("{} bytes from {:#05x} is not in memory", length, start))?;
+        m.borrow_mut().breakpoints_mut().add_hook_watch(Watchpoint {
+            start,
+            length,
+            kind: WatchKind::Write,
+        });
+        h.borrow_mut().write.push((start, length, hook));
+        Ok(())
+    };
+    let on_write_byte = on_write.clone();
+    engine.register_fn("on_write", on_write);
+    engine.register_fn("on_write", move |at: Dynamic, hook: FnPtr| {
+        on_write_byte(at, 1, hook)
+    });
+}
+
+fn register_checks(
+    engine: &mut Engine,
+    machine: &Rc<RefCell<Chip8>>,
+    hooks: &Rc<RefCell<Hooks>>,
+    settings: &Settings,
+) {
+    engine.register_fn("assert", |ok: bool| -> ScriptResult<()> {
+        match ok {
+            true => Ok(()),
+            false => Err("assertion failed".into()),
+        }
+    });
+    engine.register_fn("assert", |ok: bool, message: &str| -> ScriptResult<()> {
+        match ok {
+            true => Ok(()),
+            false => Err(format!("assertion failed: {}", message).into()),
+        }
+    });
+    let h = hooks.clone();
+    engine.register_fn("stop", move || h.borrow_mut().stop = true);
+    //at the --scale and --palette of the run, .ppm and .pbm work too
+    let (m, scale, palette) = (machine.clone(), settings.scale as usize, settings.palette);
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        let chip8 = m.borrow();
+        let (video, width, height) = (
+            chip8.get_display(),
+            VIDEO_WIDTH as usize,
+            VIDEO_HEIGHT as usize,
+        );
+        let result = if path.ends_with(".ppm") {
+            screenshot::write_ppm(path, video, width, height, scale, &palette)
+        } else if path.ends_with(".pbm") {
+            screenshot::write_pbm(path, video, width, height, scale)
+        } else {
+            screenshot::write_png(path, video, width, height, scale, &palette)
+        };
+        result.map_err(|e| format!("{}: {}", path, e).into())
+    });
+}
+
+//a number, or a label, game.asm:LINE or hex string looked up in the debug map
+fn address(machine: &RefCell<Chip8>, at: Dynamic) -> ScriptResult<u16> {
+    if let Ok(address) = at.as_int() {
+        return u16::try_from(address)
+            .ok()
+            .filter(|&address| (address as usize) < MEMORY_SIZE)
+            .ok_or_else(|| format!("address {} is not in memory", address).into());
+    }
+    let location = at
+        .into_string()
+        .map_err(|kind| format!("expected an address, got {}", kind))?;
+    match machine.borrow().symbols() {
+        Some(symbols) => symbols.resolve(&location).map_err(Into::into),
+        None => debug_map::parse_address(&location).ok_or_else(|| {
+            format!(
+                "'{}' is not a hex address and there is no debug map",
+                location
+            )
+            .into()
+        }),
+    }
+}
+
+//reads follow the bus's mirrors, devices can't be read without side effects
+fn peek(chip8: &Chip8, address: u16) -> ScriptResult<i64> {
+    chip8
+        .peek(address)
+        .map(|value| value as i64)
+        .ok_or_else(|| format!("{:#05x} is a device and can't be peeked", address).into())
+}
+
+fn register(n: i64) -> ScriptResult<usize> {
+    (0..16)
+        .contains(&n)
+        .then_some(n as usize)
+        .ok_or_else(|| format!("there is no register V{}", n).into())
+}
+
+fn key(k: i64) -> ScriptResult<usize> {
+    (0..16)
+        .contains(&k)
+        .then_some(k as usize)
+        .ok_or_else(|| format!("there is no key {}", k).into())
+}
+
+fn byte(value: i64) -> ScriptResult<u8> {
+    u8::try_from(value).map_err(|_| format!("{} does not fit in a byte", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Rom;
+
+    const PROGRAM: [u8; 16] = [
+        0x60, 0x05, //200 V0 = 5
+        0x61, 0x07, //202 V1 = 7
+        0xa3, 0x00, //204 I = 300
+        0xf0, 0x33, //206 bcd of V0 to 300..302
+        0xf1, 0x55, //208 save V0 and V1 to 300 and 301
+        0xe1, 0x9e, //20a skip while key 7 is down
+        0x12, 0x0c, //20c loop
+        0x12, 0x0e, //20e loop, key 7 was down
+    ];
+
+    fn load(name: &str, source: &str) -> (Rc<RefCell<Chip8>>, Result<Script, String>) {
+        let rom = Rom {
+            program: PROGRAM.to_vec(),
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let settings = Settings::default();
+        let machine = Rc::new(RefCell::new(settings.create_machine(&rom).unwrap()));
+        let path =
+            std::env::temp_dir().join(format!("chip8-script-{}-{}.rhai", name, std::process::id()));
+        fs::write(&path, source).unwrap();
+        let script = Script::load(path.to_str().unwrap(), machine.clone(), &settings);
+        fs::remove_file(path).unwrap();
+        (machine, script)
+    }
+
+    //frames of 10 instructions, hooks stopping them the way a headless run does
+    fn run(script: &mut Script, machine: &RefCell<Chip8>, frames: u64) -> Result<(), String> {
+        for _ in 0..frames {
+            script.start_frame();
+            let mut budget = 10;
+            loop {
+                let result = machine.borrow_mut().run_frame(budget);
+                let Err(fault) = result else {
+                    break;
+                };
+                budget = 0;
+                script.handle(&fault);
+                match fault.kind {
+                    FaultKind::Breakpoint => {
+                        machine.borrow_mut().breakpoints_mut().resume_from(fault.pc)
+                    }
+                    FaultKind::Watchpoint { .. } => {}
+                    _ => panic!("{}", fault),
+                }
+            }
+            script.end_frame()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pc_hooks() {
+        let (machine, script) = load(
+            "pc",
+            r#"
+            on_pc(0x206, || set_v(14, v(14) + 1));
+            on_pc("208", || set_v(13, pc() - 0x200));
+            "#,
+        );
+        let mut script = script.unwrap();
+        run(&mut script, &machine, 3).unwrap();
+        let chip8 = machine.borrow();
+        assert_eq!(chip8.registers()[14], 1);
+        assert_eq!(chip8.registers()[13], 0x08);
+        assert_eq!(chip8.pc(), 0x20c);
+    }
+
+    #[test]
+    fn write_hooks_see_every_address() {
+        //a log of (offset, value) pairs at 401, counted at 400
+        let (machine, script) = load(
+            "write",
+            r#"
+            on_write(0x300, 3, |at, value| {
+                let n = peek(0x400);
+                poke(0x401 + n * 2, at - 0x300);
+                poke(0x402 + n * 2, value);
+                poke(0x400, n + 1);
+            });
+            on_write(0x302, |at, value| set_v(14, bcd(0x300)));
+            "#,
+        );
+        let mut script = script.unwrap();
+        run(&mut script, &machine, 1).unwrap();
+        let chip8 = machine.borrow();
+        let log = &chip8.memory()[0x400..0x40b];
+        //bcd of 5 writes 5 0 0 from the ones digit up, then 5 and 7 are saved
+        //over it
+        assert_eq!(log, [5, 2, 5, 1, 0, 0, 0, 0, 5, 1, 7]);
+        assert_eq!(chip8.registers()[14], 5);
+    }
+
+    #[test]
+    fn failed_asserts_end_the_run() {
+        let (machine, script) = load("assert", "on_frame(|| assert(frame() < 2, \"too late\"));");
+        let mut script = script.unwrap();
+        let error = run(&mut script, &machine, 5).err().unwrap();
+        assert!(error.contains("in frame 2: "), "{}", error);
+        assert!(error.contains("assertion failed: too late"), "{}", error);
+        assert_eq!(machine.borrow().frame_count(), 2);
+        //at the top level the script doesn't load
+        let (_, script) = load("assert-top", "assert(false);");
+        assert!(script.err().unwrap().contains("assertion failed"));
+    }
+
+    #[test]
+    fn keys() {
+        let (machine, script) = load("press", "press(7);");
+        run(&mut script.unwrap(), &machine, 1).unwrap();
+        assert_eq!(machine.borrow().pc(), 0x20e);
+
+        let (machine, script) = load("tap", "tap(3, 2); press(5); release(5);");
+        let mut script = script.unwrap();
+        assert!(!machine.borrow().keypad()[5]);
+        run(&mut script, &machine, 2).unwrap();
+        assert!(machine.borrow().keypad()[3]);
+        script.start_frame();
+        assert!(!machine.borrow().keypad()[3]);
+        assert_eq!(machine.borrow().pc(), 0x20c);
+
+        let (_, script) = load("bad-key", "press(16);");
+        assert!(script.err().unwrap().contains("there is no key 16"));
+    }
+}
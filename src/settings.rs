@@ -0,0 +1,470 @@
+use super::bus::{MemoryBus, Protect};
+use super::chip8::{self, Chip8, MemoryPolicy, QuirkProfile, StackDepth, Timing};
+use super::config::Config;
+use super::debug_map::DebugMap;
+use super::file_utils;
+use super::gym::{DoneRule, RewardTerm};
+use super::loader::Rom;
+use super::profiler::Profile;
+use super::renderer::{Backend, Effects, Palette};
+use super::romdb;
+use super::scheduler::Speed;
+use super::trace::{self, TraceFilter, Tracer};
+use std::str::FromStr;
+
+pub const DEFAULT_SCALE: u32 = 10;
+//cpu speed, independent of the 60hz timer rate
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: u32 = 600;
+
+//everything a run can be configured with. command line flags are applied as
+//key/value pairs through set() so other sources can use the same names
+#[derive(Clone)]
+pub struct Settings {
+    pub scale: u32,
+    pub instructions_per_second: u32,
+    //multiple of real time the emulator starts at
+    pub speed: f64,
+    //speed while tab is held
+    pub turbo: Speed,
+    //Timing::Vip ignores instructions_per_second and uses the original
+    //interpreter's per-opcode cycle costs instead
+    pub timing: Timing,
+    pub quirks: QuirkProfile,
+    //None uses the depth of the quirk profile
+    pub stack_depth: Option<StackDepth>,
+    pub memory_policy: MemoryPolicy,
+    //read-only areas, for debugging
+    pub protect: Vec<Protect>,
+    //None picks a random seed
+    pub seed: Option<u64>,
+    pub palette: Palette,
+    //crt effects at startup, F1-F5 toggle them
+    pub effects: Effects,
+    pub renderer: Backend,
+    pub mute: bool,
+    pub headless: bool,
+    //stop after this many frames
+    pub frames: Option<u64>,
+    pub debug: bool,
+    pub keymap: String,
+    //replay this movie instead of reading the keyboard
+    pub movie: Option<String>,
+    //capture every frame to a .gif or raw .rgb file
+    pub record: Option<String>,
+    //gifs keep every nth frame
+    pub gif_skip: u64,
+    //game name shown in the window title
+    pub title: Option<String>,
+    //reload the rom when its file changes
+    pub watch: bool,
+    pub load_address: u16,
+    //None starts at the load address
+    pub entry_point: Option<u16>,
+    //ring, ring:N or a file to trace executed instructions to
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+    //where to save execution counts when the run ends, .asm or .lst for an
+    //annotated listing
+    pub profile: Option<String>,
+    //local tcp port to wait for gdb on
+    pub gdb: Option<u16>,
+    //breakpoint locations: file:line, labels or hex addresses
+    pub breaks: Vec<String>,
+    //Rhai script that drives a run without a window
+    pub script: Option<String>,
+    //for gym: memory the reward is counted from, when episodes end, frames
+    //per step, chance of a frame keeping the last keys, and instances
+    pub reward: Vec<RewardTerm>,
+    pub done: Vec<DoneRule>,
+    pub frame_skip: u32,
+    pub sticky: f64,
+    pub envs: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            scale: DEFAULT_SCALE,
+            instructions_per_second: DEFAULT_INSTRUCTIONS_PER_SECOND,
+            speed: 1.0,
+            turbo: Speed::Unthrottled,
+            timing: Timing::Fixed,
+            quirks: QuirkProfile::Modern,
+            stack_depth: None,
+            memory_policy: MemoryPolicy::Wrap,
+            protect: Vec::new(),
+            seed: None,
+            palette: Palette::default(),
+            effects: Effects::default(),
+            renderer: Backend::Accelerated,
+            mute: false,
+            headless: false,
+            frames: None,
+            debug: false,
+            keymap: String::from("qwerty"),
+            movie: None,
+            record: None,
+            gif_skip: 1,
+            title: None,
+            watch: false,
+            load_address: chip8::DEFAULT_LOAD_ADDRESS,
+            entry_point: None,
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            profile: None,
+            gdb: None,
+            breaks: Vec::new(),
+            script: None,
+            reward: Vec::new(),
+            done: Vec::new(),
+            frame_skip: 1,
+            sticky: 0.0,
+            envs: 1,
+        }
+    }
+}
+
+impl Settings {
+    //built in defaults, then the config file's defaults, then the rom
+    //database, then options stored with the rom, then the config file's
+    //section for the rom, then the command line
+    pub fn for_rom(rom: &Rom, overrides: &[(String, String)]) -> Result<Settings, String> {
+        Settings::layered(rom, &Config::load()?, overrides)
+    }
+    fn layered(
+        rom: &Rom,
+        config: &Config,
+        overrides: &[(String, String)],
+    ) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        let rom_sha1 = file_utils::sha1_hex(&rom.program);
+        config.apply_defaults(&mut settings)?;
+        if let Some(info) = romdb::lookup(&rom_sha1) {
+            info.apply(&mut settings)?;
+        }
+        for (key, value) in &rom.settings {
+            settings.set(key, value)?;
+        }
+        config.apply_rom(&mut settings, &rom_sha1)?;
+        for (key, value) in overrides {
+            settings.set(key, value)?;
+        }
+        Ok(settings)
+    }
+    //the load address .asm and .8o sources are built for. it has to be known
+    //before the rom exists, so only the config defaults and the command line
+    //can move it
+    pub fn source_origin(overrides: &[(String, String)]) -> Result<u16, String> {
+        let mut settings = Settings::default();
+        Config::load()?.apply_defaults(&mut settings)?;
+        for (key, value) in overrides.iter().filter(|(key, _)| key == "origin") {
+            settings.set(key, value)?;
+        }
+        Ok(settings.load_address)
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let bad = |what: &str| format!("invalid {} '{}'", what, value);
+        match key {
+            "scale" => {
+                self.scale = value
+                    .parse()
+                    .ok()
+                    .filter(|scale| (1..=64).contains(scale))
+                    .ok_or_else(|| bad("scale"))?
+            }
+            "ips" => {
+                self.instructions_per_second = value
+                    .parse()
+                    .ok()
+                    .filter(|ips| *ips > 0)
+                    .ok_or_else(|| bad("ips"))?
+            }
+            "speed" => {
+                self.speed = value
+                    .parse()
+                    .ok()
+                    .filter(|speed: &f64| *speed > 0.0 && speed.is_finite())
+                    .ok_or_else(|| bad("speed"))?
+            }
+            "turbo" => {
+                self.turbo = match value {
+                    "unthrottled" => Speed::Unthrottled,
+                    _ => value
+                        .parse()
+                        .ok()
+                        .filter(|speed: &f64| *speed > 0.0 && speed.is_finite())
+                        .map(Speed::Scaled)
+                        .ok_or_else(|| bad("turbo"))?,
+                }
+            }
+            "timing" => self.timing = value.parse()?,
+            "quirks" => self.quirks = value.parse()?,
+            "stack" => self.stack_depth = Some(value.parse()?),
+            "memory" => self.memory_policy = value.parse()?,
+            "protect" => {
+                self.protect = match value {
+                    "none" => Vec::new(),
+                    _ => value
+                        .split(',')
+                        .map(|area| area.trim().parse())
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            "seed" => self.seed = Some(value.parse().map_err(|_| bad("seed"))?),
+            "palette" => self.palette = value.parse()?,
+            "scanlines" => {
+                self.effects.scanlines = parse_bool(value).ok_or_else(|| bad("scanlines"))?
+            }
+            "grid" => self.effects.grid = parse_bool(value).ok_or_else(|| bad("grid"))?,
+            "bloom" => self.effects.bloom = parse_bool(value).ok_or_else(|| bad("bloom"))?,
+            "curvature" => {
+                self.effects.curvature = parse_bool(value).ok_or_else(|| bad("curvature"))?
+            }
+            "vignette" => {
+                self.effects.vignette = parse_bool(value).ok_or_else(|| bad("vignette"))?
+            }
+            "renderer" => self.renderer = value.parse()?,
+            "mute" => self.mute = parse_bool(value).ok_or_else(|| bad("mute"))?,
+            "headless" => self.headless = parse_bool(value).ok_or_else(|| bad("headless"))?,
+            "frames" => self.frames = Some(value.parse().map_err(|_| bad("frames"))?),
+            "debug" => self.debug = parse_bool(value).ok_or_else(|| bad("debug"))?,
+            "keymap" => self.keymap = value.to_string(),
+            "movie" => self.movie = Some(value.to_string()),
+            "record" => self.record = Some(value.to_string()),
+            "gif-skip" => {
+                self.gif_skip = value
+                    .parse()
+                    .ok()
+                    .filter(|frames| *frames > 0)
+                    .ok_or_else(|| bad("gif frame skip"))?
+            }
+            "title" => self.title = Some(value.to_string()),
+            "watch" => self.watch = parse_bool(value).ok_or_else(|| bad("watch"))?,
+            "origin" => self.load_address = parse_address(value).ok_or_else(|| bad("origin"))?,
+            "entry" => self.entry_point = Some(parse_address(value).ok_or_else(|| bad("entry"))?),
+            "trace" => self.trace = Some(value.to_string()),
+            "profile" => self.profile = Some(value.to_string()),
+            "script" => self.script = Some(value.to_string()),
+            "gdb" => self.gdb = Some(value.parse().map_err(|_| bad("port"))?),
+            "break" => {
+                self.breaks = match value {
+                    "none" => Vec::new(),
+                    _ => value.split(',').map(|b| b.trim().to_string()).collect(),
+                }
+            }
+            "reward" => self.reward = parse_list(value)?,
+            "done" => self.done = parse_list(value)?,
+            "frame-skip" => {
+                self.frame_skip = value
+                    .parse()
+                    .ok()
+                    .filter(|frames| *frames > 0)
+                    .ok_or_else(|| bad("frame skip"))?
+            }
+            "sticky" => {
+                self.sticky = value
+                    .parse()
+                    .ok()
+                    .filter(|p: &f64| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| bad("sticky probability"))?
+            }
+            "envs" => {
+                self.envs = value
+                    .parse()
+                    .ok()
+                    .filter(|envs| *envs > 0)
+                    .ok_or_else(|| bad("instance count"))?
+            }
+            "trace-pc" => {
+                self.trace_filter.addresses =
+                    Some(trace::parse_address_range(value).ok_or_else(|| bad("address range"))?)
+            }
+            "trace-ops" => {
+                self.trace_filter.classes =
+                    Some(trace::parse_classes(value).ok_or_else(|| bad("opcode classes"))?)
+            }
+            "trace-frames" => {
+                self.trace_filter.frames =
+                    Some(trace::parse_frame_range(value).ok_or_else(|| bad("frame range"))?)
+            }
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+    //fresh machine with the rom loaded, set up the way these settings say
+    pub fn create_machine(&self, rom: &Rom) -> Result<Chip8, String> {
+        let mut chip8 = Chip8::new();
+        let mut quirks = self.quirks.quirks();
+        if let Some(depth) = self.stack_depth {
+            quirks.stack_depth = depth;
+        }
+        chip8.set_quirks(quirks);
+        chip8.set_memory_policy(self.memory_policy);
+        chip8.set_timing(self.timing);
+        if let Some(seed) = self.seed {
+            chip8.set_seed(seed);
+        }
+        chip8.set_load_address(self.load_address);
+        chip8.set_entry_point(self.entry_point.unwrap_or(self.load_address));
+        chip8.set_bus(Box::new(self.create_bus(rom.program.len())));
+        self.attach_symbols(&mut chip8, rom)?;
+        if let Some(destination) = &self.trace {
+            chip8.set_tracer(Some(Tracer::open(destination, self.trace_filter.clone())?));
+        }
+        if self.profile.is_some() {
+            chip8.set_profile(Some(Profile::new()));
+        }
+        chip8.load_program(&rom.program)?;
+        Ok(chip8)
+    }
+    //gives the machine the rom's labels and source lines and sets the --break
+    //breakpoints, which can name them. on a reload they are resolved again,
+    //breakpoints a debugger set stay where they are
+    pub fn attach_symbols(&self, chip8: &mut Chip8, rom: &Rom) -> Result<(), String> {
+        chip8.set_symbols(rom.symbols.clone());
+        let no_symbols = DebugMap::default();
+        let symbols = rom.symbols.as_deref().unwrap_or(&no_symbols);
+        let breaks = self
+            .breaks
+            .iter()
+            .map(|location| {
+                symbols
+                    .resolve(location)
+                    .map_err(|e| format!("--break {}: {}", location, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        chip8.breakpoints_mut().set_breaks(breaks);
+        Ok(())
+    }
+    //writes the --profile report for a finished run
+    pub fn save_profile(&self, chip8: &Chip8, rom: &[u8]) -> Result<(), String> {
+        if let (Some(path), Some(profile)) = (&self.profile, chip8.profile()) {
+            profile.save(path, rom, chip8.load_address())?;
+            println!("Saved profile to {}", path);
+        }
+        Ok(())
+    }
+    //plain ram with the --protect areas mapped read-only
+    pub fn create_bus(&self, rom_length: usize) -> MemoryBus {
+        let mut bus = MemoryBus::new();
+        for area in &self.protect {
+            match area {
+                Protect::Font => {
+                    bus.map_read_only(chip8::FONTSET_START_ADDRESS, chip8::FONTSET.len() as u16)
+                }
+                Protect::Interpreter => bus.map_read_only(0, self.load_address),
+                Protect::Rom => bus.map_read_only(self.load_address, rom_length as u16),
+            }
+        }
+        bus
+    }
+}
+
+//hex, with or without 0x or $ in front
+fn parse_address(value: &str) -> Option<u16> {
+    let digits = value
+        .strip_prefix("0x")
+        .or(value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16)
+        .ok()
+        .filter(|address| (*address as usize) < chip8::MEMORY_SIZE)
+}
+
+//comma separated, "none" for an empty list
+fn parse_list<T: FromStr<Err = String>>(value: &str) -> Result<Vec<T>, String> {
+    match value {
+        "none" => Ok(Vec::new()),
+        _ => value.split(',').map(|item| item.trim().parse()).collect(),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn rom_with_label(address: u16) -> Rom {
+        Rom {
+            program: vec![0x12, 0x00],
+            settings: Vec::new(),
+            symbols: Some(Arc::new(DebugMap::new(
+                Vec::new(),
+                vec![(address, String::from("loop"))],
+            ))),
+        }
+    }
+
+    #[test]
+    fn reloads_keep_debugger_breakpoints() {
+        let mut settings = Settings::default();
+        settings.set("break", "loop").unwrap();
+        let mut chip8 = Chip8::new();
+        settings
+            .attach_symbols(&mut chip8, &rom_with_label(0x204))
+            .unwrap();
+        chip8.breakpoints_mut().add(0x300);
+        //the label moved in the rebuilt rom
+        settings
+            .attach_symbols(&mut chip8, &rom_with_label(0x206))
+            .unwrap();
+        let breakpoints = chip8.breakpoints();
+        assert!(!breakpoints.contains(0x204));
+        assert!(breakpoints.contains(0x206));
+        assert!(breakpoints.contains(0x300));
+    }
+
+    #[test]
+    fn known_roms_beat_config_defaults() {
+        let pong = Rom {
+            program: include_bytes!("pong.rom").to_vec(),
+            settings: Vec::new(),
+            symbols: None,
+        };
+        let sha1 = file_utils::sha1_hex(&pong.program);
+        let config = Config::parse(&format!(
+            "quirks = schip\nscale = 5\nips = 700\n[{}]\nips = 900\n",
+            sha1
+        ))
+        .unwrap();
+        //the database has pong on vip
+        let settings = Settings::layered(&pong, &config, &[]).unwrap();
+        assert_eq!(settings.quirks, QuirkProfile::Vip);
+        assert_eq!(settings.scale, 5);
+        assert_eq!(settings.instructions_per_second, 900);
+        let overrides = [(String::from("quirks"), String::from("modern"))];
+        let settings = Settings::layered(&pong, &config, &overrides).unwrap();
+        assert_eq!(settings.quirks, QuirkProfile::Modern);
+        //roms the database doesn't know take the defaults
+        let other = Rom {
+            program: vec![0x12, 0x00],
+            settings: vec![(String::from("scale"), String::from("8"))],
+            symbols: None,
+        };
+        let settings = Settings::layered(&other, &config, &[]).unwrap();
+        assert_eq!(settings.quirks, QuirkProfile::Schip);
+        assert_eq!(settings.scale, 8);
+        assert_eq!(settings.instructions_per_second, 700);
+    }
+
+    #[test]
+    fn turbo_speeds() {
+        let mut settings = Settings::default();
+        assert!(settings.turbo == Speed::Unthrottled);
+        settings.set("turbo", "4").unwrap();
+        assert!(settings.turbo == Speed::Scaled(4.0));
+        settings.set("turbo", "unthrottled").unwrap();
+        assert!(settings.turbo == Speed::Unthrottled);
+        for value in ["0", "-2", "inf", "fast"] {
+            assert!(settings.set("turbo", value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use super::debug_map::DebugMap;
+use super::disassembler;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+//binary traces start with this, followed by one record per step:
+//  frame u32, pc u16, opcode u16 (all little endian)
+//  count u8: number of register changes, bit 7 set if I changed, bit 6 set
+//  if memory was written
+//  per register change: register u8, old u8, new u8
+//  if I changed: old u16, new u16
+//  if memory was written: count u8, then per write address u16, old u8, new u8
+pub const BINARY_MAGIC: &[u8; 8] = b"C8TRACE1";
+//binary traces that --trace-pc or --trace-ops left steps out of start with
+//this instead, text traces with FILTERED_NOTE as their first line
+pub const FILTERED_MAGIC: &[u8; 8] = b"C8TRACEF";
+pub const FILTERED_NOTE: &str = "# filtered with --trace-pc or --trace-ops, steps are missing";
+const DEFAULT_RING_SIZE: usize = 64;
+
+//one executed instruction and what it changed
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceStep {
+    pub frame: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    //(register, old, new)
+    pub registers: Vec<(u8, u8, u8)>,
+    //(old, new)
+    pub index: Option<(u16, u16)>,
+    //(address, old, new)
+    pub memory: Vec<(u16, u8, u8)>,
+}
+
+impl TraceStep {
+    pub fn new(
+        frame: u64,
+        pc: u16,
+        opcode: u16,
+        before: (&[u8; 16], u16),
+        after: (&[u8; 16], u16),
+        memory: Vec<(u16, u8, u8)>,
+    ) -> Self {
+        let registers = (0..16)
+            .filter(|&i| before.0[i] != after.0[i])
+            .map(|i| (i as u8, before.0[i], after.0[i]))
+            .collect();
+        let index = (before.1 != after.1).then_some((before.1, after.1));
+        Self {
+            frame,
+            pc,
+            opcode,
+            registers,
+            index,
+            memory,
+        }
+    }
+    pub fn write_binary(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(16);
+        record.extend((self.frame as u32).to_le_bytes());
+        record.extend(self.pc.to_le_bytes());
+        record.extend(self.opcode.to_le_bytes());
+        let mut count = self.registers.len() as u8;
+        if self.index.is_some() {
+            count |= 0x80;
+        }
+        if !self.memory.is_empty() {
+            count |= 0x40;
+        }
+        record.push(count);
+        for &(register, old, new) in &self.registers {
+            record.extend([register, old, new]);
+        }
+        if let Some((old, new)) = self.index {
+            record.extend(old.to_le_bytes());
+            record.extend(new.to_le_bytes());
+        }
+        if !self.memory.is_empty() {
+            //FX55 writes at most 16 bytes
+            record.push(self.memory.len() as u8);
+            for &(address, old, new) in &self.memory {
+                record.extend(address.to_le_bytes());
+                record.extend([old, new]);
+            }
+        }
+        out.write_all(&record)
+    }
+}
+
+//frame 12  pc 023a  6a02  MVI VA, 02  VA 00>02 I 0200>0204 @0300 00>05
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_text(None))
+    }
+}
+
+impl TraceStep {
+    //the text line, with label names in it when there is a debug map
+    pub fn to_text(&self, symbols: Option<&DebugMap>) -> String {
+        let bytes = self.opcode.to_be_bytes();
+        let mut instruction = disassembler::disassemble_chip8(&bytes, 0).replace('\t', " ");
+        if let Some(symbols) = symbols {
+            instruction = symbols.label_operands(&instruction);
+            if let Some(label) = symbols.label_at(self.pc) {
+                instruction = format!("{}: {}", label, instruction);
+            }
+        }
+        let mut line = format!(
+            "frame {}  pc {:04x}  {:04x}  {:<16}",
+            self.frame, self.pc, self.opcode, instruction
+        );
+        for (register, old, new) in &self.registers {
+            line.push_str(&format!(" V{:X} {:02x}>{:02x}", register, old, new));
+        }
+        if let Some((old, new)) = self.index {
+            line.push_str(&format!(" I {:04x}>{:04x}", old, new));
+        }
+        for (address, old, new) in &self.memory {
+            line.push_str(&format!(" @{:04x} {:02x}>{:02x}", address, old, new));
+        }
+        line.trim_end().to_string()
+    }
+}
+
+//true for trace files that don't hold every executed step
+pub fn is_filtered(data: &[u8]) -> bool {
+    data.starts_with(FILTERED_MAGIC) || data.starts_with(FILTERED_NOTE.as_bytes())
+}
+
+//the steps in a binary trace file
+pub fn read_binary(data: &[u8]) -> Result<Vec<TraceStep>, String> {
+    let mut data = data
+        .strip_prefix(BINARY_MAGIC)
+        .or(data.strip_prefix(FILTERED_MAGIC))
+        .ok_or("not a binary trace")?;
+    let mut steps = Vec::new();
+    while !data.is_empty() {
+        let header = take(&mut data, 9)?;
+        let count = header[8];
+        let mut registers = Vec::new();
+        for _ in 0..count & 0x1f {
+            let change = take(&mut data, 3)?;
+            registers.push((change[0], change[1], change[2]));
+        }
+        let index = if count & 0x80 != 0 {
+            let change = take(&mut data, 4)?;
+            Some((
+                u16::from_le_bytes([change[0], change[1]]),
+                u16::from_le_bytes([change[2], change[3]]),
+            ))
+        } else {
+            None
+        };
+        let mut memory = Vec::new();
+        if count & 0x40 != 0 {
+            for _ in 0..take(&mut data, 1)?[0] {
+                let write = take(&mut data, 4)?;
+                memory.push((u16::from_le_bytes([write[0], write[1]]), write[2], write[3]));
+            }
+        }
+        steps.push(TraceStep {
+            frame: u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as u64,
+            pc: u16::from_le_bytes([header[4], header[5]]),
+            opcode: u16::from_le_bytes([header[6], header[7]]),
+            registers,
+            index,
+            memory,
+        });
+    }
+    Ok(steps)
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8], String> {
+    if data.len() < length {
+        return Err(String::from("binary trace is cut short"));
+    }
+    let (bytes, rest) = data.split_at(length);
+    *data = rest;
+    Ok(bytes)
+}
+
+//which steps get traced, None lets everything through
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<u16>>,
+    //first nybble of the opcode, bit n set for class n
+    pub classes: Option<u16>,
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn wants_frame(&self, frame: u64) -> bool {
+        self.frames.as_ref().is_none_or(|f| f.contains(&frame))
+    }
+    pub fn wants(&self, frame: u64, pc: u16, opcode: u16) -> bool {
+        self.wants_frame(frame)
+            && self.addresses.as_ref().is_none_or(|a| a.contains(&pc))
+            && self.classes.is_none_or(|c| c & 1 << (opcode >> 12) != 0)
+    }
+    //a frame range still traces every step of the frames it covers
+    pub fn skips_steps(&self) -> bool {
+        self.addresses.is_some() || self.classes.is_some()
+    }
+}
+
+//where traced steps go
+enum Sink {
+    //the last few steps, printed when the machine faults
+    Ring(VecDeque<TraceStep>, usize),
+    Text(BufWriter<File>),
+    Binary(BufWriter<File>),
+}
+
+pub struct Tracer {
+    sink: Sink,
+    filter: TraceFilter,
+    //set when the trace file can't be written to
+    stopped: bool,
+    //labels for text traces
+    symbols: Option<Arc<DebugMap>>,
+}
+
+impl Tracer {
+    //"ring" or "ring:N" keeps the last steps in memory, a path ending in .txt
+    //or .log gets one line per step, any other path the binary format
+    pub fn open(destination: &str, filter: TraceFilter) -> Result<Tracer, String> {
+        let sink = if destination == "ring" {
+            Sink::Ring(VecDeque::new(), DEFAULT_RING_SIZE)
+        } else if let Some(size) = destination.strip_prefix("ring:") {
+            let size = size
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("invalid trace ring size '{}'", size))?;
+            Sink::Ring(VecDeque::new(), size)
+        } else {
+            let file = File::create(destination).map_err(|e| format!("{}: {}", destination, e))?;
+            let mut out = BufWriter::new(file);
+            let text = destination.ends_with(".txt") || destination.ends_with(".log");
+            let header = match (text, filter.skips_steps()) {
+                (true, true) => format!("{}\n", FILTERED_NOTE).into_bytes(),
+                (true, false) => Vec::new(),
+                (false, true) => FILTERED_MAGIC.to_vec(),
+                (false, false) => BINARY_MAGIC.to_vec(),
+            };
+            out.write_all(&header)
+                .map_err(|e| format!("{}: {}", destination, e))?;
+            if text {
+                Sink::Text(out)
+            } else {
+                Sink::Binary(out)
+            }
+        };
+        Ok(Tracer {
+            sink,
+            filter,
+            stopped: false,
+            symbols: None,
+        })
+    }
+    //checked before each step so nothing is copied for frames out of range
+    pub fn wants_frame(&self, frame: u64) -> bool {
+        !self.stopped && self.filter.wants_frame(frame)
+    }
+    //a trace that can't be written stops the trace, not the emulator
+    pub fn record(&mut self, step: TraceStep) {
+        if self.stopped || !self.filter.wants(step.frame, step.pc, step.opcode) {
+            return;
+        }
+        let result = match &mut self.sink {
+            Sink::Ring(steps, size) => {
+                if steps.len() == *size {
+                    steps.pop_front();
+                }
+                steps.push_back(step);
+                Ok(())
+            }
+            Sink::Text(out) => writeln!(out, "{}", step.to_text(self.symbols.as_deref())),
+            Sink::Binary(out) => step.write_binary(out),
+        };
+        if let Err(e) = result {
+            eprintln!("Stopped tracing: {}", e);
+            self.stopped = true;
+        }
+    }
+    pub fn set_symbols(&mut self, symbols: Option<Arc<DebugMap>>) {
+        self.symbols = symbols;
+    }
+    //the ring buffer contents, oldest first
+    pub fn recent(&self) -> Option<&VecDeque<TraceStep>> {
+        match &self.sink {
+            Sink::Ring(steps, _) => Some(steps),
+            _ => None,
+        }
+    }
+    pub fn flush(&mut self) {
+        if let Sink::Text(out) | Sink::Binary(out) = &mut self.sink {
+            if let Err(e) = out.flush() {
+                eprintln!("Failed to write trace: {}", e);
+            }
+        }
+    }
+}
+
+//"200-2ff" or a single "23a", hex
+pub fn parse_address_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let hex = |s: &str| u16::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok();
+    match value.split_once('-') {
+        Some((start, end)) => Some(hex(start)?..=hex(end)?),
+        None => hex(value).map(|a| a..=a),
+    }
+}
+
+//"100-200" or a single "150", decimal
+pub fn parse_frame_range(value: &str) -> Option<RangeInclusive<u64>> {
+    match value.split_once('-') {
+        Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+        None => value.trim().parse().ok().map(|f| f..=f),
+    }
+}
+
+//"d", "0,8" or "1-3", the first hex digit of the opcodes to keep
+pub fn parse_classes(value: &str) -> Option<u16> {
+    let digit = |s: &str| u8::from_str_radix(s.trim(), 16).ok().filter(|&d| d < 16);
+    let mut classes = 0;
+    for part in value.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (digit(start)?, digit(end)?),
+            None => (digit(part)?, digit(part)?),
+        };
+        for class in start..=end {
+            classes |= 1 << class;
+        }
+    }
+    Some(classes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps() -> Vec<TraceStep> {
+        vec![
+            TraceStep {
+                frame: 0,
+                pc: 0x200,
+                opcode: 0x6005,
+                registers: vec![(0, 0, 5)],
+                index: None,
+                memory: Vec::new(),
+            },
+            TraceStep {
+                frame: 1,
+                pc: 0x202,
+                opcode: 0xf155,
+                registers: Vec::new(),
+                index: Some((0x300, 0x302)),
+                memory: vec![(0x300, 0, 5), (0x301, 0xff, 7)],
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut data = BINARY_MAGIC.to_vec();
+        for step in steps() {
+            step.write_binary(&mut data).unwrap();
+        }
+        assert_eq!(read_binary(&data).unwrap(), steps());
+        assert!(!is_filtered(&data));
+        data[..8].copy_from_slice(FILTERED_MAGIC);
+        assert_eq!(read_binary(&data).unwrap(), steps());
+        assert!(is_filtered(&data));
+    }
+
+    #[test]
+    fn binary_fixture() {
+        //frame 2, pc 0206, 8014 changing V0 and VF
+        let data = [
+            b'C', b'8', b'T', b'R', b'A', b'C', b'E', b'1', 2, 0, 0, 0, 0x06, 0x02, 0x14, 0x80, 2,
+            0, 0xff, 0x01, 0xf, 0, 1,
+        ];
+        let steps = read_binary(&data).unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(
+            (steps[0].frame, steps[0].pc, steps[0].opcode),
+            (2, 0x206, 0x8014)
+        );
+        assert_eq!(steps[0].registers, [(0, 0xff, 1), (0xf, 0, 1)]);
+        assert_eq!(
+            read_binary(&data[..data.len() - 1]).unwrap_err(),
+            "binary trace is cut short"
+        );
+        assert!(read_binary(b"C8TRACE9").is_err());
+    }
+
+    #[test]
+    fn filters_mark_the_file() {
+        let directory = std::env::temp_dir();
+        let path = |name: &str| {
+            directory
+                .join(format!("chip8-trace-{}-{}", std::process::id(), name))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let opcodes = TraceFilter {
+            classes: parse_classes("6"),
+            ..TraceFilter::default()
+        };
+        let frames = TraceFilter {
+            frames: parse_frame_range("0-1"),
+            ..TraceFilter::default()
+        };
+        for (name, filter, filtered) in [
+            ("ops.bin", &opcodes, true),
+            ("ops.txt", &opcodes, true),
+            ("frames.bin", &frames, false),
+            ("frames.txt", &frames, false),
+        ] {
+            let path = path(name);
+            let mut tracer = Tracer::open(&path, filter.clone()).unwrap();
+            for step in steps() {
+                tracer.record(step);
+            }
+            tracer.flush();
+            drop(tracer);
+            let data = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(is_filtered(&data), filtered, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use super::trace::{self, TraceStep};
+use std::fs;
+
+//steps shown before the one where the traces part ways
+const CONTEXT: usize = 5;
+
+//machine state just before and just after the instruction at pc runs, as
+//far as the trace tells it. our traces only hold what changed, so unless
+//they start at the reset in frame 0 registers are unknown until an
+//instruction changes them; other emulators usually log everything before
+//each step, the state after it is what the next step starts from
+#[derive(Clone, Default)]
+struct Step {
+    line: String,
+    frame: Option<u64>,
+    pc: u16,
+    opcode: Option<u16>,
+    registers: [Option<u8>; 16],
+    index: Option<u16>,
+    registers_after: [Option<u8>; 16],
+    index_after: Option<u16>,
+    //(address, value) written by the instruction at pc
+    writes: Option<Vec<(u16, u8)>>,
+}
+
+//compares two traces step by step and reports the first difference. either
+//can be a binary or text trace from --trace, or a log from another emulator
+//with lines like "PC:0200 I:0000 V0:00 V1:00 ..."
+pub fn diff(path_a: &str, path_b: &str) -> Result<(), String> {
+    let a = load(path_a)?;
+    let b = load(path_b)?;
+    for (number, (step_a, step_b)) in a.iter().zip(&b).enumerate() {
+        let differences = compare(step_a, step_b);
+        if differences.is_empty() {
+            continue;
+        }
+        println!("Traces diverge at step {}:", number);
+        for difference in differences {
+            println!("  {}", difference);
+        }
+        for (path, steps) in [(path_a, &a), (path_b, &b)] {
+            println!("{}:", path);
+            for (i, step) in steps
+                .iter()
+                .enumerate()
+                .take(number + 1)
+                .skip(number.saturating_sub(CONTEXT))
+            {
+                let marker = if i == number { '>' } else { ' ' };
+                println!("{} {}", marker, step.line);
+            }
+        }
+        return Err(format!("traces diverge at step {}", number));
+    }
+    if a.len() == b.len() {
+        println!("Traces match for all {} steps", a.len());
+    } else {
+        println!(
+            "Traces match for {} steps, {} has {} and {} has {}",
+            a.len().min(b.len()),
+            path_a,
+            a.len(),
+            path_b,
+            b.len()
+        );
+    }
+    Ok(())
+}
+
+fn compare(a: &Step, b: &Step) -> Vec<String> {
+    let mut differences = Vec::new();
+    if a.pc != b.pc {
+        differences.push(format!("pc {:04x} vs {:04x}", a.pc, b.pc));
+    }
+    if let (Some(x), Some(y)) = (a.opcode, b.opcode) {
+        if x != y {
+            differences.push(format!("opcode {:04x} vs {:04x}", x, y));
+        }
+    }
+    if let (Some(x), Some(y)) = (a.frame, b.frame) {
+        if x != y {
+            differences.push(format!("frame {} vs {}", x, y));
+        }
+    }
+    compare_state(
+        (&a.registers, a.index),
+        (&b.registers, b.index),
+        "",
+        &mut differences,
+    );
+    //what the instruction itself did, so a divergence shows at the step that
+    //caused it and the last step is checked too
+    compare_state(
+        (&a.registers_after, a.index_after),
+        (&b.registers_after, b.index_after),
+        " after the step",
+        &mut differences,
+    );
+    if let (Some(x), Some(y)) = (&a.writes, &b.writes) {
+        if x != y {
+            let show = |writes: &[(u16, u8)]| {
+                let writes: Vec<String> = writes
+                    .iter()
+                    .map(|(address, value)| format!("@{:04x}={:02x}", address, value))
+                    .collect();
+                format!("[{}]", writes.join(" "))
+            };
+            differences.push(format!("memory writes {} vs {}", show(x), show(y)));
+        }
+    }
+    differences
+}
+
+fn compare_state(
+    (registers_a, index_a): (&[Option<u8>; 16], Option<u16>),
+    (registers_b, index_b): (&[Option<u8>; 16], Option<u16>),
+    when: &str,
+    differences: &mut Vec<String>,
+) {
+    for (i, (x, y)) in registers_a.iter().zip(registers_b).enumerate() {
+        if let (Some(x), Some(y)) = (x, y) {
+            if x != y {
+                differences.push(format!("V{:X} {:02x} vs {:02x}{}", i, x, y, when));
+            }
+        }
+    }
+    if let (Some(x), Some(y)) = (index_a, index_b) {
+        if x != y {
+            differences.push(format!("I {:04x} vs {:04x}{}", x, y, when));
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Vec<Step>, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    //the state before each step is rebuilt from every step before it
+    if trace::is_filtered(&data) {
+        return Err(format!(
+            "{}: traced with --trace-pc or --trace-ops, only --trace-frames keeps every step",
+            path
+        ));
+    }
+    if data.starts_with(trace::BINARY_MAGIC) {
+        let steps = trace::read_binary(&data).map_err(|e| format!("{}: {}", path, e))?;
+        return Ok(from_trace(steps));
+    }
+    let text = String::from_utf8_lossy(&data);
+    let ours = text
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .is_some_and(|line| parse_line(line).is_some());
+    if ours {
+        let steps = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(number, line)| {
+                parse_line(line).ok_or(format!("{}: line {} is not a trace step", path, number + 1))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(from_trace(steps))
+    } else {
+        Ok(parse_foreign(&text))
+    }
+}
+
+//frame 12  pc 023a  6a02  MVI VA, 02  VA 00>02 I 0200>0204 @0300 00>05
+fn parse_line(line: &str) -> Option<TraceStep> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let [("frame", frame), ("pc", pc)] = [
+        (*tokens.first()?, *tokens.get(1)?),
+        (*tokens.get(2)?, *tokens.get(3)?),
+    ] else {
+        return None;
+    };
+    let mut step = TraceStep {
+        frame: frame.parse().ok()?,
+        pc: u16::from_str_radix(pc, 16).ok()?,
+        opcode: u16::from_str_radix(tokens.get(4)?, 16).ok()?,
+        registers: Vec::new(),
+        index: None,
+        memory: Vec::new(),
+    };
+    //the disassembly never has a '>' in it, so every change is a name
+    //followed by old>new
+    for pair in tokens[5..].windows(2) {
+        let Some((old, new)) = pair[1].split_once('>') else {
+            continue;
+        };
+        let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+        let (old, new) = (hex(old)?, hex(new)?);
+        if pair[0] == "I" {
+            step.index = Some((old, new));
+        } else if let Some(address) = pair[0].strip_prefix('@') {
+            step.memory.push((hex(address)?, old as u8, new as u8));
+        } else if let Some(register) = pair[0].strip_prefix('V') {
+            step.registers
+                .push((u8::from_str_radix(register, 16).ok()?, old as u8, new as u8));
+        }
+    }
+    Some(step)
+}
+
+//turns what each instruction changed into the state before and after each
+//instruction. a trace that starts in frame 0 starts from the reset, where
+//the registers and I are all 0
+fn from_trace(steps: Vec<TraceStep>) -> Vec<Step> {
+    let mut state = Step::default();
+    if steps.first().is_some_and(|step| step.frame == 0) {
+        state.registers = [Some(0); 16];
+        state.index = Some(0);
+    }
+    let mut result = Vec::with_capacity(steps.len());
+    for trace_step in steps {
+        //old values are what the machine held going into this instruction
+        for &(register, old, _) in &trace_step.registers {
+            state.registers[register as usize] = Some(old);
+        }
+        if let Some((old, _)) = trace_step.index {
+            state.index = Some(old);
+        }
+        let mut step = Step {
+            line: trace_step.to_string(),
+            frame: Some(trace_step.frame),
+            pc: trace_step.pc,
+            opcode: Some(trace_step.opcode),
+            writes: Some(
+                trace_step
+                    .memory
+                    .iter()
+                    .map(|&(address, _, new)| (address, new))
+                    .collect(),
+            ),
+            ..state.clone()
+        };
+        for &(register, _, new) in &trace_step.registers {
+            state.registers[register as usize] = Some(new);
+        }
+        if let Some((_, new)) = trace_step.index {
+            state.index = Some(new);
+        }
+        step.registers_after = state.registers;
+        step.index_after = state.index;
+        result.push(step);
+    }
+    result
+}
+
+//any log with one instruction per line and name/value pairs for pc, the
+//registers, I and optionally the opcode and frame, in any order and with :,
+//= or spaces between them. lines without a pc are skipped
+fn parse_foreign(text: &str) -> Vec<Step> {
+    let mut steps = Vec::new();
+    for line in text.lines() {
+        let cleaned = line.replace([':', '=', ',', '\t'], " ").to_lowercase();
+        let tokens: Vec<&str> = cleaned.split_whitespace().collect();
+        let mut step = Step {
+            line: line.trim_end().to_string(),
+            ..Step::default()
+        };
+        let mut pc = None;
+        for pair in tokens.windows(2) {
+            let hex = || {
+                let value = pair[1]
+                    .trim_start_matches("0x")
+                    .trim_start_matches(['$', '#']);
+                u16::from_str_radix(value, 16).ok()
+            };
+            match pair[0] {
+                "pc" => pc = pc.or(hex()),
+                "op" | "opcode" => step.opcode = step.opcode.or(hex()),
+                "i" => step.index = step.index.or(hex()),
+                "frame" => step.frame = step.frame.or(pair[1].parse().ok()),
+                name => {
+                    let register = name
+                        .strip_prefix('v')
+                        .filter(|r| r.len() == 1)
+                        .and_then(|r| usize::from_str_radix(r, 16).ok());
+                    if let (Some(register), Some(value)) = (register, hex()) {
+                        step.registers[register] = Some(value as u8);
+                    }
+                }
+            }
+        }
+        if let Some(pc) = pc {
+            step.pc = pc;
+            steps.push(step);
+        }
+    }
+    //each step ends in the state the next one logs
+    for i in 1..steps.len() {
+        steps[i - 1].registers_after = steps[i].registers;
+        steps[i - 1].index_after = steps[i].index;
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: &str = "\
+frame 0  pc 0200  6005  MVI V0, 05       V0 00>05
+frame 0  pc 0202  a300  MVI I, $300      I 0000>0300
+frame 1  pc 0204  f055  STR V0           @0300 00>05
+";
+
+    const FOREIGN: &str = "\
+CHIP-8 log
+PC:0200 OP:6005 I:0000 V0:00 V1:00 VF:00
+PC=0x0202, opcode=$a300, i=0000, v0=05
+pc 0204 op f055 i 0300 v0 05 frame 1
+";
+
+    #[test]
+    fn our_text_lines() {
+        let step =
+            parse_line("frame 12  pc 023a  6a02  MVI VA, 02  VA 00>02 I 0200>0204 @0300 00>05")
+                .unwrap();
+        assert_eq!((step.frame, step.pc, step.opcode), (12, 0x23a, 0x6a02));
+        assert_eq!(step.registers, [(0xa, 0, 2)]);
+        assert_eq!(step.index, Some((0x200, 0x204)));
+        assert_eq!(step.memory, [(0x300, 0, 5)]);
+        //labels from a debug map end in ':' and change nothing
+        let step = parse_line("frame 0  pc 0200  1200  loop: JMP loop").unwrap();
+        assert!(step.registers.is_empty() && step.memory.is_empty());
+        assert!(parse_line("PC:0200 I:0000 V0:00").is_none());
+        assert!(parse_line("frame x  pc 0200  6005").is_none());
+    }
+
+    #[test]
+    fn state_around_each_step() {
+        let steps: Vec<TraceStep> = OURS.lines().map(|l| parse_line(l).unwrap()).collect();
+        let steps = from_trace(steps);
+        assert_eq!(steps.len(), 3);
+        //a trace from frame 0 starts at the reset
+        assert_eq!(steps[0].registers, [Some(0); 16]);
+        assert_eq!(steps[0].index, Some(0));
+        assert_eq!(steps[0].registers_after[0], Some(5));
+        assert_eq!(steps[1].registers[0], Some(5));
+        assert_eq!(steps[1].index, Some(0));
+        assert_eq!(steps[1].index_after, Some(0x300));
+        assert_eq!(steps[2].index, Some(0x300));
+        assert_eq!(steps[2].writes, Some(vec![(0x300, 5)]));
+        assert_eq!(steps[2].frame, Some(1));
+        //later frames start from a state the trace doesn't hold
+        let later = from_trace(vec![parse_line(OURS.lines().nth(2).unwrap()).unwrap()]);
+        assert_eq!(later[0].registers[0], None);
+    }
+
+    #[test]
+    fn divergence_shows_at_its_step() {
+        let ours = from_trace(OURS.lines().map(|l| parse_line(l).unwrap()).collect());
+        //the first instruction loaded 06, the last one changed I
+        let foreign = parse_foreign("PC:0200 I:0000 V0:00\nPC:0202 I:0000 V0:06\n");
+        assert_eq!(
+            compare(&ours[0], &foreign[0]),
+            ["V0 05 vs 06 after the step"]
+        );
+        let other = from_trace(
+            "frame 0  pc 0200  6005  MVI V0, 05       V0 00>05\n\
+             frame 0  pc 0202  a300  MVI I, $300      I 0000>0301\n"
+                .lines()
+                .map(|l| parse_line(l).unwrap())
+                .collect(),
+        );
+        assert_eq!(
+            compare(&ours[1], &other[1]),
+            ["I 0300 vs 0301 after the step"]
+        );
+    }
+
+    #[test]
+    fn foreign_logs() {
+        let steps = parse_foreign(FOREIGN);
+        assert_eq!(steps.len(), 3);
+        assert_eq!((steps[0].pc, steps[0].opcode), (0x200, Some(0x6005)));
+        assert_eq!(steps[0].registers[0xf], Some(0));
+        assert_eq!(steps[0].registers[2], None);
+        assert_eq!((steps[1].pc, steps[1].opcode), (0x202, Some(0xa300)));
+        assert_eq!(steps[1].registers[0], Some(5));
+        assert_eq!((steps[2].index, steps[2].frame), (Some(0x300), Some(1)));
+        assert_eq!(steps[2].writes, None);
+    }
+
+    #[test]
+    fn ours_against_a_foreign_log() {
+        let ours = from_trace(OURS.lines().map(|l| parse_line(l).unwrap()).collect());
+        let foreign = parse_foreign(FOREIGN);
+        for (a, b) in ours.iter().zip(&foreign) {
+            assert!(compare(a, b).is_empty(), "{}", a.line);
+        }
+        let wrong = parse_foreign("PC:0200 V0:00\nPC:0204 V0:05\n");
+        assert!(compare(&ours[0], &wrong[0]).is_empty());
+        assert_eq!(compare(&ours[1], &wrong[1]), ["pc 0202 vs 0204"]);
+    }
+
+    #[test]
+    fn filtered_traces_are_rejected() {
+        let path = std::env::temp_dir().join(format!("chip8-tracediff-{}.txt", std::process::id()));
+        fs::write(&path, format!("{}\n{}", trace::FILTERED_NOTE, OURS)).unwrap();
+        let result = load(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err_and(|e| e.contains("--trace-pc")));
+    }
+}